@@ -1,132 +1,1092 @@
 pub mod arg_handling {
-    use crate::arg_handling::arg_handling::arg_handling::KeySize::Size256;
+    use crate::arg_handling::options::{
+        command_usage, general_usage, parse_config_file, tokenize, ArgError, CommandName,
+        OptionSpec,
+    };
+    use crate::cryptography::encoding::{base64_decode, hex_decode};
+    use crate::cryptography::kdf::{pbkdf2_hmac_sha256, PBKDF2_DEFAULT_ITERATIONS};
+    use crate::cryptography::ratchet::RekeyPolicy;
+    use crate::cryptography::rc4::{MAX_DROP_BYTES, RECOMMENDED_DROP_BYTES};
+    use crate::network::handshake::DEFAULT_HANDSHAKE_TIMEOUT;
+    use crate::ui::terminal::read_hidden;
     use crate::{ERROR, SUCCESS};
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::fs::OpenOptions;
     use std::process::exit;
+    use std::time::Duration;
+
+    const PROGRAM: &str = "kryptos-client";
+
+    /*
+       Everyone in a room needs the same salt to end up with the same key, so unless told otherwise
+       we all use this one
+    */
+    const DEFAULT_SALT: &str = "kryptos";
+    const DEFAULT_CIPHER: EncryptionInfo = EncryptionInfo::AesGcm;
+    /*
+       GCM only does whole messages, so encrypt and decrypt default to a cipher that can stream
+    */
+    const DEFAULT_FILE_CIPHER: EncryptionInfo = EncryptionInfo::AesCtr;
+    const DEFAULT_BENCH_BYTES: usize = 16 * 1024 * 1024;
+    /// Where the key comes from when neither --key nor --key-file is given
+    const KEY_VARIABLE: &str = "KRYPTOS_KEY";
     /*
            Enum we will use to pass encryption info for creation of context
         */
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub enum EncryptionInfo {
         AesCbc,
         AesCtr,
         AesEcb,
+        AesGcm,
+        ChaCha20Poly1305,
         Rc4,
     }
 
-    #[derive(Clone, Copy)]
+    impl EncryptionInfo {
+        /// In the order the help lists them, best first
+        pub const ALL: [EncryptionInfo; 6] = [
+            EncryptionInfo::AesGcm,
+            EncryptionInfo::ChaCha20Poly1305,
+            EncryptionInfo::AesCbc,
+            EncryptionInfo::AesCtr,
+            EncryptionInfo::AesEcb,
+            EncryptionInfo::Rc4,
+        ];
+
+        /// The name it goes by on the command line
+        pub fn name(self) -> &'static str {
+            match self {
+                EncryptionInfo::AesCbc => "AesCbc",
+                EncryptionInfo::AesCtr => "AesCtr",
+                EncryptionInfo::AesEcb => "AesEcb",
+                EncryptionInfo::AesGcm => "AesGcm",
+                EncryptionInfo::ChaCha20Poly1305 => "ChaCha20Poly1305",
+                EncryptionInfo::Rc4 => "Rc4",
+            }
+        }
+
+        pub fn from_name(name: &str) -> Option<EncryptionInfo> {
+            EncryptionInfo::ALL.into_iter().find(|x| x.name() == name)
+        }
+
+        /// ECB leaks patterns in the plaintext and RC4 has biased keystream, they're only here for compatibility
+        pub fn is_unsafe(self) -> bool {
+            matches!(self, EncryptionInfo::AesEcb | EncryptionInfo::Rc4)
+        }
+
+        /// Whether it has a StreamingEncryption implementation, which encrypt and decrypt need
+        pub fn supports_streaming(self) -> bool {
+            matches!(
+                self,
+                EncryptionInfo::AesCbc | EncryptionInfo::AesCtr | EncryptionInfo::Rc4
+            )
+        }
+
+        /*
+           AES and RC4 take any of the three sizes, ChaCha20 is only defined for 256 bit keys
+        */
+        pub fn supports_key_size(self, key_size: KeySize) -> bool {
+            self != EncryptionInfo::ChaCha20Poly1305 || key_size == KeySize::Size256
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub enum KeySize {
         Size128,
         Size192,
         Size256,
     }
-    impl Into<usize> for KeySize {
-        fn into(self) -> usize {
+
+    impl KeySize {
+        /// None for anything that isn't one of the three sizes we support, there is no falling back to a default
+        pub fn from_bits(bits: usize) -> Option<KeySize> {
+            match bits {
+                128 => Some(KeySize::Size128),
+                192 => Some(KeySize::Size192),
+                256 => Some(KeySize::Size256),
+                _ => None,
+            }
+        }
+
+        pub fn bits(self) -> usize {
             match self {
                 KeySize::Size128 => 128,
                 KeySize::Size192 => 192,
                 KeySize::Size256 => 256,
             }
         }
+
+        pub fn bytes(self) -> usize {
+            self.bits() / 8
+        }
     }
 
-    impl From<usize> for KeySize {
-        fn from(value: usize) -> Self {
-            match value {
-                128 => KeySize::Size128,
-                192 => KeySize::Size192,
-                256 => KeySize::Size256,
-                _ => Size256, // default to 256
-            }
+    /*
+       What the user gave us as the key. Text starting with hex: or base64: is the key itself written out, which
+       is the only way to get a binary key in, anything else is a passphrase to derive one from. No Debug on
+       purpose, keys have no business turning up in logs or panic messages.
+    */
+    #[derive(Clone, PartialEq, Eq)]
+    pub enum KeyMaterial {
+        Passphrase(String),
+        Raw(Vec<u8>),
+    }
+
+    /*
+       Which cipher and the key for it, everything that has to match between whoever encrypts and whoever decrypts
+    */
+    #[derive(Clone)]
+    pub struct CipherConfig {
+        pub enc_type: EncryptionInfo,
+        pub key: KeyMaterial,
+        pub key_size: KeySize,
+        pub salt: String,
+        pub iterations: u32,
+        pub rc4_drop: usize, // Keystream bytes to discard for RC4-drop[n], 0 for plain RC4
+    }
+
+    impl CipherConfig {
+        /*
+           Stretches a passphrase out to a full entropy key of the requested size. A raw key is used as it is,
+           salt and iterations don't come into it and parsing already made sure it is key_size long.
+        */
+        pub fn derive_key(&self) -> Vec<u8> {
+            let passphrase = match &self.key {
+                KeyMaterial::Passphrase(x) => x,
+                KeyMaterial::Raw(x) => return x.clone(),
+            };
+            let mut key = vec![0u8; self.key_size.bytes()];
+            pbkdf2_hmac_sha256(
+                passphrase.as_bytes(),
+                self.salt.as_bytes(),
+                self.iterations,
+                &mut key,
+            );
+            key
         }
     }
 
     pub struct KryptosConfig {
-        pub enc_type: EncryptionInfo,
-        pub key: String,
+        pub cipher: CipherConfig,
+        pub handshake: bool, // Do an X25519 key exchange on connect and use the passphrase only to authenticate it
+        pub handshake_timeout: Duration, // How long to wait for someone to handshake with
+        pub line_mode: bool, // Skip the TUI even when running in a terminal
+        pub reconnect: bool, // Reconnect with backoff instead of exiting when the connection drops
+        pub rekey_policy: RekeyPolicy,
         pub port: u16,
         pub ip: String,
     }
 
-    pub fn parse_arguments(args: Vec<String>) -> KryptosConfig {
-        let use_key: bool = args.len() == 5;
-        if (args.len() > 5) {
-            println!("Too many arguments!");
-            println!("Usage: kryptos-client ip port encryption-type key");
-            println!("Try --help for help.");
+    /// For encrypt and decrypt, None means stdin or stdout
+    pub struct FileConfig {
+        pub cipher: CipherConfig,
+        pub input: Option<String>,
+        pub output: Option<String>,
+    }
+
+    pub struct BenchConfig {
+        pub ciphers: Vec<EncryptionInfo>,
+        pub key_size: KeySize,
+        pub bytes: usize, // How much goes through each cipher each way
+    }
+
+    pub enum Subcommand {
+        Connect(KryptosConfig),
+        Encrypt(FileConfig),
+        Decrypt(FileConfig),
+        Keygen(KeySize),
+        Bench(BenchConfig),
+    }
+
+    /*
+       Every option there is. Help text that depends on the ciphers or defaults gets built from them here.
+    */
+    fn option_specs() -> Vec<OptionSpec> {
+        use CommandName::*;
+        const EVERY: &[CommandName] = &[Connect, Encrypt, Decrypt, Keygen, Bench];
+        const KEYED: &[CommandName] = &[Connect, Encrypt, Decrypt];
+
+        let ciphers: Vec<String> = EncryptionInfo::ALL
+            .iter()
+            .map(|x| match x.is_unsafe() {
+                true => format!("{} (unsafe)", x.name()),
+                false => x.name().to_string(),
+            })
+            .collect();
+
+        vec![
+            OptionSpec::flag("help", Some('h'), EVERY, "Show this help"),
+            OptionSpec::flag("version", Some('V'), EVERY, "Show the version"),
+            OptionSpec::value(
+                "config",
+                None,
+                "path",
+                EVERY,
+                "Read options from a file of name = value lines, the command line wins".to_string(),
+            ),
+            OptionSpec::value(
+                "host",
+                Some('H'),
+                "address",
+                &[Connect],
+                "Server to connect to (required)".to_string(),
+            ),
+            OptionSpec::value(
+                "port",
+                Some('p'),
+                "port",
+                &[Connect],
+                "Server port, outside the reserved range (required)".to_string(),
+            ),
+            OptionSpec::value(
+                "cipher",
+                Some('c'),
+                "name",
+                &[Connect, Encrypt, Decrypt, Bench],
+                format!(
+                    "{} (default: {}, {} for encrypt and decrypt, every cipher for bench)",
+                    ciphers.join(", "),
+                    DEFAULT_CIPHER.name(),
+                    DEFAULT_FILE_CIPHER.name()
+                ),
+            ),
+            OptionSpec::value(
+                "key-size",
+                Some('s'),
+                "bits",
+                EVERY,
+                "128, 192 or 256, ChaCha20Poly1305 is 256 only (default: 256)".to_string(),
+            ),
+            OptionSpec::value(
+                "key",
+                Some('k'),
+                "key",
+                KEYED,
+                "A passphrase to derive the key from with PBKDF2-HMAC-SHA256, or hex:... or base64:... for the key itself. Needs --allow-cli-key, anyone can see it in ps and the shell history".to_string(),
+            ),
+            OptionSpec::value(
+                "key-file",
+                Some('f'),
+                "path",
+                KEYED,
+                format!(
+                    "Read the key from the first line of a file. Without this or --key it comes from {} or is asked for",
+                    KEY_VARIABLE
+                ),
+            ),
+            OptionSpec::flag(
+                "allow-cli-key",
+                None,
+                KEYED,
+                "Accept --key on the command line (it is always fine in a config file)",
+            ),
+            OptionSpec::value(
+                "salt",
+                None,
+                "room",
+                KEYED,
+                format!(
+                    "Key derivation salt, must match the rest of the room (default: {})",
+                    DEFAULT_SALT
+                ),
+            ),
+            OptionSpec::value(
+                "iterations",
+                Some('i'),
+                "n",
+                KEYED,
+                format!(
+                    "PBKDF2 iteration count, must match the rest of the room (default: {})",
+                    PBKDF2_DEFAULT_ITERATIONS
+                ),
+            ),
+            OptionSpec::value(
+                "rc4-drop",
+                None,
+                "n",
+                KEYED,
+                format!(
+                    "Discard the first n bytes of RC4 keystream, must match the room ({} recommended, at most {}, default: 0)",
+                    RECOMMENDED_DROP_BYTES, MAX_DROP_BYTES
+                ),
+            ),
+            OptionSpec::flag(
+                "handshake",
+                None,
+                &[Connect],
+                "Agree on a fresh session key with an X25519 exchange when connecting",
+            ),
+            OptionSpec::flag(
+                "line-mode",
+                Some('l'),
+                &[Connect],
+                "Plain line by line input and output instead of the full screen terminal UI",
+            ),
+            OptionSpec::flag(
+                "reconnect",
+                Some('r'),
+                &[Connect],
+                "Keep trying to reconnect when the connection drops, messages typed meanwhile are sent once back",
+            ),
+            OptionSpec::value(
+                "rekey-messages",
+                None,
+                "n",
+                &[Connect],
+                "Move to a new key after sending this many messages, authenticated ciphers only (default: never)".to_string(),
+            ),
+            OptionSpec::value(
+                "rekey-bytes",
+                None,
+                "n",
+                &[Connect],
+                "Move to a new key after sending this many bytes, authenticated ciphers only (default: never)".to_string(),
+            ),
+            OptionSpec::value(
+                "rekey-seconds",
+                None,
+                "n",
+                &[Connect],
+                "Move to a new key after this long on one, authenticated ciphers only (default: never)".to_string(),
+            ),
+            OptionSpec::value(
+                "bytes",
+                Some('n'),
+                "n",
+                &[Bench],
+                format!(
+                    "How much to push through each cipher (default: {})",
+                    DEFAULT_BENCH_BYTES
+                ),
+            ),
+        ]
+    }
+
+    pub fn parse_arguments(args: Vec<String>) -> Subcommand {
+        if args.len() < 2 {
+            println!("{}", general_usage(PROGRAM));
             exit(ERROR);
         }
 
-        if { args.len() < 2 } {
-            println!("Usage: kryptos-client ip port encryption-type key");
-            println!("Try --help for help.");
-            exit(ERROR);
+        /*
+           Only offer to ask for the key when there is a terminal to ask on, otherwise leaving it out is an error
+        */
+        let sources = KeySources {
+            environment: env::var(KEY_VARIABLE).ok(),
+            prompt: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/tty")
+                .is_ok(),
+        };
+        match parse(&args[1..], &sources) {
+            Ok(Parsed::Run(x)) => x,
+            Ok(Parsed::Help(text)) => {
+                println!("{}", text);
+                exit(SUCCESS);
+            }
+            Ok(Parsed::Version) => {
+                println!("Kryptos client version {}", env!("CARGO_PKG_VERSION"));
+                exit(SUCCESS);
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                eprintln!("Try --help for help.");
+                exit(ERROR);
+            }
+        }
+    }
+
+    enum Parsed {
+        Run(Subcommand),
+        Help(String),
+        Version,
+    }
+
+    /*
+       Where a key can come from besides the options. Kept apart from the parsing so the tests don't pick up the
+       real environment or sit waiting at a prompt.
+    */
+    struct KeySources {
+        environment: Option<String>,
+        prompt: bool,
+    }
+
+    /*
+       The options that ended up set, from the config file and then the command line so the command line wins.
+       Flags are just present or not.
+    */
+    struct Values {
+        values: HashMap<&'static str, Option<String>>,
+    }
+
+    impl Values {
+        fn get(&self, name: &str) -> Option<&str> {
+            self.values.get(name).and_then(|x| x.as_deref())
         }
-        if (args[1] == "--help") {
-            println!("Usage: kryptos-client ip port encryption-type key");
-            println!("Encryption Options: AesCbc, AesCtr, AesEcb (unsafe), Rc4 (unsafe)");
-            println!("Key Size Options: 128, 192, 256");
-            println!("This is a simple encrypted telnet chat client written in Rust.");
-            println!("The server is available on my github");
-            println!("Options: --help, --version");
-            exit(SUCCESS);
+
+        fn flag(&self, name: &str) -> bool {
+            self.values.contains_key(name)
         }
 
-        if (args[1] == "--version") {
-            println!("Kryptos client version {}", env!("CARGO_PKG_VERSION"));
-            exit(SUCCESS);
+        fn parse<T>(
+            &self,
+            name: &'static str,
+            convert: impl Fn(&str) -> Option<T>,
+            reason: &str,
+        ) -> Result<Option<T>, ArgError> {
+            match self.get(name) {
+                None => Ok(None),
+                Some(value) => match convert(value) {
+                    Some(x) => Ok(Some(x)),
+                    None => Err(ArgError::Invalid {
+                        option: format!("--{}", name),
+                        reason: format!("{} {}", value, reason),
+                    }),
+                },
+            }
         }
+    }
 
-        if { args.len() < 5 } {
-            println!("Usage: kryptos-client ip port encryption-type key");
-            println!("Try --help for help.");
-            exit(ERROR);
+    fn parse(args: &[String], sources: &KeySources) -> Result<Parsed, ArgError> {
+        let specs = option_specs();
+        let (explicit, args) = match args.first() {
+            Some(x) if !x.starts_with('-') => match CommandName::from_name(x) {
+                Some(command) => (Some(command), &args[1..]),
+                None => return Err(ArgError::UnknownCommand(x.clone())),
+            },
+            _ => (None, args),
+        };
+        let command = explicit.unwrap_or(CommandName::Connect);
+
+        let tokens = tokenize(args, &specs, command)?;
+        let given = |name| tokens.options.iter().any(|(x, _)| *x == name);
+        if given("help") {
+            return Ok(Parsed::Help(match explicit {
+                Some(command) => command_usage(PROGRAM, &specs, command),
+                None => general_usage(PROGRAM),
+            }));
+        }
+        if given("version") {
+            return Ok(Parsed::Version);
         }
+        let key_on_command_line = given("key");
 
-        let ip = args[1].clone();
+        let mut values = HashMap::new();
+        let config_path = tokens
+            .options
+            .iter()
+            .rev()
+            .find(|(x, _)| *x == "config")
+            .and_then(|(_, value)| value.clone());
+        if let Some(path) = config_path {
+            let contents = read_file(&path)?;
+            values.extend(parse_config_file(&path, &contents, &specs, command)?);
+        }
+        values.extend(tokens.options);
+        let values = Values { values };
 
-        let port = match args[2].parse::<u16>() {
-            Ok(x) if x < 1024 => {
-                eprintln!("Port must not be in the reserved range!");
-                exit(ERROR);
-            }
-            Ok(x) => x,
-            Err(_) => {
-                eprintln!("Error occurred while parsing port!");
-                exit(ERROR);
+        /*
+           The config file is only readable by whoever it belongs to (or should be), the command line isn't
+        */
+        if key_on_command_line && !values.flag("allow-cli-key") {
+            return Err(ArgError::Invalid {
+                option: "--key".to_string(),
+                reason: format!(
+                    "the command line is visible to everyone, use --key-file, {} or the prompt instead (or --allow-cli-key if you really mean it)",
+                    KEY_VARIABLE
+                ),
+            });
+        }
+
+        let mut positional = tokens.positional.into_iter();
+        let subcommand = match command {
+            CommandName::Connect => Subcommand::Connect(connect_config(&values, sources)?),
+            CommandName::Encrypt | CommandName::Decrypt => {
+                /*
+                   Checked before the cipher config so nobody gets asked for a key only to be told this
+                */
+                let enc_type = enc_type(&values)?.unwrap_or(DEFAULT_FILE_CIPHER);
+                if !enc_type.supports_streaming() {
+                    let streaming: Vec<&str> = EncryptionInfo::ALL
+                        .into_iter()
+                        .filter(|x| x.supports_streaming())
+                        .map(|x| x.name())
+                        .collect();
+                    return Err(ArgError::Invalid {
+                        option: "--cipher".to_string(),
+                        reason: format!(
+                            "{} can't stream, {} works with {}",
+                            enc_type.name(),
+                            command.name(),
+                            streaming.join(", ")
+                        ),
+                    });
+                }
+                let config = FileConfig {
+                    cipher: cipher_config(&values, DEFAULT_FILE_CIPHER, sources)?,
+                    input: positional.next().filter(|x| x != "-"),
+                    output: positional.next().filter(|x| x != "-"),
+                };
+                match command {
+                    CommandName::Encrypt => Subcommand::Encrypt(config),
+                    _ => Subcommand::Decrypt(config),
+                }
             }
+            CommandName::Keygen => Subcommand::Keygen(key_size(&values)?),
+            CommandName::Bench => Subcommand::Bench(BenchConfig {
+                ciphers: match enc_type(&values)? {
+                    Some(x) => vec![x],
+                    None => EncryptionInfo::ALL.to_vec(),
+                },
+                key_size: key_size(&values)?,
+                bytes: values
+                    .parse(
+                        "bytes",
+                        |x| x.parse().ok().filter(|x| *x > 0),
+                        "is not a positive number",
+                    )?
+                    .unwrap_or(DEFAULT_BENCH_BYTES),
+            }),
         };
+        Ok(Parsed::Run(subcommand))
+    }
 
-        let encryption_type = match args[3].as_str() {
-            "AesCbc" => EncryptionInfo::AesCbc,
-            "AesCtr" => EncryptionInfo::AesCtr,
-            "AesEcb" => EncryptionInfo::AesEcb,
-            "Rc4" => EncryptionInfo::Rc4,
-            _ => {
-                eprintln!("Invalid encryption type!");
-                eprintln!("Try --help for help.");
-                exit(ERROR);
+    fn read_file(path: &str) -> Result<String, ArgError> {
+        fs::read_to_string(path).map_err(|error| ArgError::Io {
+            path: path.to_string(),
+            error,
+        })
+    }
+
+    fn enc_type(values: &Values) -> Result<Option<EncryptionInfo>, ArgError> {
+        values.parse("cipher", EncryptionInfo::from_name, "is not a cipher")
+    }
+
+    fn key_size(values: &Values) -> Result<KeySize, ArgError> {
+        Ok(values
+            .parse(
+                "key-size",
+                |x| x.parse().ok().and_then(KeySize::from_bits),
+                "is not one of 128, 192 or 256",
+            )?
+            .unwrap_or(KeySize::Size256))
+    }
+
+    fn cipher_config(
+        values: &Values,
+        default_cipher: EncryptionInfo,
+        sources: &KeySources,
+    ) -> Result<CipherConfig, ArgError> {
+        let enc_type = enc_type(values)?.unwrap_or(default_cipher);
+        let key_size = key_size(values)?;
+        if !enc_type.supports_key_size(key_size) {
+            return Err(ArgError::Invalid {
+                option: "--key-size".to_string(),
+                reason: format!("{} only supports 256 bit keys", enc_type.name()),
+            });
+        }
+        let iterations = values
+            .parse(
+                "iterations",
+                |x| x.parse().ok().filter(|x| *x > 0),
+                "is not a positive number",
+            )?
+            .unwrap_or(PBKDF2_DEFAULT_ITERATIONS);
+        /*
+           An empty salt would let one precomputed table cover every room using the same passphrase
+        */
+        let salt = values.get("salt").unwrap_or(DEFAULT_SALT);
+        if salt.is_empty() {
+            return Err(ArgError::Invalid {
+                option: "--salt".to_string(),
+                reason: "the salt must not be empty".to_string(),
+            });
+        }
+        let rc4_drop = values
+            .parse(
+                "rc4-drop",
+                |x| x.parse().ok().filter(|x| *x <= MAX_DROP_BYTES),
+                &format!("is not a number up to {}", MAX_DROP_BYTES),
+            )?
+            .unwrap_or(0);
+
+        Ok(CipherConfig {
+            enc_type,
+            key: key(values, key_size, sources)?,
+            key_size,
+            salt: salt.to_string(),
+            iterations,
+            rc4_drop,
+        })
+    }
+
+    /*
+       The first of --key, --key-file, the environment and the prompt that there is, left until everything
+       else has been checked since the prompt holds things up
+    */
+    fn key(
+        values: &Values,
+        key_size: KeySize,
+        sources: &KeySources,
+    ) -> Result<KeyMaterial, ArgError> {
+        let (text, source) = if let Some(key) = values.get("key") {
+            (key.to_string(), "--key")
+        } else if let Some(path) = values.get("key-file") {
+            let contents = read_file(path)?;
+            (
+                contents.lines().next().unwrap_or("").to_string(),
+                "--key-file",
+            )
+        } else if let Some(key) = &sources.environment {
+            (key.clone(), KEY_VARIABLE)
+        } else if sources.prompt {
+            let key = read_hidden("Key: ").map_err(|error| ArgError::Io {
+                path: "/dev/tty".to_string(),
+                error,
+            })?;
+            (key, "key")
+        } else {
+            return Err(ArgError::Required("key-file or KRYPTOS_KEY"));
+        };
+
+        let key = decode_key(text.trim(), source)?;
+        if let KeyMaterial::Raw(bytes) = &key {
+            if bytes.len() != key_size.bytes() {
+                return Err(ArgError::Invalid {
+                    option: source.to_string(),
+                    reason: format!(
+                        "the key is {} bits but --key-size is {}",
+                        bytes.len() * 8,
+                        key_size.bits()
+                    ),
+                });
             }
+        }
+        Ok(key)
+    }
+
+    fn decode_key(text: &str, source: &str) -> Result<KeyMaterial, ArgError> {
+        let invalid = |reason: &str| ArgError::Invalid {
+            option: source.to_string(),
+            reason: reason.to_string(),
         };
-        let key = args[4].trim().to_string();
+        if let Some(hex) = text.strip_prefix("hex:") {
+            return hex_decode(hex)
+                .map(KeyMaterial::Raw)
+                .ok_or_else(|| invalid("the key is not valid hex"));
+        }
+        if let Some(base64) = text.strip_prefix("base64:") {
+            return base64_decode(base64)
+                .map(KeyMaterial::Raw)
+                .ok_or_else(|| invalid("the key is not valid base64"));
+        }
 
-        let actual_size = key.len();
+        /*
+           Any passphrase works since it gets stretched out to a full key, it just can't be nothing
+        */
+        if text.is_empty() {
+            return Err(invalid("the passphrase must not be empty"));
+        }
+        Ok(KeyMaterial::Passphrase(text.to_string()))
+    }
 
-        if ((key.len()) * 8 != 128 && (key.len()) * 8 != 192 && (key.len() * 8) != 256) {
-            eprintln!("Invalid key!");
-            eprintln!(
-                "Valid key sizes are 128, 192, 256! The provided key was of length {}.",
-                actual_size * 8
-            );
-            exit(ERROR);
+    fn connect_config(values: &Values, sources: &KeySources) -> Result<KryptosConfig, ArgError> {
+        let ip = match values.get("host") {
+            Some(x) => x.to_string(),
+            None => return Err(ArgError::Required("host")),
+        };
+        let port = match values.parse(
+            "port",
+            |x| x.parse::<u16>().ok().filter(|x| *x >= 1024),
+            "is not a port outside the reserved range",
+        )? {
+            Some(x) => x,
+            None => return Err(ArgError::Required("port")),
+        };
+
+        let cipher = cipher_config(values, DEFAULT_CIPHER, sources)?;
+        let mut rekey_policy = RekeyPolicy::default();
+        if let Some(x) = rekey_limit(values, "rekey-messages", cipher.enc_type)? {
+            rekey_policy.max_messages = x;
+        }
+        if let Some(x) = rekey_limit(values, "rekey-bytes", cipher.enc_type)? {
+            rekey_policy.max_bytes = x;
+        }
+        if let Some(x) = rekey_limit(values, "rekey-seconds", cipher.enc_type)? {
+            rekey_policy.max_age = x.map(Duration::from_secs);
         }
 
-        let config = KryptosConfig {
-            enc_type: encryption_type,
-            key,
+        Ok(KryptosConfig {
+            cipher,
+            handshake: values.flag("handshake"),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            line_mode: values.flag("line-mode"),
+            reconnect: values.flag("reconnect"),
+            rekey_policy,
             port,
             ip,
-        };
+        })
+    }
 
-        config
+    /*
+       Zero means never rekey on this limit, the outer None is the option not being given at all.
+       The unauthenticated ciphers can't rekey, there's no telling a message under the old key from one
+       under the new key so it would just decrypt to garbage.
+    */
+    fn rekey_limit(
+        values: &Values,
+        name: &'static str,
+        enc_type: EncryptionInfo,
+    ) -> Result<Option<Option<u64>>, ArgError> {
+        let limit = values.parse(
+            name,
+            |x| x.parse::<u64>().ok().map(|x| Some(x).filter(|x| *x > 0)),
+            "is not a number",
+        )?;
+        if limit.is_some_and(|x| x.is_some()) && enc_type.is_unsafe() {
+            return Err(ArgError::Invalid {
+                option: format!("--{}", name),
+                reason: format!("{} isn't authenticated so it can't rekey", enc_type.name()),
+            });
+        }
+        Ok(limit)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::cryptography::encoding::hex_encode;
+
+        fn run(args: &[&str]) -> Result<Subcommand, ArgError> {
+            run_with(
+                args,
+                &KeySources {
+                    environment: None,
+                    prompt: false,
+                },
+            )
+        }
+
+        fn run_with(args: &[&str], sources: &KeySources) -> Result<Subcommand, ArgError> {
+            let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+            match parse(&args, sources)? {
+                Parsed::Run(x) => Ok(x),
+                _ => panic!("expected a command to run"),
+            }
+        }
+
+        fn passphrase(text: &str) -> KeyMaterial {
+            KeyMaterial::Passphrase(text.to_string())
+        }
+
+        #[test]
+        fn parses_connect_options() {
+            let config = match run(&[
+                "-H",
+                "10.0.0.1",
+                "--port=4000",
+                "-c",
+                "ChaCha20Poly1305",
+                "-rlk",
+                "pass",
+                "--allow-cli-key",
+                "--rekey-messages",
+                "0",
+            ]) {
+                Ok(Subcommand::Connect(x)) => x,
+                _ => panic!("expected connect"),
+            };
+            assert_eq!(config.ip, "10.0.0.1");
+            assert_eq!(config.port, 4000);
+            assert_eq!(config.cipher.enc_type, EncryptionInfo::ChaCha20Poly1305);
+            assert!(config.cipher.key == passphrase("pass"));
+            assert!(config.reconnect && config.line_mode && !config.handshake);
+            assert_eq!(config.rekey_policy.max_messages, None);
+            assert_eq!(config.rekey_policy.max_bytes, None);
+            assert_eq!(config.rekey_policy.max_age, None);
+            assert_eq!(config.cipher.iterations, PBKDF2_DEFAULT_ITERATIONS);
+
+            assert!(matches!(
+                run(&[
+                    "connect",
+                    "--host",
+                    "h",
+                    "--port",
+                    "4000",
+                    "--key",
+                    "k",
+                    "--allow-cli-key"
+                ]),
+                Ok(Subcommand::Connect(_))
+            ));
+        }
+
+        #[test]
+        fn parses_file_commands() {
+            let config = match run(&[
+                "decrypt",
+                "-k",
+                "pass",
+                "--allow-cli-key",
+                "--",
+                "-input",
+                "-",
+            ]) {
+                Ok(Subcommand::Decrypt(x)) => x,
+                _ => panic!("expected decrypt"),
+            };
+            assert_eq!(config.input.as_deref(), Some("-input"));
+            assert_eq!(config.output, None);
+            assert_eq!(config.cipher.enc_type, DEFAULT_FILE_CIPHER);
+
+            assert!(matches!(
+                run(&["encrypt", "-k", "pass", "--allow-cli-key", "-c", "AesGcm"]),
+                Err(ArgError::Invalid { .. })
+            ));
+            assert!(matches!(
+                run(&["encrypt", "-k", "pass", "--allow-cli-key", "a", "b", "c"]),
+                Err(ArgError::TooManyArguments(_))
+            ));
+            assert!(matches!(
+                run(&["keygen", "-s", "128"]),
+                Ok(Subcommand::Keygen(KeySize::Size128))
+            ));
+        }
+
+        #[test]
+        fn rejects_bad_arguments() {
+            assert!(matches!(run(&["chat"]), Err(ArgError::UnknownCommand(_))));
+            assert!(matches!(run(&["--bogus"]), Err(ArgError::UnknownOption(_))));
+            assert!(matches!(
+                run(&["keygen", "--host", "h"]),
+                Err(ArgError::NotForCommand { .. })
+            ));
+            assert!(matches!(
+                run(&["--host", "h", "--port", "80", "-k", "k", "--allow-cli-key"]),
+                Err(ArgError::Invalid { .. })
+            ));
+            assert!(matches!(
+                run(&["--host", "h", "--port", "4000"]),
+                Err(ArgError::Required(_))
+            ));
+            assert!(matches!(run(&["--host"]), Err(ArgError::MissingValue(_))));
+            assert!(matches!(
+                run(&["--reconnect=yes"]),
+                Err(ArgError::UnexpectedValue(_))
+            ));
+            assert!(matches!(
+                run(&[
+                    "-H",
+                    "h",
+                    "-p",
+                    "4000",
+                    "-k",
+                    "k",
+                    "--allow-cli-key",
+                    "-c",
+                    "AesGcm",
+                    "-s",
+                    "512"
+                ]),
+                Err(ArgError::Invalid { .. })
+            ));
+            assert!(matches!(
+                run(&[
+                    "-H",
+                    "h",
+                    "-p",
+                    "4000",
+                    "-k",
+                    "k",
+                    "--allow-cli-key",
+                    "-c",
+                    "ChaCha20Poly1305",
+                    "-s",
+                    "128"
+                ]),
+                Err(ArgError::Invalid { .. })
+            ));
+            let rekeying = |cipher: &str, limit: &str| {
+                run(&[
+                    "-H",
+                    "h",
+                    "-p",
+                    "4000",
+                    "-k",
+                    "k",
+                    "--allow-cli-key",
+                    "-c",
+                    cipher,
+                    "--rekey-messages",
+                    limit,
+                ])
+            };
+            assert!(matches!(
+                rekeying("Rc4", "10"),
+                Err(ArgError::Invalid { .. })
+            ));
+            assert!(matches!(
+                rekeying("AesEcb", "10"),
+                Err(ArgError::Invalid { .. })
+            ));
+            assert!(matches!(
+                rekeying("AesEcb", "0"),
+                Ok(Subcommand::Connect(_))
+            ));
+            assert!(matches!(
+                rekeying("AesCbc", "10"),
+                Ok(Subcommand::Connect(_))
+            ));
+        }
+
+        #[test]
+        fn validates_key_derivation_settings() {
+            let connect = |extra: &[&str]| {
+                let mut args = vec!["-H", "h", "-p", "4000", "-k", "k", "--allow-cli-key"];
+                args.extend_from_slice(extra);
+                run(&args)
+            };
+            let config = match connect(&["--salt", "room 7", "-i", "1"]) {
+                Ok(Subcommand::Connect(x)) => x,
+                _ => panic!("expected connect"),
+            };
+            assert_eq!(
+                (config.cipher.salt.as_str(), config.cipher.iterations),
+                ("room 7", 1)
+            );
+            let config = match connect(&["-c", "Rc4", "--rc4-drop", "4096"]) {
+                Ok(Subcommand::Connect(x)) => x,
+                _ => panic!("expected connect"),
+            };
+            assert_eq!(config.cipher.rc4_drop, MAX_DROP_BYTES);
+
+            for bad in [
+                &["-i", "0"][..],
+                &["-i", "-5"],
+                &["-i", "lots"],
+                &["-i", "4294967296"],
+                &["--salt", ""],
+                &["-c", "Rc4", "--rc4-drop", "4097"],
+                &["-c", "Rc4", "--rc4-drop", "-1"],
+            ] {
+                assert!(
+                    matches!(connect(bad), Err(ArgError::Invalid { .. })),
+                    "{:?}",
+                    bad
+                );
+            }
+        }
+
+        #[test]
+        fn command_line_overrides_the_config_file() {
+            let specs = option_specs();
+            let options = parse_config_file(
+                "test.conf",
+                "# a room\nhost = 10.0.0.1\nport = 4000 # the usual\nreconnect = true\nbytes = 5\n",
+                &specs,
+                CommandName::Connect,
+            )
+            .unwrap();
+            // bytes is for bench, connect skips it
+            assert_eq!(options.len(), 3);
+            assert!(
+                parse_config_file("test.conf", "colour = red", &specs, CommandName::Connect)
+                    .is_err()
+            );
+            assert!(parse_config_file(
+                "test.conf",
+                "reconnect = maybe",
+                &specs,
+                CommandName::Connect
+            )
+            .is_err());
+
+            let path = std::env::temp_dir().join(format!("kryptos-config-{}", std::process::id()));
+            fs::write(
+                &path,
+                "host = 10.0.0.1\nport = 4000\nkey = from file\nline-mode = yes\n",
+            )
+            .unwrap();
+            let result = run(&["--config", path.to_str().unwrap(), "--port", "5000"]);
+            fs::remove_file(&path).unwrap();
+            let config = match result {
+                Ok(Subcommand::Connect(x)) => x,
+                _ => panic!("expected connect"),
+            };
+            assert_eq!((config.ip.as_str(), config.port), ("10.0.0.1", 5000));
+            assert!(config.cipher.key == passphrase("from file"));
+            assert!(config.line_mode);
+        }
+
+        #[test]
+        fn reads_the_key_from_every_source() {
+            let environment = |key: &str| KeySources {
+                environment: Some(key.to_string()),
+                prompt: false,
+            };
+            let key = |result| match result {
+                Ok(Subcommand::Encrypt(x)) => x.cipher.key,
+                Err(e) => panic!("{}", e),
+                _ => panic!("expected encrypt"),
+            };
+
+            assert!(matches!(
+                run(&["encrypt", "-k", "pass"]),
+                Err(ArgError::Invalid { .. })
+            ));
+            assert!(matches!(run(&["encrypt"]), Err(ArgError::Required(_))));
+            assert!(
+                key(run_with(&["encrypt"], &environment("  from env \n")))
+                    == passphrase("from env")
+            );
+
+            let path = std::env::temp_dir().join(format!("kryptos-key-{}", std::process::id()));
+            let raw: Vec<u8> = (0..32).collect();
+            fs::write(&path, format!("hex:{}\nnot this line\n", hex_encode(&raw))).unwrap();
+            let from_file = run_with(
+                &["encrypt", "--key-file", path.to_str().unwrap()],
+                &environment("loses to the file"),
+            );
+            fs::remove_file(&path).unwrap();
+            assert!(key(from_file) == KeyMaterial::Raw(raw));
+
+            assert!(
+                key(run_with(
+                    &["encrypt", "-s", "128"],
+                    &environment("base64:AAECAwQFBgcICQoLDA0ODw==")
+                )) == KeyMaterial::Raw((0..16).collect())
+            );
+            // Raw keys have to be exactly the key size, and properly encoded
+            assert!(matches!(
+                run_with(
+                    &["encrypt"],
+                    &environment("base64:AAECAwQFBgcICQoLDA0ODw==")
+                ),
+                Err(ArgError::Invalid { .. })
+            ));
+            assert!(matches!(
+                run_with(&["encrypt"], &environment("hex:xyz")),
+                Err(ArgError::Invalid { .. })
+            ));
+            assert!(matches!(
+                run_with(&["encrypt"], &environment("   ")),
+                Err(ArgError::Invalid { .. })
+            ));
+        }
+
+        #[test]
+        fn help_lists_every_cipher() {
+            let specs = option_specs();
+            let usage = command_usage(PROGRAM, &specs, CommandName::Connect);
+            for cipher in EncryptionInfo::ALL {
+                assert!(usage.contains(cipher.name()));
+            }
+            assert!(usage.contains("--reconnect"));
+            assert!(!command_usage(PROGRAM, &specs, CommandName::Keygen).contains("--host"));
+        }
     }
 }
@@ -1 +1,2 @@
-pub mod arg_handling;
\ No newline at end of file
+pub mod arg_handling;
+pub mod options;
@@ -0,0 +1,364 @@
+use std::fmt;
+use std::io;
+
+/*
+   Everything the binary can be asked to do, the first argument picks one. Leaving it out means connect.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandName {
+    Connect,
+    Encrypt,
+    Decrypt,
+    Keygen,
+    Bench,
+}
+
+impl CommandName {
+    pub const ALL: [CommandName; 5] = [
+        CommandName::Connect,
+        CommandName::Encrypt,
+        CommandName::Decrypt,
+        CommandName::Keygen,
+        CommandName::Bench,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandName::Connect => "connect",
+            CommandName::Encrypt => "encrypt",
+            CommandName::Decrypt => "decrypt",
+            CommandName::Keygen => "keygen",
+            CommandName::Bench => "bench",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CommandName> {
+        CommandName::ALL.into_iter().find(|x| x.name() == name)
+    }
+
+    /// What goes after the options in its usage line
+    pub fn arguments(self) -> &'static str {
+        match self {
+            CommandName::Encrypt | CommandName::Decrypt => " [input [output]]",
+            _ => "",
+        }
+    }
+
+    /// How many arguments it takes that aren't options
+    pub fn max_positional(self) -> usize {
+        match self {
+            CommandName::Encrypt | CommandName::Decrypt => 2,
+            _ => 0,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            CommandName::Connect => "Join a chat room (the default when no command is given)",
+            CommandName::Encrypt => "Encrypt a file, or stdin to stdout",
+            CommandName::Decrypt => "Decrypt what encrypt produced",
+            CommandName::Keygen => "Print a random key",
+            CommandName::Bench => "Measure how fast each cipher is on this machine",
+        }
+    }
+}
+
+/*
+   One entry in the option table. The same table drives parsing, the config file and the help text so none of
+   them can drift apart.
+*/
+pub struct OptionSpec {
+    pub long: &'static str,
+    pub short: Option<char>,
+    pub value: Option<&'static str>, // What to call the value in the help, None for flags
+    pub help: String,
+    pub commands: &'static [CommandName],
+}
+
+impl OptionSpec {
+    pub fn flag(
+        long: &'static str,
+        short: Option<char>,
+        commands: &'static [CommandName],
+        help: &str,
+    ) -> OptionSpec {
+        OptionSpec {
+            long,
+            short,
+            value: None,
+            help: help.to_string(),
+            commands,
+        }
+    }
+
+    pub fn value(
+        long: &'static str,
+        short: Option<char>,
+        value: &'static str,
+        commands: &'static [CommandName],
+        help: String,
+    ) -> OptionSpec {
+        OptionSpec {
+            long,
+            short,
+            value: Some(value),
+            help,
+            commands,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ArgError {
+    UnknownCommand(String),
+    UnknownOption(String),
+    /// The option exists, just not for this command
+    NotForCommand {
+        option: String,
+        command: CommandName,
+    },
+    MissingValue(String),
+    /// A flag was given a value with --flag=value
+    UnexpectedValue(String),
+    TooManyArguments(String),
+    /// A required option was left out
+    Required(&'static str),
+    Invalid {
+        option: String,
+        reason: String,
+    },
+    ConfigFile {
+        path: String,
+        line: usize,
+        error: Box<ArgError>,
+    },
+    Io {
+        path: String,
+        error: io::Error,
+    },
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgError::UnknownCommand(command) => write!(f, "unknown command {}", command),
+            ArgError::UnknownOption(option) => write!(f, "unknown option {}", option),
+            ArgError::NotForCommand { option, command } => {
+                write!(f, "{} can't be used with {}", option, command.name())
+            }
+            ArgError::MissingValue(option) => write!(f, "option {} requires a value", option),
+            ArgError::UnexpectedValue(option) => {
+                write!(f, "option {} doesn't take a value", option)
+            }
+            ArgError::TooManyArguments(argument) => write!(f, "unexpected argument {}", argument),
+            ArgError::Required(option) => write!(f, "--{} is required", option),
+            ArgError::Invalid { option, reason } => write!(f, "invalid {}: {}", option, reason),
+            ArgError::ConfigFile { path, line, error } => {
+                write!(f, "{} line {}: {}", path, line, error)
+            }
+            ArgError::Io { path, error } => write!(f, "{}: {}", path, error),
+        }
+    }
+}
+
+impl std::error::Error for ArgError {}
+
+/*
+   The command line split up into options and everything else. Options are kept in the order given, long name
+   and value, flags get no value.
+*/
+#[derive(Debug, Default)]
+pub struct Tokens {
+    pub options: Vec<(&'static str, Option<String>)>,
+    pub positional: Vec<String>,
+}
+
+/*
+   The usual conventions, --name value, --name=value, -n value, -nvalue, flags bunched up as -abc, a lone - is an
+   argument (stdin/stdout) and everything after -- is an argument even if it looks like an option
+*/
+pub fn tokenize(
+    args: &[String],
+    specs: &[OptionSpec],
+    command: CommandName,
+) -> Result<Tokens, ArgError> {
+    let mut tokens = Tokens::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            tokens.positional.extend(args.by_ref().cloned());
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, inline) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let spec = find(specs, command, &format!("--{}", name), |x| x.long == name)?;
+            let value = match (spec.value, inline) {
+                (None, Some(_)) => return Err(ArgError::UnexpectedValue(arg.clone())),
+                (None, None) => None,
+                (Some(_), Some(x)) => Some(x),
+                (Some(_), None) => match args.next() {
+                    Some(x) => Some(x.clone()),
+                    None => return Err(ArgError::MissingValue(arg.clone())),
+                },
+            };
+            tokens.options.push((spec.long, value));
+            continue;
+        }
+
+        let shorts = match arg.strip_prefix('-') {
+            Some(x) if !x.is_empty() => x,
+            _ => {
+                tokens.positional.push(arg.clone());
+                continue;
+            }
+        };
+
+        /*
+           Flags until we hit one that takes a value, which gets the rest of the bunch or the next argument
+        */
+        for (index, short) in shorts.char_indices() {
+            let spec = find(specs, command, &format!("-{}", short), |x| {
+                x.short == Some(short)
+            })?;
+            if spec.value.is_none() {
+                tokens.options.push((spec.long, None));
+                continue;
+            }
+            let rest = &shorts[index + short.len_utf8()..];
+            let value = if !rest.is_empty() {
+                rest.to_string()
+            } else {
+                match args.next() {
+                    Some(x) => x.clone(),
+                    None => return Err(ArgError::MissingValue(format!("-{}", short))),
+                }
+            };
+            tokens.options.push((spec.long, Some(value)));
+            break;
+        }
+    }
+
+    if tokens.positional.len() > command.max_positional() {
+        return Err(ArgError::TooManyArguments(
+            tokens.positional[command.max_positional()].clone(),
+        ));
+    }
+    Ok(tokens)
+}
+
+fn find<'a>(
+    specs: &'a [OptionSpec],
+    command: CommandName,
+    shown_as: &str,
+    matches: impl Fn(&OptionSpec) -> bool,
+) -> Result<&'a OptionSpec, ArgError> {
+    let spec = match specs.iter().find(|x| matches(x)) {
+        Some(x) => x,
+        None => return Err(ArgError::UnknownOption(shown_as.to_string())),
+    };
+    if !spec.commands.contains(&command) {
+        return Err(ArgError::NotForCommand {
+            option: shown_as.to_string(),
+            command,
+        });
+    }
+    Ok(spec)
+}
+
+/*
+   A config file is one option per line as name = value, using the long option names. Flags take true or false.
+   Blank lines and anything after a # are ignored. Options meant for other commands are skipped so one file can
+   serve all of them.
+*/
+pub fn parse_config_file(
+    path: &str,
+    contents: &str,
+    specs: &[OptionSpec],
+    command: CommandName,
+) -> Result<Vec<(&'static str, Option<String>)>, ArgError> {
+    let mut options = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line_error = |error| ArgError::ConfigFile {
+            path: path.to_string(),
+            line: number + 1,
+            error: Box::new(error),
+        };
+
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return Err(line_error(ArgError::MissingValue(line.to_string()))),
+        };
+        let spec = match specs.iter().find(|x| x.long == name) {
+            Some(x) => x,
+            None => return Err(line_error(ArgError::UnknownOption(name.to_string()))),
+        };
+        if !spec.commands.contains(&command) {
+            continue;
+        }
+
+        if spec.value.is_some() {
+            options.push((spec.long, Some(value.to_string())));
+            continue;
+        }
+        match value {
+            "true" | "yes" | "on" => options.push((spec.long, None)),
+            "false" | "no" | "off" => {}
+            _ => {
+                return Err(line_error(ArgError::Invalid {
+                    option: name.to_string(),
+                    reason: "must be true or false".to_string(),
+                }))
+            }
+        }
+    }
+    Ok(options)
+}
+
+/*
+   Lists the commands, for when no particular one was asked about
+*/
+pub fn general_usage(program: &str) -> String {
+    let mut usage = format!("Usage: {} [command] [options]\n\nCommands:\n", program);
+    for command in CommandName::ALL {
+        usage.push_str(&format!(
+            "  {:<10} {}\n",
+            command.name(),
+            command.description()
+        ));
+    }
+    usage.push_str(&format!(
+        "\n{} <command> --help lists the options for that command.",
+        program
+    ));
+    usage
+}
+
+pub fn command_usage(program: &str, specs: &[OptionSpec], command: CommandName) -> String {
+    let mut usage = format!(
+        "Usage: {} {} [options]{}\n{}\n\nOptions:\n",
+        program,
+        command.name(),
+        command.arguments(),
+        command.description()
+    );
+    for spec in specs.iter().filter(|x| x.commands.contains(&command)) {
+        let mut name = match spec.short {
+            Some(short) => format!("-{}, --{}", short, spec.long),
+            None => format!("    --{}", spec.long),
+        };
+        if let Some(value) = spec.value {
+            name.push_str(&format!(" {}", value));
+        }
+        usage.push_str(&format!("  {:<28} {}\n", name, spec.help));
+    }
+    usage.pop();
+    usage
+}
@@ -0,0 +1,36 @@
+use kryptos::server::mock::MockServer;
+use kryptos::ERROR;
+use std::env;
+use std::process::exit;
+use std::thread::park;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9000";
+
+/*
+   Runs the mock server on its own so the client can be tried out without the real one. Relays everything it
+   gets to everyone connected until it is killed.
+*/
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 2 || args.get(1).is_some_and(|x| x == "--help") {
+        println!(
+            "Usage: kryptos-mock-server [address] (default: {})",
+            DEFAULT_ADDRESS
+        );
+        exit(ERROR);
+    }
+    let address = args.get(1).map(String::as_str).unwrap_or(DEFAULT_ADDRESS);
+
+    let server = match MockServer::start(address) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", address, e);
+            exit(ERROR);
+        }
+    };
+    println!("Relaying on {}", server.address());
+
+    loop {
+        park();
+    }
+}
@@ -0,0 +1,834 @@
+use crate::arg_handling::arg_handling::arg_handling::{EncryptionInfo, KeySize, KryptosConfig};
+use crate::client::error::ClientError;
+use crate::client::event::{ClientEvent, SendStatus};
+use crate::cryptography::cryptography::{CryptoError, EncryptionContext};
+use crate::cryptography::ratchet::RekeyPolicy;
+use crate::network::framing::{write_frame, FrameBuffer, FrameError};
+use crate::network::handshake::{is_handshake_frame, perform_handshake};
+use crate::network::message::{Message, MessageKind};
+use crate::network::reconnect::Backoff;
+use crate::network::replay::{now_millis, Delivery, OutgoingSequence, ReplayGuard};
+use crate::network::transfer::{
+    chunk_message, FileHeader, IncomingTransfers, ReceivedFiles, TransferError,
+    FILE_CHUNK_SIZE_BYTES,
+};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+type StateMachine = Arc<Mutex<EncryptionContext>>;
+/*
+   Files the read thread has finished receiving, waiting on whoever uses the client to say where they go
+*/
+type SharedReceivedFiles = Arc<Mutex<ReceivedFiles>>;
+type SharedOutgoing = Arc<Mutex<Outgoing>>;
+
+/*
+   How many chat messages we hold on to while reconnecting, past this they're dropped
+*/
+const MAX_PENDING_MESSAGES: usize = 256;
+
+/*
+   With a time limit on keys the reader wakes up at least this often to see if it's up, even when nobody is
+   saying anything. Otherwise an idle session would sit on one key until the next message went out.
+*/
+const REKEY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/*
+   Everything needed to set the session up again after losing the connection
+*/
+struct SessionSettings {
+    address: String,
+    pre_shared_key: Vec<u8>, // Straight out of PBKDF2, before any handshake
+    handshake: bool,
+    handshake_timeout: Duration,
+    enc_type: EncryptionInfo,
+    key_size: KeySize,
+    rc4_drop: usize,
+    rekey_policy: RekeyPolicy,
+    reconnect: bool,
+}
+
+/*
+   The sending side, shared between the caller and the reader (which swaps in a new socket when it
+   reconnects). stream is None while disconnected and messages collect in pending until we're back.
+*/
+struct Outgoing {
+    stream: Option<TcpStream>,
+    sequence: OutgoingSequence,
+    pending: VecDeque<Message>,
+    reconnect: bool,
+}
+
+impl Outgoing {
+    /*
+       Only chat waits for a reconnect. A rekey announcement or file chunk from before the connection dropped
+       would be out of place by the time it got sent.
+    */
+    fn hold(&mut self, message: Message) -> Result<SendStatus, ClientError> {
+        if !self.reconnect || message.kind != MessageKind::Chat {
+            return Err(ClientError::NotConnected);
+        }
+        if self.pending.len() >= MAX_PENDING_MESSAGES {
+            return Err(ClientError::QueueFull);
+        }
+        self.pending.push_back(message);
+        Ok(SendStatus::Queued {
+            waiting: self.pending.len(),
+        })
+    }
+
+    /*
+       Shutting the socket down makes sure the reader notices too, it either reconnects or ends the session
+    */
+    fn connection_lost(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/*
+   A connection to a Kryptos room. Connecting sets up the session (key derivation, handshake, cipher) and starts
+   a thread reading from the room, everything it receives comes out of next_event in order. Sending goes through
+   the methods here from whichever thread the caller likes.
+*/
+pub struct KryptosClient {
+    settings: Arc<SessionSettings>,
+    encryption_context: StateMachine,
+    outgoing: SharedOutgoing,
+    received_files: SharedReceivedFiles,
+    events: Mutex<Receiver<ClientEvent>>,
+    closing: Arc<AtomicBool>,
+    reader: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl KryptosClient {
+    pub fn connect(config: &KryptosConfig) -> Result<KryptosClient, ClientError> {
+        let settings = Arc::new(SessionSettings {
+            address: format!("{}:{}", config.ip, config.port),
+            pre_shared_key: config.cipher.derive_key(),
+            handshake: config.handshake,
+            handshake_timeout: config.handshake_timeout,
+            enc_type: config.cipher.enc_type,
+            key_size: config.cipher.key_size,
+            rc4_drop: config.cipher.rc4_drop,
+            rekey_policy: config.rekey_policy,
+            reconnect: config.reconnect,
+        });
+
+        let (stream, session_key) = connect_session(&settings)?;
+        let encryption_context = Arc::new(Mutex::new(create_context(&settings, &session_key)?));
+
+        /*
+           Reading and writing each get their own handle on the socket, the reader can sit blocked in read
+           without ever getting in the way of a write
+        */
+        let read_stream = stream.try_clone().map_err(ClientError::Io)?;
+        let outgoing: SharedOutgoing = Arc::new(Mutex::new(Outgoing {
+            stream: Some(stream),
+            sequence: OutgoingSequence::new(),
+            pending: VecDeque::new(),
+            reconnect: settings.reconnect,
+        }));
+        let received_files: SharedReceivedFiles = Arc::new(Mutex::new(ReceivedFiles::new()));
+        let closing = Arc::new(AtomicBool::new(false));
+        let (events, receiver) = channel();
+
+        let reader = Reader {
+            stream: read_stream,
+            stream_id: outgoing.lock().unwrap().sequence.stream_id(),
+            encryption_context: encryption_context.clone(),
+            outgoing: outgoing.clone(),
+            settings: settings.clone(),
+            received_files: received_files.clone(),
+            events,
+            closing: closing.clone(),
+            frames: FrameBuffer::new(),
+            replay_guard: ReplayGuard::new(),
+            transfers: IncomingTransfers::new(),
+        };
+        let reader = spawn(move || reader.run());
+
+        Ok(KryptosClient {
+            settings,
+            encryption_context,
+            outgoing,
+            received_files,
+            events: Mutex::new(receiver),
+            closing,
+            reader: Mutex::new(Some(reader)),
+        })
+    }
+
+    /// Sends a line of chat to the room
+    pub fn send(&self, text: &str) -> Result<SendStatus, ClientError> {
+        self.send_bytes(text.as_bytes())
+    }
+
+    /// Chat doesn't have to be text, the other side gets exactly these bytes
+    pub fn send_bytes(&self, body: &[u8]) -> Result<SendStatus, ClientError> {
+        send_message(
+            &self.outgoing,
+            &self.encryption_context,
+            Message::chat(body),
+        )
+    }
+
+    /*
+       Announces the file with its name, size and hash, then sends it a chunk at a time. Every chunk is its own
+       message so it gets the same encryption, sequence number and rekeying as chat does. Hands back the header
+       that went out.
+    */
+    pub fn send_file(&self, path: &Path) -> Result<FileHeader, ClientError> {
+        if !self.is_connected() {
+            return Err(ClientError::NotConnected);
+        }
+        let file_error = |error| ClientError::File {
+            path: path.display().to_string(),
+            error,
+        };
+        let header = FileHeader::for_path(path).map_err(file_error)?;
+        let mut file = File::open(path).map_err(file_error)?;
+
+        send_message(
+            &self.outgoing,
+            &self.encryption_context,
+            header.to_message(),
+        )?;
+
+        /*
+           Always at least one chunk, even an empty file needs one to tell the other side it is complete
+        */
+        let mut buffer = vec![0u8; FILE_CHUNK_SIZE_BYTES];
+        let mut sent = 0u64;
+        loop {
+            let read = file.read(&mut buffer).map_err(file_error)?;
+            sent += read as u64;
+            let chunk = chunk_message(header.transfer_id, &buffer[..read]);
+            send_message(&self.outgoing, &self.encryption_context, chunk)?;
+            if read == 0 || sent >= header.size {
+                break;
+            }
+        }
+        Ok(header)
+    }
+
+    /*
+       For talking to the server itself. Still framed so the stream stays in step, anyone else in the room
+       just sees a message that fails to decrypt and drops it.
+    */
+    pub fn send_raw(&self, line: &str) -> Result<(), ClientError> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        let mut stream = match &outgoing.stream {
+            Some(x) => x,
+            None => return Err(ClientError::NotConnected),
+        };
+        if let Err(e) = write_frame(&mut stream, line.as_bytes()) {
+            outgoing.connection_lost();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Moves to the next key right away instead of waiting for the rekey policy to call for it, returns the new epoch
+    pub fn rekey(&self) -> Result<u64, ClientError> {
+        let mut context = self.encryption_context.lock().unwrap();
+        send_rekey(&mut self.outgoing.lock().unwrap(), &mut context)?;
+        Ok(context.ratchet.epoch())
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.encryption_context.lock().unwrap().ratchet.epoch()
+    }
+
+    pub fn address(&self) -> &str {
+        &self.settings.address
+    }
+
+    /// False while reconnecting and once the session is over
+    pub fn is_connected(&self) -> bool {
+        self.outgoing.lock().unwrap().stream.is_some()
+    }
+
+    /*
+       Saves the oldest file waiting, under the name it was sent with unless told otherwise. A directory means
+       save it in there under its own name. Hands back the file's name and where it ended up.
+    */
+    pub fn save_received_file(&self, path: Option<&str>) -> Result<(String, PathBuf), ClientError> {
+        let file = match self.received_files.lock().unwrap().pop_front() {
+            Some(x) => x,
+            None => return Err(ClientError::NoReceivedFiles),
+        };
+
+        let mut path = PathBuf::from(path.unwrap_or(&file.name));
+        if path.is_dir() {
+            path.push(&file.name);
+        }
+
+        match file.save(&path) {
+            Ok(_) => Ok((file.name, path)),
+            Err(error) => {
+                // Put it back so the caller can try somewhere else
+                self.received_files.lock().unwrap().push_front(file);
+                Err(ClientError::File {
+                    path: path.display().to_string(),
+                    error,
+                })
+            }
+        }
+    }
+
+    /// Drops the oldest file waiting to be saved, hands back its name
+    pub fn discard_received_file(&self) -> Option<String> {
+        self.received_files
+            .lock()
+            .unwrap()
+            .pop_front()
+            .map(|x| x.name)
+    }
+
+    /// Waits for the next thing to happen, None once the session is over and every event has been handed out
+    pub fn next_event(&self) -> Option<ClientEvent> {
+        self.events.lock().unwrap().recv().ok()
+    }
+
+    /// next_event that gives up after timeout, None if nothing happened in time
+    pub fn next_event_timeout(&self, timeout: Duration) -> Option<ClientEvent> {
+        self.events.lock().unwrap().recv_timeout(timeout).ok()
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = ClientEvent> + '_ {
+        std::iter::from_fn(|| self.next_event())
+    }
+
+    /*
+       Shutting the socket down wakes the reader out of its blocking read, and with closing set it knows the
+       connection going away was us and not the server. A reader in the middle of reconnecting sees closing
+       before it hands over a new socket. Safe to call more than once, dropping the client calls it too.
+    */
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        if let Some(stream) = &self.outgoing.lock().unwrap().stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(reader) = self.reader.lock().unwrap().take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+impl Drop for KryptosClient {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/*
+   Opens the connection and, if we're doing one, runs the handshake. Hands back the socket and the key to
+   encrypt with, which is just the passphrase key when there is no handshake.
+*/
+fn connect_session(settings: &SessionSettings) -> Result<(TcpStream, Vec<u8>), ClientError> {
+    let mut stream =
+        TcpStream::connect(&settings.address).map_err(|error| ClientError::Connect {
+            address: settings.address.clone(),
+            error,
+        })?;
+
+    /*
+       With a handshake the passphrase derived key only authenticates the exchange, what we actually
+       encrypt with is the fresh key that comes out of it
+    */
+    let mut session_key = settings.pre_shared_key.clone();
+    if settings.handshake {
+        /*
+           The read timeout is what gets us out of a read when nobody in the room is saying anything, the
+           deadline covers a room that's busy with everything but a handshake for us
+        */
+        stream
+            .set_read_timeout(Some(settings.handshake_timeout))
+            .map_err(ClientError::Io)?;
+        perform_handshake(
+            &mut stream,
+            &settings.pre_shared_key,
+            &mut session_key,
+            Instant::now() + settings.handshake_timeout,
+        )?;
+        stream.set_read_timeout(None).map_err(ClientError::Io)?;
+    }
+    Ok((stream, session_key))
+}
+
+fn create_context(
+    settings: &SessionSettings,
+    session_key: &[u8],
+) -> Result<EncryptionContext, ClientError> {
+    let mut state = EncryptionContext::create(
+        settings.enc_type,
+        settings.key_size,
+        session_key,
+        settings.rc4_drop,
+    )?;
+    state.ratchet.set_policy(settings.rekey_policy);
+    Ok(state)
+}
+
+/*
+   The read thread. Everything it learns goes out as a ClientEvent, it never decides anything for the caller
+   beyond reconnecting when that is turned on.
+*/
+struct Reader {
+    stream: TcpStream,
+    encryption_context: StateMachine,
+    outgoing: SharedOutgoing,
+    settings: Arc<SessionSettings>,
+    received_files: SharedReceivedFiles,
+    events: Sender<ClientEvent>,
+    closing: Arc<AtomicBool>,
+    /*
+       Lives across reads since a single read can leave us holding a partial frame that the next read completes
+    */
+    frames: FrameBuffer,
+    replay_guard: ReplayGuard,
+    transfers: IncomingTransfers,
+    stream_id: u64, // Our own, so we can tell our files coming back off the relay from anyone else's
+}
+
+impl Reader {
+    fn run(mut self) {
+        self.start_rekey_timer();
+        let reason = self.read_until_closed();
+        self.notify(ClientEvent::Closed(reason));
+    }
+
+    /*
+       Nobody listening just means the client is on its way out
+    */
+    fn notify(&self, event: ClientEvent) {
+        let _ = self.events.send(event);
+    }
+
+    fn notice(&self, text: String) {
+        self.notify(ClientEvent::Notice(text));
+    }
+
+    /*
+       Returns why the session ended, None when it was us closing it
+    */
+    fn read_until_closed(&mut self) -> Option<ClientError> {
+        // Big enough that a whole file chunk usually comes in with one read
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            /*
+               Blocks until the server sends something or the connection goes away, either way nothing else
+               waits on us. The only time we wake up without either is to check a time limited key.
+            */
+            let result = self.stream.read(&mut buffer);
+            if self.closing.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            let lost = match result {
+                Ok(0) => {
+                    self.drop_partial_frame();
+                    ClientError::ConnectionClosed
+                }
+                Ok(n) => {
+                    self.frames.push(&buffer[..n]);
+                    /*
+                       Once a length header is bad we have no idea where the next frame starts so there is
+                       no recovering the stream from here
+                    */
+                    match self.handle_frames() {
+                        Ok(()) => {
+                            self.rekey_if_due();
+                            continue;
+                        }
+                        Err(e) => ClientError::Frame(e),
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    self.rekey_if_due();
+                    continue;
+                }
+                Err(e) => {
+                    self.drop_partial_frame();
+                    ClientError::Io(e)
+                }
+            };
+
+            // Nothing more goes out on the old socket, from here until we're back messages wait in pending
+            self.outgoing.lock().unwrap().connection_lost();
+            if !self.settings.reconnect {
+                return Some(lost);
+            }
+            self.notify(ClientEvent::ConnectionLost(lost));
+
+            self.stream = self.reconnect()?;
+            self.frames = FrameBuffer::new();
+            self.start_rekey_timer();
+        }
+    }
+
+    /*
+       Only needed with a time limit on keys, the read timeout is what wakes us up to check it
+    */
+    fn start_rekey_timer(&self) {
+        let interval = match self.settings.rekey_policy.max_age {
+            Some(x) => x.clamp(Duration::from_millis(10), REKEY_CHECK_INTERVAL),
+            None => return,
+        };
+        if let Err(e) = self.stream.set_read_timeout(Some(interval)) {
+            self.notice(format!("Warning: keys won't be rotated on time: {}", e));
+        }
+    }
+
+    /*
+       send_message only looks at the rekey policy after sending, this covers the time limit running out
+       while we're quiet. Nothing happens while disconnected, the announcement would only sit in the queue.
+    */
+    fn rekey_if_due(&self) {
+        let mut context = self.encryption_context.lock().unwrap();
+        if !context.ratchet.rekey_due() || !context.can_rekey() {
+            return;
+        }
+        let mut outgoing = self.outgoing.lock().unwrap();
+        if outgoing.stream.is_none() {
+            return;
+        }
+        if let Err(e) = send_rekey(&mut outgoing, &mut context) {
+            drop(outgoing);
+            drop(context);
+            self.notice(format!("Failed to rekey: {}", e));
+        }
+    }
+
+    fn drop_partial_frame(&self) {
+        if let Err(e) = self.frames.finish() {
+            self.notice(format!("Dropped incomplete message: {}", e));
+        }
+    }
+
+    fn handle_frames(&mut self) -> Result<(), FrameError> {
+        while let Some(frame) = self.frames.next_frame()? {
+            self.handle_frame(frame);
+        }
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, mut frame: Vec<u8>) {
+        /*
+           Other clients' handshakes (and the relay's echo of our own) go to everyone in the room, they aren't
+           encrypted traffic so there's nothing to drop or warn about
+        */
+        if is_handshake_frame(&frame) {
+            return;
+        }
+
+        let mut decrypted_buffer = vec![0; frame.len()];
+        let mut encryption_context = match self.encryption_context.lock() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
+        /*
+           A frame we can't decrypt (tampered, truncated, wrong key) only costs us that one message,
+           the framing is still intact so we carry on with the next one
+        */
+        if let Err(e) = encryption_context.decrypt(&mut frame, &mut decrypted_buffer) {
+            drop(encryption_context);
+            self.notice(format!("Dropped a message: {}", e));
+            return;
+        }
+
+        let message = match Message::decode(&decrypted_buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                drop(encryption_context);
+                self.notice(format!("Dropped a message: {}", e));
+                return;
+            }
+        };
+
+        /*
+           Only now that the message has authenticated do we trust its sequence number, a replayed or
+           stale message never gets to rekey us or reach the caller
+        */
+        match self.replay_guard.check(
+            message.stream_id,
+            message.sequence,
+            message.timestamp,
+            now_millis(),
+        ) {
+            Ok(Delivery::InOrder) => {}
+            Ok(Delivery::Reordered) => {
+                self.notice(format!(
+                    "Warning: message {} arrived out of order",
+                    message.sequence
+                ));
+            }
+            Err(e) => {
+                drop(encryption_context);
+                self.notice(format!("Warning: dropped a message: {}", e));
+                return;
+            }
+        }
+
+        /*
+           The peer has moved on to a new key, catch up to the same epoch. If we already rekeyed
+           to it ourselves there is nothing to do.
+        */
+        if let Some(epoch) = message.epoch() {
+            if let Err(e) = encryption_context.catch_up(epoch) {
+                drop(encryption_context);
+                self.notice(format!("Warning: dropped a message: {}", e));
+                return;
+            }
+        }
+        // Let go of the context before anything else so a sender never waits on us
+        drop(encryption_context);
+
+        match message.kind {
+            MessageKind::Chat => self.notify(ClientEvent::Message(message)),
+            MessageKind::Rekey => {}
+            MessageKind::FileStart | MessageKind::FileChunk => self.handle_file_message(&message),
+        }
+    }
+
+    /*
+       Feeds file transfer messages into the transfers in progress, a file that finishes and passes its hash
+       check gets queued up for the caller to save
+    */
+    fn handle_file_message(&mut self, message: &Message) {
+        // The relay sends our own files back to us, there's no point collecting a copy of what we already have
+        if message.stream_id == self.stream_id {
+            return;
+        }
+
+        if message.kind == MessageKind::FileStart {
+            let header = match FileHeader::from_message(message) {
+                Ok(x) => x,
+                Err(e) => {
+                    self.notice(format!("Dropped a message: {}", e));
+                    return;
+                }
+            };
+            if !self.received_files.lock().unwrap().has_room(header.size) {
+                let error = TransferError::ReceivedQueueFull(header.name);
+                self.notice(format!("Warning: refused a file: {}", error));
+                return;
+            }
+            match self.transfers.start(message.stream_id, header) {
+                Ok(header) => self.notice(format!(
+                    "Receiving file {} ({} bytes)",
+                    header.name, header.size
+                )),
+                Err(e) => self.notice(format!("Warning: refused a file: {}", e)),
+            }
+            return;
+        }
+
+        match self.transfers.chunk(message.stream_id, message) {
+            Ok(Some(file)) => {
+                let event = ClientEvent::FileReceived {
+                    name: file.name.clone(),
+                    size: file.data.len(),
+                };
+                // Others may have finished since this one started, so there might not be room for it any more
+                match self.received_files.lock().unwrap().push_back(file) {
+                    Ok(_) => self.notify(event),
+                    Err(e) => self.notice(format!("Warning: refused a file: {}", e)),
+                }
+            }
+            Ok(None) => {}
+            Err(e) => self.notice(format!("Warning: file transfer failed: {}", e)),
+        }
+    }
+
+    /*
+       Keeps trying until there is a new connection, or returns None if the client is closed first. Once connected
+       the sending side switches over to the new socket and everything sent while we were gone goes out.
+    */
+    fn reconnect(&mut self) -> Option<TcpStream> {
+        let mut backoff = Backoff::default();
+        loop {
+            let delay = backoff.next_delay();
+            self.notify(ClientEvent::Reconnecting {
+                attempt: backoff.attempt(),
+                delay,
+            });
+            if !sleep_unless_closing(delay, &self.closing) {
+                return None;
+            }
+
+            let (stream, session_key) = match connect_session(&self.settings) {
+                Ok(x) => x,
+                Err(e) => {
+                    self.notice(format!("Reconnect failed: {}", e));
+                    continue;
+                }
+            };
+            let read_stream = match stream.try_clone() {
+                Ok(x) => x,
+                Err(e) => {
+                    self.notice(format!("Reconnect failed: {}", ClientError::Io(e)));
+                    continue;
+                }
+            };
+
+            /*
+               A handshake means a brand new session key, so the cipher, ratchet and what we've seen from
+               everyone start over along with it. Whoever we shared the old key with lost the relay too (or
+               we'd have nobody to handshake with) and is doing the same. Without a handshake the rest of the
+               room is still on our current key and epoch, so we just carry on with those.
+            */
+            let mut fresh_context = None;
+            if self.settings.handshake {
+                match create_context(&self.settings, &session_key) {
+                    Ok(x) => fresh_context = Some(x),
+                    Err(e) => {
+                        self.notice(format!("Reconnect failed: {}", e));
+                        continue;
+                    }
+                }
+            }
+
+            let mut context = self.encryption_context.lock().unwrap();
+            let mut outgoing = self.outgoing.lock().unwrap();
+            if self.closing.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Some(fresh_context) = fresh_context {
+                *context = fresh_context;
+                self.replay_guard = ReplayGuard::new();
+                self.transfers = IncomingTransfers::new();
+            }
+            outgoing.stream = Some(stream);
+
+            let pending: Vec<Message> = outgoing.pending.drain(..).collect();
+            let mut sent = 0;
+            for message in pending {
+                match send_locked(&mut outgoing, &mut context, message) {
+                    Ok(SendStatus::Sent) => sent += 1,
+                    Ok(SendStatus::Queued { .. }) => {}
+                    Err(e) => self.notice(format!("Failed to send a waiting message: {}", e)),
+                }
+            }
+            self.notify(ClientEvent::Reconnected { sent });
+            return Some(read_stream);
+        }
+    }
+}
+
+/*
+   Encrypts and sends a single message, then rekeys if we've now sent enough under the current key.
+   The encryption context stays locked the whole time so nothing else can get encrypted between us
+   announcing the new epoch and actually switching to it.
+*/
+fn send_message(
+    outgoing: &SharedOutgoing,
+    encryption_context: &StateMachine,
+    message: Message,
+) -> Result<SendStatus, ClientError> {
+    let mut context = encryption_context.lock().unwrap();
+    let mut outgoing = outgoing.lock().unwrap();
+    send_locked(&mut outgoing, &mut context, message)
+}
+
+/*
+   send_message for when both locks are already held. The encryption context always gets locked before
+   outgoing, by both threads, so they can never end up waiting on each other.
+*/
+fn send_locked(
+    outgoing: &mut Outgoing,
+    context: &mut EncryptionContext,
+    message: Message,
+) -> Result<SendStatus, ClientError> {
+    let length = message.body.len();
+    let status = encrypt_and_write(outgoing, context, message)?;
+    if status == SendStatus::Sent {
+        context.ratchet.record(length);
+        if context.ratchet.rekey_due() {
+            send_rekey(outgoing, context)?;
+        }
+    }
+    Ok(status)
+}
+
+/*
+   The rekey announcement goes out under the old key, the peer can't decrypt anything else until it
+   has seen it anyway. The caller holds both locks across the whole thing.
+*/
+fn send_rekey(outgoing: &mut Outgoing, context: &mut EncryptionContext) -> Result<(), ClientError> {
+    // Don't tell the room we're moving on to a key we can't actually switch to
+    if !context.can_rekey() {
+        return Err(CryptoError::RekeyUnsupported.into());
+    }
+    let announcement = Message::rekey(context.ratchet.epoch() + 1);
+    encrypt_and_write(outgoing, context, announcement)?;
+    context.rekey()?;
+    Ok(())
+}
+
+/*
+   Sleeps a little at a time so closing never has to wait out a long backoff, false if we're closing
+*/
+fn sleep_unless_closing(duration: Duration, closing: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    while !closing.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        sleep((deadline - now).min(Duration::from_millis(100)));
+    }
+    false
+}
+
+fn encrypt_and_write(
+    outgoing: &mut Outgoing,
+    context: &mut EncryptionContext,
+    mut message: Message,
+) -> Result<SendStatus, ClientError> {
+    let mut stream = match &outgoing.stream {
+        Some(x) => x,
+        None => return outgoing.hold(message),
+    };
+
+    message.stream_id = outgoing.sequence.stream_id();
+    message.sequence = outgoing.sequence.next_sequence();
+    // Anything that sat in the queue while we were reconnecting goes out stamped with when it actually left
+    message.timestamp = now_millis();
+    let mut encrypted_buffer = Vec::new();
+    context
+        .context
+        .encrypt(&mut message.encode(), &mut encrypted_buffer)?;
+
+    /*
+       One encrypt call, one frame, so the reader on the other end decrypts exactly what we encrypted here.
+       If the write fails the reader finds out from the socket being shut down, with reconnecting on the
+       message just waits for the new connection.
+    */
+    if let Err(e) = write_frame(&mut stream, encrypted_buffer.as_slice()) {
+        outgoing.connection_lost();
+        if outgoing.reconnect {
+            return outgoing.hold(message);
+        }
+        return Err(e.into());
+    }
+    Ok(SendStatus::Sent)
+}
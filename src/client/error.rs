@@ -0,0 +1,79 @@
+use crate::cryptography::cryptography::CryptoError;
+use crate::network::framing::FrameError;
+use crate::network::handshake::HandshakeError;
+use std::fmt;
+use std::io;
+
+/*
+   Everything the client hands back instead of exiting. Whether one of these ends the session is up to whoever
+   is driving the client, the only ones the client treats as final itself come through ClientEvent::Closed.
+*/
+#[derive(Debug)]
+pub enum ClientError {
+    Connect {
+        address: String,
+        error: io::Error,
+    },
+    Handshake(HandshakeError),
+    Encryption(CryptoError),
+    /// Writing to the server failed, or it sent a frame we can't make sense of
+    Frame(FrameError),
+    /// Reading from the server failed
+    Io(io::Error),
+    /// The server hung up on us
+    ConnectionClosed,
+    /// There is no connection to send on, either it was closed or reconnecting is turned off
+    NotConnected,
+    /// Disconnected with too many messages already waiting for the reconnect
+    QueueFull,
+    /// A file being sent or saved, holds the path
+    File {
+        path: String,
+        error: io::Error,
+    },
+    NoReceivedFiles,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Connect { address, error } => {
+                write!(f, "could not connect to {}: {}", address, error)
+            }
+            ClientError::Handshake(error) => write!(f, "handshake failed: {}", error),
+            ClientError::Encryption(error) => write!(f, "encryption failed: {}", error),
+            ClientError::Frame(error) => write!(f, "{}", error),
+            ClientError::Io(error) => write!(f, "failed reading from the server: {}", error),
+            ClientError::ConnectionClosed => write!(f, "remote server has closed the connection"),
+            ClientError::NotConnected => write!(f, "not connected"),
+            ClientError::QueueFull => {
+                write!(
+                    f,
+                    "not connected and too many messages waiting, message dropped"
+                )
+            }
+            ClientError::File { path, error } => write!(f, "{}: {}", path, error),
+            ClientError::NoReceivedFiles => write!(f, "no received files waiting"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<HandshakeError> for ClientError {
+    fn from(error: HandshakeError) -> Self {
+        ClientError::Handshake(error)
+    }
+}
+
+impl From<CryptoError> for ClientError {
+    fn from(error: CryptoError) -> Self {
+        ClientError::Encryption(error)
+    }
+}
+
+impl From<FrameError> for ClientError {
+    fn from(error: FrameError) -> Self {
+        ClientError::Frame(error)
+    }
+}
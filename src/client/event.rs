@@ -0,0 +1,41 @@
+use crate::client::error::ClientError;
+use crate::network::message::Message;
+use std::time::Duration;
+
+/*
+   Everything the read thread has to tell whoever is using the client, in the order it happened. Closed is
+   always the last one, after it the channel ends.
+*/
+#[derive(Debug)]
+pub enum ClientEvent {
+    /// A chat message from the room, already decrypted and past the replay check
+    Message(Message),
+    /// A file came in whole and its hash checked out, it waits for save_received_file or discard_received_file
+    FileReceived {
+        name: String,
+        size: usize,
+    },
+    /// Something worth telling the user that isn't an error for the session, a dropped message and the like
+    Notice(String),
+    /// The connection went away, with reconnecting turned on we start trying to get it back
+    ConnectionLost(ClientError),
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// Back on a new connection, sent is how many of the messages waiting for it went out
+    Reconnected {
+        sent: usize,
+    },
+    /// The session is over, None when it was us that closed it
+    Closed(Option<ClientError>),
+}
+
+/*
+   A chat message either went straight out or is waiting on a reconnect
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendStatus {
+    Sent,
+    Queued { waiting: usize },
+}
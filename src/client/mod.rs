@@ -0,0 +1,3 @@
+pub mod connection;
+pub mod error;
+pub mod event;
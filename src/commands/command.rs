@@ -0,0 +1,182 @@
+use std::fmt;
+
+/*
+   Everything the user can type at the prompt. A line that doesn't start with / is just chat, a line
+   starting with // sends the rest of it as chat so messages can still start with a slash.
+*/
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Chat(String),
+    Quit,
+    Nick(String),
+    Me(String),
+    Clear,
+    Help,
+    Rekey,
+    Cipher,
+    Raw(String),
+    Send(String),
+    Save(Option<String>),
+    Discard,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown(String),
+    /// Holds the usage line for the command that was missing its argument
+    MissingArgument(&'static str),
+    /// Holds the usage line for the command that was given something it doesn't take
+    UnexpectedArgument(&'static str),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => {
+                write!(f, "unknown command /{}, try /help", name)
+            }
+            CommandError::MissingArgument(usage) | CommandError::UnexpectedArgument(usage) => {
+                write!(f, "usage: {}", usage)
+            }
+        }
+    }
+}
+
+/*
+   Usage line and description for each command, in the order /help lists them
+*/
+pub const COMMAND_HELP: &[(&str, &str)] = &[
+    ("/nick <name>", "Set the name your messages are sent under"),
+    ("/me <action>", "Send an action, shown as * name action"),
+    ("/send <path>", "Send a file to the room"),
+    (
+        "/save [path]",
+        "Save the oldest received file, to its own name by default",
+    ),
+    ("/discard", "Drop the oldest received file"),
+    (
+        "/rekey",
+        "Move to a new key now instead of waiting for the rekey limits",
+    ),
+    ("/cipher", "Show the cipher and key size in use"),
+    (
+        "/raw <line>",
+        "Send a line to the server unencrypted, anyone on the path can read it",
+    ),
+    ("/clear", "Clear the screen"),
+    ("/help", "Show this list"),
+    ("/quit", "Disconnect and exit"),
+];
+
+fn usage(name: &str) -> &'static str {
+    COMMAND_HELP
+        .iter()
+        .map(|(usage, _)| *usage)
+        .find(|usage| usage[1..].split(' ').next() == Some(name))
+        .unwrap_or("/help")
+}
+
+impl Command {
+    /// Parses a line that has already been trimmed and is not empty
+    pub fn parse(line: &str) -> Result<Command, CommandError> {
+        let command = match line.strip_prefix('/') {
+            Some(x) => x,
+            None => return Ok(Command::Chat(line.to_string())),
+        };
+        if command.starts_with('/') {
+            return Ok(Command::Chat(command.to_string()));
+        }
+
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+
+        let required = |build: fn(String) -> Command| {
+            if argument.is_empty() {
+                Err(CommandError::MissingArgument(usage(name)))
+            } else {
+                Ok(build(argument.to_string()))
+            }
+        };
+        let bare = |command: Command| {
+            if argument.is_empty() {
+                Ok(command)
+            } else {
+                Err(CommandError::UnexpectedArgument(usage(name)))
+            }
+        };
+
+        match name {
+            "nick" => {
+                // Names go in front of every message, so keep them to a single word
+                if argument.contains(char::is_whitespace) {
+                    return Err(CommandError::UnexpectedArgument(usage(name)));
+                }
+                required(Command::Nick)
+            }
+            "me" => required(Command::Me),
+            "raw" => required(Command::Raw),
+            "send" => required(Command::Send),
+            "save" => Ok(Command::Save(
+                Some(argument.to_string()).filter(|x| !x.is_empty()),
+            )),
+            "quit" => bare(Command::Quit),
+            "clear" => bare(Command::Clear),
+            "help" => bare(Command::Help),
+            "rekey" => bare(Command::Rekey),
+            "cipher" => bare(Command::Cipher),
+            "discard" => bare(Command::Discard),
+            _ => Err(CommandError::Unknown(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("hello"), Ok(Command::Chat("hello".into())));
+        assert_eq!(Command::parse("//etc"), Ok(Command::Chat("/etc".into())));
+        assert_eq!(Command::parse("/quit"), Ok(Command::Quit));
+        assert_eq!(
+            Command::parse("/nick  bob"),
+            Ok(Command::Nick("bob".into()))
+        );
+        assert_eq!(
+            Command::parse("/me waves hello"),
+            Ok(Command::Me("waves hello".into()))
+        );
+        assert_eq!(
+            Command::parse("/raw PING server"),
+            Ok(Command::Raw("PING server".into()))
+        );
+        assert_eq!(Command::parse("/save"), Ok(Command::Save(None)));
+        assert_eq!(
+            Command::parse("/save /tmp/x"),
+            Ok(Command::Save(Some("/tmp/x".into())))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert_eq!(
+            Command::parse("/frobnicate now"),
+            Err(CommandError::Unknown("frobnicate".into()))
+        );
+        assert_eq!(
+            Command::parse("/nick"),
+            Err(CommandError::MissingArgument("/nick <name>"))
+        );
+        assert_eq!(
+            Command::parse("/nick two words"),
+            Err(CommandError::UnexpectedArgument("/nick <name>"))
+        );
+        assert_eq!(
+            Command::parse("/quit now"),
+            Err(CommandError::UnexpectedArgument("/quit"))
+        );
+    }
+}
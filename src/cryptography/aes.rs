@@ -1,10 +1,29 @@
-use crate::cryptography::cryptography::Encryption;
+use crate::cryptography::cryptography::{constant_time_eq, CryptoError, Encryption};
+use crate::cryptography::hmac::{hmac_sha256, HmacSha256, HMAC_SHA256_LENGTH_BYTES};
+use crate::cryptography::streaming::{StreamDirection, StreamingEncryption};
 use rand::RngCore;
 use std::cmp::PartialEq;
 
 const AES_BLOCK_LENGTH_BYTES: usize = 16;
 const AES_KEY_LENGTH_BYTES_MAX: usize = 32;
 
+/*
+   GCM uses the standard 96 bit nonce (so the counter block is nonce || 32 bit counter) and the full 128 bit tag
+*/
+const GCM_NONCE_LENGTH_BYTES: usize = 12;
+const GCM_TAG_LENGTH_BYTES: usize = 16;
+
+/*
+   The reduction polynomial x^128 + x^7 + x^2 + x + 1 in GCM's reflected bit order
+*/
+const GCM_REDUCTION: u128 = 0xe1 << 120;
+
+/*
+   CBC and CTR don't authenticate anything on their own so their output gets an HMAC-SHA256 tag appended,
+   keyed with a MAC key derived from the session key using this label so the two keys are never the same
+*/
+const MAC_KEY_LABEL: &[u8] = b"kryptos encrypt-then-mac key";
+
 const NUM_COLUMNS: u8 = 4;
 
 type AesState = [[u8; 4]; 4];
@@ -91,6 +110,59 @@ fn multiply(x: u8, y: u8) -> u8 {
     // The multiplication follows the logic of the AES algorithm for multiplication in GF(2^8).
 }
 
+/*
+   Multiplication in GF(2^128) for GHASH, as per NIST SP 800-38D algorithm 1.
+   GCM numbers its bits from the most significant end so bit 0 of x is the top bit of the u128.
+   This is the plain shift and add version, slow compared to table driven versions but it does not
+   index any tables with secret data so it doesn't leak through the cache.
+*/
+fn gf_128_multiply(x: u128, y: u128) -> u128 {
+    let mut z = 0u128;
+    let mut v = y;
+
+    for i in 0..128 {
+        // Masks instead of branches so every bit costs the same regardless of the key
+        let x_bit = (x >> (127 - i)) & 1;
+        z ^= v & 0u128.wrapping_sub(x_bit);
+
+        let lsb = v & 1;
+        v >>= 1;
+        v ^= GCM_REDUCTION & 0u128.wrapping_sub(lsb);
+    }
+    z
+}
+
+/*
+   PKCS#7, always adds between 1 and 16 bytes each holding the padding length. A message that is already
+   block aligned gets a whole extra block so the last byte can always be trusted to say how much to strip.
+*/
+fn pkcs7_pad(buffer: &mut Vec<u8>) {
+    let padding_length = AES_BLOCK_LENGTH_BYTES - (buffer.len() % AES_BLOCK_LENGTH_BYTES);
+    buffer.resize(buffer.len() + padding_length, padding_length as u8);
+}
+
+/*
+   Anything that isn't exactly what pkcs7_pad would have produced is an error, we never guess
+*/
+fn pkcs7_unpad(buffer: &mut Vec<u8>) -> Result<(), CryptoError> {
+    let padding_length = match buffer.last() {
+        Some(&x) => x as usize,
+        None => return Err(CryptoError::BadPadding),
+    };
+    if padding_length == 0
+        || padding_length > AES_BLOCK_LENGTH_BYTES
+        || padding_length > buffer.len()
+        || !buffer[buffer.len() - padding_length..]
+            .iter()
+            .all(|&byte| byte as usize == padding_length)
+    {
+        return Err(CryptoError::BadPadding);
+    }
+
+    buffer.truncate(buffer.len() - padding_length);
+    Ok(())
+}
+
 /*
     Convert to and from a C-style 2d array.
 
@@ -118,12 +190,15 @@ fn from_2d_array(state: &AesState) -> [u8; 16] {
     buffer
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AesMode {
     CBC, // Cipher block chaining
     ECB, //Codebook
     CTR, // Counter
+    GCM, // Galois counter mode (authenticated)
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum AesSize {
     S128, // 128-bit key
     S192, // 192-bit key
@@ -138,6 +213,31 @@ pub struct AESContext {
     key: [u8; AES_KEY_LENGTH_BYTES_MAX],
     round_keys: [u8; 256], //240 bytes holds all of the round keys with a 256 bit key
     initialization_vector: [u8; AES_BLOCK_LENGTH_BYTES],
+    associated_data: Vec<u8>, // Only used by GCM, authenticated but not encrypted
+    mac_key: [u8; HMAC_SHA256_LENGTH_BYTES], // Only used by CBC and CTR for encrypt-then-MAC
+    stream: Option<AesStream>, // The streaming encryption in progress, if any
+}
+
+/*
+   Everything a CBC or CTR stream needs to carry between updates
+*/
+struct AesStream {
+    direction: StreamDirection,
+    mac: HmacSha256, // Runs over the IV and ciphertext as they go past, same as the one shot tag
+    chaining: [u8; AES_BLOCK_LENGTH_BYTES], // CBC: the previous ciphertext block, CTR: the next counter block
+    keystream: [u8; AES_BLOCK_LENGTH_BYTES], // CTR only, the current block of keystream
+    keystream_used: usize,
+    pending: Vec<u8>, // Input we can't process yet, a partial block or what might be the tag
+    has_iv: bool,
+    received: usize, // Total bytes fed in, only for error reporting
+}
+
+impl AesStream {
+    fn start(&mut self, initialization_vector: [u8; AES_BLOCK_LENGTH_BYTES]) {
+        self.chaining = initialization_vector;
+        self.mac.update(&initialization_vector);
+        self.has_iv = true;
+    }
 }
 
 impl PartialEq<AesSize> for AesSize {
@@ -158,25 +258,20 @@ impl PartialEq<AesSize> for AesSize {
 }
 
 impl AESContext {
-    pub fn new(mode: AesMode, size: AesSize, key: Option<&[u8]>) -> Self {
+    pub fn new(mode: AesMode, size: AesSize, key: Option<&[u8]>) -> Result<Self, CryptoError> {
         let mut new = AESContext {
             mode,
             size,
             key: [0u8; 32],
             round_keys: [0u8; 256],
             initialization_vector: [0u8; 16],
+            associated_data: Vec::new(),
+            stream: None,
+            mac_key: [0u8; HMAC_SHA256_LENGTH_BYTES],
         };
 
-        if key.is_some() {
-            let key = key.unwrap();
-            let key_size = match new.size {
-                AesSize::S128 => 128,
-                AesSize::S192 => 192,
-                AesSize::S256 => 256,
-            };
-            for i in 0..key_size / 8 {
-                new.key[i] = key[i];
-            }
+        if let Some(key) = key {
+            new.set_key(key)?;
         } else {
             let mut key = [0u8; 32];
             rand::rng().fill_bytes(&mut key); // Generate a full key regardless of size it just won't use the extra bytes for sub 256 bit keys
@@ -193,7 +288,7 @@ impl AESContext {
         rand::rng().fill_bytes(&mut new.initialization_vector);
         new.initialize_context();
 
-        new
+        Ok(new)
     }
     fn add_round_key(&mut self, round: u8, state: &mut AesState) {
         for i in 0..4 {
@@ -386,16 +481,98 @@ impl AESContext {
     }
     fn initialize_context(&mut self) {
         self.key_expansion();
+        self.derive_mac_key();
+    }
+
+    fn key_length_bytes(&self) -> usize {
+        match self.size {
+            AesSize::S128 => 16,
+            AesSize::S192 => 24,
+            AesSize::S256 => 32,
+        }
+    }
+
+    fn derive_mac_key(&mut self) {
+        let key_length = self.key_length_bytes();
+        self.mac_key = hmac_sha256(&self.key[..key_length], MAC_KEY_LABEL);
+    }
+
+    /*
+       The modes that get an encrypt-then-MAC tag, GCM has its own tag and ECB is a lost cause anyway
+    */
+    fn uses_encrypt_then_mac(&self) -> bool {
+        self.mode == AesMode::CBC || self.mode == AesMode::CTR
+    }
+
+    /*
+       CBC and ECB only work on whole blocks so they get PKCS#7 padding, CTR and GCM are stream modes
+       and the ciphertext is exactly as long as the plaintext
+    */
+    fn uses_padding(&self) -> bool {
+        self.mode == AesMode::CBC || self.mode == AesMode::ECB
+    }
+
+    /*
+       Checks and strips the HMAC tag off the end of the message. Nothing gets decrypted
+       unless this passes.
+    */
+    fn verify_and_strip_mac(&self, input: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let minimum = AES_BLOCK_LENGTH_BYTES + HMAC_SHA256_LENGTH_BYTES;
+        if input.len() < minimum {
+            return Err(CryptoError::CiphertextTooShort {
+                minimum,
+                actual: input.len(),
+            });
+        }
+
+        let message_length = input.len() - HMAC_SHA256_LENGTH_BYTES;
+        let expected_tag = hmac_sha256(&self.mac_key, &input[..message_length]);
+        if !constant_time_eq(&expected_tag, &input[message_length..]) {
+            return Err(CryptoError::AuthenticationFailed);
+        }
+
+        input.truncate(message_length);
+        Ok(())
+    }
+
+    /*
+       Make sure the ciphertext is something this mode could actually have produced before we go indexing into it,
+       the IV/nonce prefix has to be there and block modes need whole blocks
+    */
+    fn check_ciphertext_length(&self, input: &[u8]) -> Result<(), CryptoError> {
+        let (prefix, block_aligned) = match self.mode {
+            AesMode::CBC => (AES_BLOCK_LENGTH_BYTES, true),
+            AesMode::ECB => (0, true),
+            AesMode::CTR => (AES_BLOCK_LENGTH_BYTES, false),
+            AesMode::GCM => (GCM_NONCE_LENGTH_BYTES + GCM_TAG_LENGTH_BYTES, false),
+        };
+
+        let minimum = if block_aligned {
+            prefix + AES_BLOCK_LENGTH_BYTES
+        } else {
+            prefix
+        };
+        if input.len() < minimum {
+            return Err(CryptoError::CiphertextTooShort {
+                minimum,
+                actual: input.len(),
+            });
+        }
+
+        if block_aligned && (input.len() - prefix) % AES_BLOCK_LENGTH_BYTES != 0 {
+            return Err(CryptoError::MisalignedCiphertext {
+                block_size: AES_BLOCK_LENGTH_BYTES,
+                actual: input.len() - prefix,
+            });
+        }
+        Ok(())
     }
 
     fn set_initialization_vector(&mut self, iv: &[u8]) {
         self.key_expansion();
 
-        for (i, byte) in iv.iter().enumerate() {
+        for (i, byte) in iv.iter().take(AES_BLOCK_LENGTH_BYTES).enumerate() {
             self.initialization_vector[i] = *byte;
-            if i == AES_BLOCK_LENGTH_BYTES {
-                break;
-            }
         }
     }
 
@@ -516,10 +693,6 @@ impl AESContext {
 
     */
     fn cbc_encrypt(&mut self, buffer: &[u8], output: &mut Vec<u8>) {
-        /*
-           Generate a fresh IV every encryption operation
-        */
-        self.generate_initialization_vector();
         /*
            Casting these just in case it goes negative on the subtraction operation, don't want wraparound or panic because of this
         */
@@ -597,9 +770,6 @@ impl AESContext {
     }
 
     fn ctr_encrypt(&mut self, buffer: &[u8], output: &mut Vec<u8>) {
-        /*
-           Generate a fresh IV every encryption operation
-        */
         let mut xor_buffer;
         /*
            Casting these just in case it goes negative on the subtraction operation, don't want wraparound or panic because of this
@@ -609,12 +779,10 @@ impl AESContext {
 
         /*
            We need to treat encryption and decryption different.
-           On encryption, we need to generate a new nonce to use as a counter.
+           On encryption, we use the fresh nonce generated by encrypt as a counter.
            On decryption we need to extract the nonce from the prefix of the input buffer (first 16 bytes)
         */
 
-        self.generate_initialization_vector();
-
         /*
            Resize if required to store the 16 byte IV as a prefix to the rest of the data
         */
@@ -690,64 +858,139 @@ impl AESContext {
         }
     }
     /*
-       Functions below are just for testing. I can remove them but fuggit they can stay
+       Set the associated data that GCM will authenticate alongside every message. Both sides have to use the
+       same associated data or every tag check will fail. It is empty unless set.
     */
-    pub fn test_round_key(&mut self, key: &[u8], round: usize) -> bool {
-        let key_size = match self.size {
-            AesSize::S128 => 128,
-            AesSize::S192 => 192,
-            AesSize::S256 => 256,
-        };
-        let start = round * (key_size / 8);
-        let end = start + (key_size / 8);
-        let round_key = &self.round_keys[start..end];
+    pub fn set_associated_data(&mut self, associated_data: &[u8]) {
+        self.associated_data = associated_data.to_vec();
+    }
 
-        for i in 0..key_size / 8 {
-            if key[i] != round_key[i] {
-                return false;
+    /*
+       GHASH over the associated data and ciphertext, each zero padded out to a block boundary
+       and followed by a final block holding both of their lengths in bits
+    */
+    fn ghash(&self, hash_key: u128, ciphertext: &[u8]) -> u128 {
+        let mut tag = 0u128;
+
+        for data in [self.associated_data.as_slice(), ciphertext] {
+            for chunk in data.chunks(AES_BLOCK_LENGTH_BYTES) {
+                let mut block = [0u8; AES_BLOCK_LENGTH_BYTES];
+                block[..chunk.len()].copy_from_slice(chunk);
+                tag = gf_128_multiply(tag ^ u128::from_be_bytes(block), hash_key);
             }
         }
 
-        true
+        let lengths =
+            ((self.associated_data.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        gf_128_multiply(tag ^ lengths, hash_key)
     }
 
-    pub fn print_round_keys(&mut self, key: &[u8; AES_KEY_LENGTH_BYTES_MAX]) {
-        self.set_key(key);
-        self.key_expansion();
-        let num_rounds = match self.size {
-            AesSize::S128 => 10,
-            AesSize::S192 => 12,
-            AesSize::S256 => 14,
-        };
-        for i in 0..=num_rounds {
-            let start = i * 16;
-            let end = start + 16;
-
-            println!(
-                "round {} round key: {:02x?}",
-                i,
-                &self.round_keys[start..end]
-            );
+    /*
+       The hash key H is just the all zero block run through the cipher
+    */
+    fn gcm_hash_key(&mut self) -> u128 {
+        let mut hash_key = [0u8; AES_BLOCK_LENGTH_BYTES];
+        self.cipher(&[0u8; AES_BLOCK_LENGTH_BYTES], &mut hash_key);
+        u128::from_be_bytes(hash_key)
+    }
+
+    /*
+       GCTR from SP 800-38D, only the low 32 bits of the counter block are incremented
+       and they wrap around without touching the nonce
+    */
+    fn gcm_counter_mode(
+        &mut self,
+        initial_counter: [u8; AES_BLOCK_LENGTH_BYTES],
+        buffer: &[u8],
+        output: &mut [u8],
+    ) {
+        let mut counter_block = initial_counter;
+        let mut keystream = [0u8; AES_BLOCK_LENGTH_BYTES];
+
+        for (i, chunk) in buffer.chunks(AES_BLOCK_LENGTH_BYTES).enumerate() {
+            self.cipher(&counter_block, &mut keystream);
+            for (j, byte) in chunk.iter().enumerate() {
+                output[i * AES_BLOCK_LENGTH_BYTES + j] = byte ^ keystream[j];
+            }
+
+            let mut counter = [0u8; 4];
+            counter.copy_from_slice(&counter_block[GCM_NONCE_LENGTH_BYTES..]);
+            let counter = u32::from_be_bytes(counter).wrapping_add(1);
+            counter_block[GCM_NONCE_LENGTH_BYTES..].copy_from_slice(&counter.to_be_bytes());
         }
     }
-}
 
-impl PartialEq for AesMode {
-    fn eq(&self, other: &Self) -> bool {
-        match self {
-            AesMode::CBC => match other {
-                AesMode::CBC => true,
-                _ => false,
-            },
-            AesMode::ECB => match other {
-                AesMode::ECB => true,
-                _ => false,
-            },
-            AesMode::CTR => match other {
-                AesMode::CTR => true,
-                _ => false,
-            },
+    /*
+       J0 from the spec, the nonce followed by a 32 bit counter starting at 1. The block with counter 1 is only used to
+       encrypt the tag, the message itself starts at counter 2.
+    */
+    fn gcm_first_counter_block(nonce: &[u8]) -> [u8; AES_BLOCK_LENGTH_BYTES] {
+        let mut counter_block = [0u8; AES_BLOCK_LENGTH_BYTES];
+        counter_block[..GCM_NONCE_LENGTH_BYTES].copy_from_slice(&nonce[..GCM_NONCE_LENGTH_BYTES]);
+        counter_block[AES_BLOCK_LENGTH_BYTES - 1] = 1;
+        counter_block
+    }
+
+    fn gcm_tag(&mut self, nonce: &[u8], ciphertext: &[u8]) -> [u8; GCM_TAG_LENGTH_BYTES] {
+        let hash_key = self.gcm_hash_key();
+        let hash = self.ghash(hash_key, ciphertext);
+
+        let mut encrypted_counter = [0u8; AES_BLOCK_LENGTH_BYTES];
+        self.cipher(
+            &Self::gcm_first_counter_block(nonce),
+            &mut encrypted_counter,
+        );
+
+        (u128::from_be_bytes(encrypted_counter) ^ hash).to_be_bytes()
+    }
+
+    /*
+       Output layout is nonce (12 bytes) || ciphertext || tag (16 bytes), same idea as CBC/CTR
+       carrying the IV as a prefix. There is no padding since this is a counter mode under the hood.
+    */
+    fn gcm_encrypt(&mut self, buffer: &[u8], output: &mut Vec<u8>) {
+        let mut nonce = [0u8; GCM_NONCE_LENGTH_BYTES];
+        rand::fill(&mut nonce);
+
+        output.resize(
+            GCM_NONCE_LENGTH_BYTES + buffer.len() + GCM_TAG_LENGTH_BYTES,
+            0,
+        );
+        output[..GCM_NONCE_LENGTH_BYTES].copy_from_slice(&nonce);
+
+        let mut counter_block = Self::gcm_first_counter_block(&nonce);
+        counter_block[AES_BLOCK_LENGTH_BYTES - 1] = 2;
+        self.gcm_counter_mode(
+            counter_block,
+            buffer,
+            &mut output[GCM_NONCE_LENGTH_BYTES..GCM_NONCE_LENGTH_BYTES + buffer.len()],
+        );
+
+        let tag = self.gcm_tag(
+            &nonce,
+            &output[GCM_NONCE_LENGTH_BYTES..GCM_NONCE_LENGTH_BYTES + buffer.len()],
+        );
+        output[GCM_NONCE_LENGTH_BYTES + buffer.len()..].copy_from_slice(&tag);
+    }
+
+    /*
+       Nothing is decrypted unless the tag verifies, the length has already been checked by the caller
+    */
+    fn gcm_decrypt(&mut self, buffer: &[u8], output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let nonce = &buffer[..GCM_NONCE_LENGTH_BYTES];
+        let ciphertext = &buffer[GCM_NONCE_LENGTH_BYTES..buffer.len() - GCM_TAG_LENGTH_BYTES];
+        let received_tag = &buffer[buffer.len() - GCM_TAG_LENGTH_BYTES..];
+
+        let expected_tag = self.gcm_tag(nonce, ciphertext);
+        if !constant_time_eq(&expected_tag, received_tag) {
+            return Err(CryptoError::AuthenticationFailed);
         }
+
+        output.resize(ciphertext.len(), 0);
+        let mut counter_block = Self::gcm_first_counter_block(nonce);
+        counter_block[AES_BLOCK_LENGTH_BYTES - 1] = 2;
+        self.gcm_counter_mode(counter_block, ciphertext, output);
+        Ok(())
     }
 }
 
@@ -756,23 +999,34 @@ impl Encryption for AESContext {
         self.initialize_context();
     }
 
-    fn encrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) {
-        let mut len = input.len();
-        let output_len = output.len();
-
-        if (self.mode != AesMode::ECB) {
-            output.resize(len + AES_BLOCK_LENGTH_BYTES, 0);
+    fn encrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        /*
+           GCM is a counter mode so it needs no padding, handle it before any of the block mode padding below
+        */
+        if self.mode == AesMode::GCM {
+            self.gcm_encrypt(input, output);
+            return Ok(());
         }
-        let padding_len = (AES_BLOCK_LENGTH_BYTES - (len % AES_BLOCK_LENGTH_BYTES));
-        if (padding_len < AES_BLOCK_LENGTH_BYTES) {
-            for _ in 0..padding_len {
-                input.push(padding_len as u8);
-            }
+
+        if self.uses_padding() {
+            pkcs7_pad(input);
         }
 
-        if (output.len() < input.len()) {
+        /*
+           Everything but ECB carries the 16 byte IV in front of the ciphertext
+        */
+        if self.mode != AesMode::ECB {
+            output.resize(input.len() + AES_BLOCK_LENGTH_BYTES, 0);
+        } else {
             output.resize(input.len(), 0);
         }
+
+        /*
+           Generate a fresh IV every encryption operation, the mode functions just use whatever IV is set
+        */
+        if self.mode != AesMode::ECB {
+            self.generate_initialization_vector();
+        }
         match self.mode {
             AesMode::CBC => {
                 self.cbc_encrypt(input, output);
@@ -791,12 +1045,34 @@ impl Encryption for AESContext {
             AesMode::CTR => {
                 self.ctr_encrypt(input, output);
             }
+            AesMode::GCM => unreachable!("GCM is handled before padding"),
         }
+
+        /*
+           Encrypt-then-MAC, the tag covers the IV and all of the ciphertext
+        */
+        if self.uses_encrypt_then_mac() {
+            let tag = hmac_sha256(&self.mac_key, output);
+            output.extend_from_slice(&tag);
+        }
+        Ok(())
     }
 
-    fn decrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) {
+    fn decrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        /*
+           A message that fails authentication is an error rather than whatever garbage
+           the forged ciphertext would have decrypted to
+        */
+        if self.uses_encrypt_then_mac() {
+            self.verify_and_strip_mac(input)?;
+        }
+        self.check_ciphertext_length(input)?;
+
+        if self.mode == AesMode::GCM {
+            return self.gcm_decrypt(input, output);
+        }
+
         let input_size = input.len();
-        let output_size = output.len();
 
         if (self.mode != AesMode::ECB) {
             output.resize(input_size - AES_BLOCK_LENGTH_BYTES, 0); // Shave off the IV
@@ -820,36 +1096,676 @@ impl Encryption for AESContext {
             AesMode::CTR => {
                 self.ctr_decrypt(input, output);
             }
+            AesMode::GCM => unreachable!("GCM is handled before unpadding"),
+        }
+
+        if self.uses_padding() {
+            pkcs7_unpad(output)?;
+        }
+        Ok(())
+    }
+
+    fn set_key(&mut self, key: &[u8]) -> Result<(), CryptoError> {
+        if key.len() != self.key_length_bytes() {
+            return Err(CryptoError::InvalidKeyLength(key.len()));
+        }
+        self.key[..key.len()].copy_from_slice(key);
+        self.initialize_context();
+        Ok(())
+    }
+
+    fn get_key(&self) -> &[u8] {
+        &self.key[..self.key_length_bytes()]
+    }
+
+    fn is_authenticated(&self) -> bool {
+        self.mode != AesMode::ECB
+    }
+}
+
+impl AESContext {
+    /*
+       Encrypts as much of the pending input as we can, every whole block for CBC and everything for CTR
+    */
+    fn stream_encrypt_pending(&mut self, stream: &mut AesStream, output: &mut Vec<u8>) {
+        let start = output.len();
+
+        if self.mode == AesMode::CBC {
+            let length = stream.pending.len() / AES_BLOCK_LENGTH_BYTES * AES_BLOCK_LENGTH_BYTES;
+            let mut block = [0u8; AES_BLOCK_LENGTH_BYTES];
+            for chunk in stream.pending[..length].chunks(AES_BLOCK_LENGTH_BYTES) {
+                let mut current_slice = [0u8; AES_BLOCK_LENGTH_BYTES];
+                current_slice.copy_from_slice(chunk);
+                self.xor_with_initialization_vector(&mut current_slice, Some(&stream.chaining));
+                self.cipher(&current_slice, &mut block);
+                stream.chaining = block;
+                output.extend_from_slice(&block);
+            }
+            stream.pending.drain(..length);
+        } else {
+            for i in 0..stream.pending.len() {
+                let byte = stream.pending[i] ^ self.stream_keystream_byte(stream);
+                output.push(byte);
+            }
+            stream.pending.clear();
         }
-        let mut len = output.len();
-        for i in 0..len - 1 {
-            if (output[i] == 0) && output[i + 1] == 0 {
-                len = i;
-                output.resize(len, 0);
-                break;
+
+        stream.mac.update(&output[start..]);
+    }
+
+    /*
+       Decrypts as much of the pending input as we can. The last 32 bytes could be the tag so they always
+       stay behind, and for CBC so does the last block since we can't strip its padding until we know it is last.
+    */
+    fn stream_decrypt_pending(&mut self, stream: &mut AesStream, output: &mut Vec<u8>) {
+        if !stream.has_iv {
+            if stream.pending.len() < AES_BLOCK_LENGTH_BYTES {
+                return;
             }
+            let mut initialization_vector = [0u8; AES_BLOCK_LENGTH_BYTES];
+            initialization_vector.copy_from_slice(&stream.pending[..AES_BLOCK_LENGTH_BYTES]);
+            stream.pending.drain(..AES_BLOCK_LENGTH_BYTES);
+            stream.start(initialization_vector);
         }
-        if let Some(&last_byte) = output.last() {
-            let pad_len = last_byte as usize;
-            if pad_len > 0 && pad_len <= AES_BLOCK_LENGTH_BYTES && output.len() >= pad_len {
-                let padding_valid = output[output.len() - pad_len..]
-                    .iter()
-                    .all(|&b| b == last_byte);
-                if padding_valid {
-                    output.resize(output.len() - pad_len, 0);
-                }
+
+        if self.mode == AesMode::CBC {
+            let available = stream
+                .pending
+                .len()
+                .saturating_sub(HMAC_SHA256_LENGTH_BYTES + AES_BLOCK_LENGTH_BYTES);
+            let length = available / AES_BLOCK_LENGTH_BYTES * AES_BLOCK_LENGTH_BYTES;
+            for i in (0..length).step_by(AES_BLOCK_LENGTH_BYTES) {
+                let mut current_slice = [0u8; AES_BLOCK_LENGTH_BYTES];
+                current_slice.copy_from_slice(&stream.pending[i..i + AES_BLOCK_LENGTH_BYTES]);
+                output.extend_from_slice(&self.stream_cbc_decrypt_block(stream, current_slice));
             }
+            stream.pending.drain(..length);
+        } else {
+            let length = stream
+                .pending
+                .len()
+                .saturating_sub(HMAC_SHA256_LENGTH_BYTES);
+            stream.mac.update(&stream.pending[..length]);
+            for i in 0..length {
+                let byte = stream.pending[i] ^ self.stream_keystream_byte(stream);
+                output.push(byte);
+            }
+            stream.pending.drain(..length);
         }
     }
 
-    fn set_key(&mut self, key: &[u8]) {
-        for (index, byte) in key.iter().enumerate() {
-            self.key[index] = *byte;
+    fn stream_cbc_decrypt_block(
+        &mut self,
+        stream: &mut AesStream,
+        ciphertext: [u8; AES_BLOCK_LENGTH_BYTES],
+    ) -> [u8; AES_BLOCK_LENGTH_BYTES] {
+        let mut block = [0u8; AES_BLOCK_LENGTH_BYTES];
+        stream.mac.update(&ciphertext);
+        self.inverted_cipher(&ciphertext, &mut block);
+        self.xor_with_initialization_vector(&mut block, Some(&stream.chaining));
+        stream.chaining = ciphertext;
+        block
+    }
+
+    /*
+       Same counter layout as ctr_encrypt, the first keystream block is the IV itself encrypted
+    */
+    fn stream_keystream_byte(&mut self, stream: &mut AesStream) -> u8 {
+        if stream.keystream_used == AES_BLOCK_LENGTH_BYTES {
+            self.cipher(&stream.chaining, &mut stream.keystream);
+            let counter = u128::from_be_bytes(stream.chaining).wrapping_add(1);
+            stream.chaining = counter.to_be_bytes();
+            stream.keystream_used = 0;
         }
-        self.key_expansion();
+        let byte = stream.keystream[stream.keystream_used];
+        stream.keystream_used += 1;
+        byte
     }
+}
 
-    fn get_key(&self) -> &[u8] {
-        &self.key
+/*
+   Streaming is there for CBC and CTR, GCM and ECB only do whole messages
+*/
+impl StreamingEncryption for AESContext {
+    fn stream_init(
+        &mut self,
+        direction: StreamDirection,
+        output: &mut Vec<u8>,
+    ) -> Result<(), CryptoError> {
+        if !self.uses_encrypt_then_mac() {
+            return Err(CryptoError::StreamingUnsupported);
+        }
+
+        let mut stream = AesStream {
+            direction,
+            mac: HmacSha256::new(&self.mac_key),
+            chaining: [0u8; AES_BLOCK_LENGTH_BYTES],
+            keystream: [0u8; AES_BLOCK_LENGTH_BYTES],
+            keystream_used: AES_BLOCK_LENGTH_BYTES,
+            pending: Vec::new(),
+            has_iv: false,
+            received: 0,
+        };
+        if direction == StreamDirection::Encrypt {
+            self.generate_initialization_vector();
+            stream.start(self.initialization_vector);
+            output.extend_from_slice(&self.initialization_vector);
+        }
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn stream_update(&mut self, chunk: &[u8], output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let mut stream = self.stream.take().ok_or(CryptoError::StreamNotStarted)?;
+        stream.received += chunk.len();
+        stream.pending.extend_from_slice(chunk);
+
+        match stream.direction {
+            StreamDirection::Encrypt => self.stream_encrypt_pending(&mut stream, output),
+            StreamDirection::Decrypt => self.stream_decrypt_pending(&mut stream, output),
+        }
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn stream_finalize(&mut self, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let mut stream = self.stream.take().ok_or(CryptoError::StreamNotStarted)?;
+
+        if stream.direction == StreamDirection::Encrypt {
+            if self.mode == AesMode::CBC {
+                pkcs7_pad(&mut stream.pending);
+            }
+            self.stream_encrypt_pending(&mut stream, output);
+            output.extend_from_slice(&stream.mac.finalize());
+            return Ok(());
+        }
+
+        /*
+           All that should be left is the tag, plus the final block for CBC
+        */
+        let final_block = if self.mode == AesMode::CBC {
+            AES_BLOCK_LENGTH_BYTES
+        } else {
+            0
+        };
+        let minimum = AES_BLOCK_LENGTH_BYTES + final_block + HMAC_SHA256_LENGTH_BYTES;
+        if !stream.has_iv || stream.received < minimum {
+            return Err(CryptoError::CiphertextTooShort {
+                minimum,
+                actual: stream.received,
+            });
+        }
+        if stream.pending.len() != final_block + HMAC_SHA256_LENGTH_BYTES {
+            return Err(CryptoError::MisalignedCiphertext {
+                block_size: AES_BLOCK_LENGTH_BYTES,
+                actual: stream.received - AES_BLOCK_LENGTH_BYTES - HMAC_SHA256_LENGTH_BYTES,
+            });
+        }
+
+        let mut plaintext = Vec::new();
+        if self.mode == AesMode::CBC {
+            let mut last_block = [0u8; AES_BLOCK_LENGTH_BYTES];
+            last_block.copy_from_slice(&stream.pending[..AES_BLOCK_LENGTH_BYTES]);
+            plaintext.extend_from_slice(&self.stream_cbc_decrypt_block(&mut stream, last_block));
+        }
+
+        let expected_tag = stream.mac.finalize();
+        if !constant_time_eq(&expected_tag, &stream.pending[final_block..]) {
+            return Err(CryptoError::AuthenticationFailed);
+        }
+
+        if self.mode == AesMode::CBC {
+            pkcs7_unpad(&mut plaintext)?;
+        }
+        output.extend_from_slice(&plaintext);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(string: &str) -> Vec<u8> {
+        let string: String = string.split_whitespace().collect();
+        (0..string.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&string[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn context(mode: AesMode, size: AesSize, key: &str) -> AESContext {
+        AESContext::new(mode, size, Some(&hex(key))).unwrap()
+    }
+
+    /*
+       FIPS-197 appendix C, one block through the raw cipher for each key size
+    */
+    const FIPS_197_PLAINTEXT: &str = "00112233445566778899aabbccddeeff";
+    const FIPS_197_EXAMPLES: [(AesSize, &str, &str); 3] = [
+        (
+            AesSize::S128,
+            "000102030405060708090a0b0c0d0e0f",
+            "69c4e0d86a7b0430d8cdb78070b4c55a",
+        ),
+        (
+            AesSize::S192,
+            "000102030405060708090a0b0c0d0e0f1011121314151617",
+            "dda97ca4864cdfe06eaf70a0ec0d7191",
+        ),
+        (
+            AesSize::S256,
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            "8ea2b7ca516745bfeafc49904b496089",
+        ),
+    ];
+
+    /*
+       SP 800-38A appendix F, the same four plaintext blocks are used for every mode and key size
+    */
+    const SP_800_38A_PLAINTEXT: &str = "6bc1bee22e409f96e93d7e117393172a ae2d8a571e03ac9c9eb76fac45af8e51 \
+                                        30c81c46a35ce411e5fbc1191a0a52ef f69f2445df4f9b17ad2b417be66c3710";
+    const SP_800_38A_KEY_128: &str = "2b7e151628aed2a6abf7158809cf4f3c";
+    const SP_800_38A_KEY_192: &str = "8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b";
+    const SP_800_38A_KEY_256: &str =
+        "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4";
+    const SP_800_38A_CBC_IV: &str = "000102030405060708090a0b0c0d0e0f";
+    const SP_800_38A_CTR_COUNTER: &str = "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff";
+
+    const SP_800_38A_ECB: [(AesSize, &str, &str); 3] = [
+        (
+            AesSize::S128,
+            SP_800_38A_KEY_128,
+            "3ad77bb40d7a3660a89ecaf32466ef97 f5d3d58503b9699de785895a96fdbaaf \
+             43b1cd7f598ece23881b00e3ed030688 7b0c785e27e8ad3f8223207104725dd4",
+        ),
+        (
+            AesSize::S192,
+            SP_800_38A_KEY_192,
+            "bd334f1d6e45f25ff712a214571fa5cc 974104846d0ad3ad7734ecb3ecee4eef \
+             ef7afd2270e2e60adce0ba2face6444e 9a4b41ba738d6c72fb16691603c18e0e",
+        ),
+        (
+            AesSize::S256,
+            SP_800_38A_KEY_256,
+            "f3eed1bdb5d2a03c064b5a7e3db181f8 591ccb10d410ed26dc5ba74a31362870 \
+             b6ed21b99ca6f4f9f153e7b1beafed1d 23304b7a39f9f3ff067d8d8f9e24ecc7",
+        ),
+    ];
+
+    const SP_800_38A_CBC: [(AesSize, &str, &str); 3] = [
+        (
+            AesSize::S128,
+            SP_800_38A_KEY_128,
+            "7649abac8119b246cee98e9b12e9197d 5086cb9b507219ee95db113a917678b2 \
+             73bed6b8e3c1743b7116e69e22229516 3ff1caa1681fac09120eca307586e1a7",
+        ),
+        (
+            AesSize::S192,
+            SP_800_38A_KEY_192,
+            "4f021db243bc633d7178183a9fa071e8 b4d9ada9ad7dedf4e5e738763f69145a \
+             571b242012fb7ae07fa9baac3df102e0 08b0e27988598881d920a9e64f5615cd",
+        ),
+        (
+            AesSize::S256,
+            SP_800_38A_KEY_256,
+            "f58c4c04d6e5f1ba779eabfb5f7bfbd6 9cfc4e967edb808d679f777bc6702c7d \
+             39f23369a9d9bacfa530e26304231461 b2eb05e2c39be9fcda6c19078c6a9d1b",
+        ),
+    ];
+
+    const SP_800_38A_CTR: [(AesSize, &str, &str); 3] = [
+        (
+            AesSize::S128,
+            SP_800_38A_KEY_128,
+            "874d6191b620e3261bef6864990db6ce 9806f66b7970fdff8617187bb9fffdff \
+             5ae4df3edbd5d35e5b4f09020db03eab 1e031dda2fbe03d1792170a0f3009cee",
+        ),
+        (
+            AesSize::S192,
+            SP_800_38A_KEY_192,
+            "1abc932417521ca24f2b0459fe7e6e0b 090339ec0aa6faefd5ccc2c6f4ce8e94 \
+             1e36b26bd1ebc670d1bd1d665620abf7 4f78a7f6d29809585a97daec58c6b050",
+        ),
+        (
+            AesSize::S256,
+            SP_800_38A_KEY_256,
+            "601ec313775789a5b7a7f504bbf3d228 f443e3ca4d62b59aca84e990cacaf5c5 \
+             2b0930daa23de94ce87017ba2d84988d dfc9c58db67aada613c2dd08457941a6",
+        ),
+    ];
+
+    #[test]
+    fn fips_197_cipher_and_inverse() {
+        for (size, key, ciphertext) in FIPS_197_EXAMPLES {
+            let mut aes = context(AesMode::ECB, size, key);
+            let mut output = [0u8; AES_BLOCK_LENGTH_BYTES];
+
+            aes.cipher(&hex(FIPS_197_PLAINTEXT), &mut output);
+            assert_eq!(output.to_vec(), hex(ciphertext));
+
+            aes.inverted_cipher(&hex(ciphertext), &mut output);
+            assert_eq!(output.to_vec(), hex(FIPS_197_PLAINTEXT));
+        }
+    }
+
+    /*
+       FIPS-197 appendix A, checked against the last round key since every word of it depends on the ones before
+    */
+    #[test]
+    fn fips_197_key_expansion() {
+        let expansions = [
+            (
+                AesSize::S128,
+                SP_800_38A_KEY_128,
+                10,
+                "d014f9a8c9ee2589e13f0cc8b6630ca6",
+            ),
+            (
+                AesSize::S192,
+                SP_800_38A_KEY_192,
+                12,
+                "e98ba06f448c773c8ecc720401002202",
+            ),
+            (
+                AesSize::S256,
+                SP_800_38A_KEY_256,
+                14,
+                "fe4890d1e6188d0b046df344706c631e",
+            ),
+        ];
+        for (size, key, rounds, last_round_key) in expansions {
+            let mut aes = context(AesMode::ECB, size, key);
+            aes.key_expansion();
+
+            let key = hex(key);
+            assert_eq!(&aes.round_keys[..key.len()], key.as_slice());
+            let start = rounds * AES_BLOCK_LENGTH_BYTES;
+            assert_eq!(
+                aes.round_keys[start..start + AES_BLOCK_LENGTH_BYTES].to_vec(),
+                hex(last_round_key)
+            );
+        }
+    }
+
+    #[test]
+    fn sp_800_38a_ecb() {
+        let plaintext = hex(SP_800_38A_PLAINTEXT);
+        for (size, key, ciphertext) in SP_800_38A_ECB {
+            let mut aes = context(AesMode::ECB, size, key);
+            let ciphertext = hex(ciphertext);
+
+            for (i, block) in plaintext.chunks(AES_BLOCK_LENGTH_BYTES).enumerate() {
+                let mut output = [0u8; AES_BLOCK_LENGTH_BYTES];
+                aes.ecb_encrypt(block, &mut output);
+                assert_eq!(
+                    output,
+                    ciphertext[i * AES_BLOCK_LENGTH_BYTES..][..AES_BLOCK_LENGTH_BYTES]
+                );
+
+                aes.ecb_decrypt(&output.clone(), &mut output);
+                assert_eq!(output, block);
+            }
+        }
+    }
+
+    #[test]
+    fn sp_800_38a_cbc() {
+        let plaintext = hex(SP_800_38A_PLAINTEXT);
+        let iv = hex(SP_800_38A_CBC_IV);
+        for (size, key, ciphertext) in SP_800_38A_CBC {
+            let mut aes = context(AesMode::CBC, size, key);
+            let expected = [iv.clone(), hex(ciphertext)].concat();
+
+            aes.set_initialization_vector(&iv);
+            let mut output = Vec::new();
+            aes.cbc_encrypt(&plaintext, &mut output);
+            assert_eq!(output, expected);
+
+            let mut decrypted = vec![0u8; plaintext.len()];
+            aes.cbc_decrypt(&expected, &mut decrypted);
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn sp_800_38a_ctr() {
+        let plaintext = hex(SP_800_38A_PLAINTEXT);
+        let counter = hex(SP_800_38A_CTR_COUNTER);
+        for (size, key, ciphertext) in SP_800_38A_CTR {
+            let mut aes = context(AesMode::CTR, size, key);
+            let expected = [counter.clone(), hex(ciphertext)].concat();
+
+            aes.set_initialization_vector(&counter);
+            let mut output = Vec::new();
+            aes.ctr_encrypt(&plaintext, &mut output);
+            assert_eq!(output, expected);
+
+            let mut decrypted = vec![0u8; plaintext.len()];
+            aes.ctr_decrypt(&expected, &mut decrypted);
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    /*
+       Test cases 2 and 14 from the original GCM spec (McGrew & Viega), one zero block under an all zero key and nonce
+    */
+    #[test]
+    fn gcm_known_answer() {
+        let vectors = [
+            (
+                AesSize::S128,
+                "00000000000000000000000000000000",
+                "0388dace60b6a392f328c2b971b2fe78",
+                "ab6e47d42cec13bdf53a67b21257bddf",
+            ),
+            (
+                AesSize::S256,
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "cea7403d4d606b6e074ec5d3baf39d18",
+                "d0d1c8a799996bf0265b98b5d48ab919",
+            ),
+        ];
+        for (size, key, ciphertext, tag) in vectors {
+            let mut aes = context(AesMode::GCM, size, key);
+            let message = [vec![0u8; GCM_NONCE_LENGTH_BYTES], hex(ciphertext), hex(tag)].concat();
+
+            let mut output = Vec::new();
+            aes.gcm_decrypt(&message, &mut output).unwrap();
+            assert_eq!(output, vec![0u8; AES_BLOCK_LENGTH_BYTES]);
+        }
+    }
+
+    /*
+       Test cases 4 and 16 from the same spec, four blocks (the last one partial) with 20 bytes of associated data
+    */
+    #[test]
+    fn gcm_known_answer_with_associated_data() {
+        let plaintext = hex(concat!(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72",
+            "1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39"
+        ));
+        let nonce = hex("cafebabefacedbaddecaf888");
+        let associated_data = hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+        let vectors = [
+            (
+                AesSize::S128,
+                "feffe9928665731c6d6a8f9467308308",
+                concat!(
+                    "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e",
+                    "21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091"
+                ),
+                "5bc94fbc3221a5db94fae95ae7121a47",
+            ),
+            (
+                AesSize::S256,
+                "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+                concat!(
+                    "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa",
+                    "8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662"
+                ),
+                "76fc6ece0f4e1768cddf8853bb2d551b",
+            ),
+        ];
+        for (size, key, ciphertext, tag) in vectors {
+            let mut aes = context(AesMode::GCM, size, key);
+            aes.set_associated_data(&associated_data);
+            let message = [nonce.clone(), hex(ciphertext), hex(tag)].concat();
+
+            let mut output = Vec::new();
+            aes.gcm_decrypt(&message, &mut output).unwrap();
+            assert_eq!(output, plaintext);
+
+            // Without the associated data, or with any of it changed, the tag no longer checks out
+            aes.set_associated_data(&[]);
+            assert_eq!(
+                aes.gcm_decrypt(&message, &mut output),
+                Err(CryptoError::AuthenticationFailed)
+            );
+            let mut tampered = associated_data.clone();
+            tampered[19] ^= 1;
+            aes.set_associated_data(&tampered);
+            assert_eq!(
+                aes.gcm_decrypt(&message, &mut output),
+                Err(CryptoError::AuthenticationFailed)
+            );
+        }
+    }
+
+    #[test]
+    fn gcm_round_trips_associated_data() {
+        let mut sender = AESContext::new(AesMode::GCM, AesSize::S256, None).unwrap();
+        let key = sender.get_key().to_vec();
+        let mut receiver = AESContext::new(AesMode::GCM, AesSize::S256, Some(&key)).unwrap();
+        sender.set_associated_data(b"room 7, epoch 3");
+
+        let mut ciphertext = Vec::new();
+        sender
+            .encrypt(&mut b"attack at dawn".to_vec(), &mut ciphertext)
+            .unwrap();
+        let mut decrypted = Vec::new();
+        receiver.set_associated_data(b"room 7, epoch 4");
+        assert_eq!(
+            receiver.decrypt(&mut ciphertext.clone(), &mut decrypted),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        receiver.set_associated_data(b"room 7, epoch 3");
+        receiver.decrypt(&mut ciphertext, &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"attack at dawn");
+    }
+
+    fn every_mode_and_size() -> Vec<(AesMode, AesSize)> {
+        let mut combinations = Vec::new();
+        for mode in [AesMode::ECB, AesMode::CBC, AesMode::CTR, AesMode::GCM] {
+            for size in [AesSize::S128, AesSize::S192, AesSize::S256] {
+                combinations.push((mode, size));
+            }
+        }
+        combinations
+    }
+
+    /*
+       Arbitrary binary data including runs of zero bytes and bytes that look like padding, every length
+       from empty up past a few blocks has to come back exactly
+    */
+    #[test]
+    fn round_trip_through_encryption_trait() {
+        for (mode, size) in every_mode_and_size() {
+            let mut aes = AESContext::new(mode, size, None).unwrap();
+            for length in 0..=100 {
+                let plaintext: Vec<u8> = (0..length).map(|i| (i * 37 % 5) as u8).collect();
+
+                let mut ciphertext = Vec::new();
+                aes.encrypt(&mut plaintext.clone(), &mut ciphertext)
+                    .unwrap();
+                let mut decrypted = Vec::new();
+                aes.decrypt(&mut ciphertext, &mut decrypted).unwrap();
+                assert_eq!(decrypted, plaintext, "{:?} length {}", mode, length);
+            }
+        }
+    }
+
+    #[test]
+    fn ciphertext_length_by_mode() {
+        for length in [0, 1, 15, 16, 17, 32] {
+            let padded = (length / AES_BLOCK_LENGTH_BYTES + 1) * AES_BLOCK_LENGTH_BYTES;
+            let expected = [
+                (AesMode::ECB, padded),
+                (
+                    AesMode::CBC,
+                    AES_BLOCK_LENGTH_BYTES + padded + HMAC_SHA256_LENGTH_BYTES,
+                ),
+                (
+                    AesMode::CTR,
+                    AES_BLOCK_LENGTH_BYTES + length + HMAC_SHA256_LENGTH_BYTES,
+                ),
+                (
+                    AesMode::GCM,
+                    GCM_NONCE_LENGTH_BYTES + length + GCM_TAG_LENGTH_BYTES,
+                ),
+            ];
+            for (mode, ciphertext_length) in expected {
+                let mut aes = AESContext::new(mode, AesSize::S128, None).unwrap();
+                let mut ciphertext = Vec::new();
+                aes.encrypt(&mut vec![0u8; length], &mut ciphertext)
+                    .unwrap();
+                assert_eq!(
+                    ciphertext.len(),
+                    ciphertext_length,
+                    "{:?} length {}",
+                    mode,
+                    length
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pkcs7_unpad_rejects_malformed_padding() {
+        let malformed: [&[u8]; 5] = [&[], &[1, 2, 3, 0], &[1, 2, 3, 17], &[1, 2, 3, 3], &[3, 3]];
+        for padded in malformed {
+            assert_eq!(
+                pkcs7_unpad(&mut padded.to_vec()),
+                Err(CryptoError::BadPadding)
+            );
+        }
+
+        let mut padded = vec![7, 3, 3, 3];
+        pkcs7_unpad(&mut padded).unwrap();
+        assert_eq!(padded, vec![7]);
+    }
+
+    #[test]
+    fn ecb_decrypt_rejects_bad_padding() {
+        let mut aes = AESContext::new(AesMode::ECB, AesSize::S256, None).unwrap();
+        let mut ciphertext = vec![0u8; AES_BLOCK_LENGTH_BYTES];
+        aes.ecb_encrypt(&[0x42; AES_BLOCK_LENGTH_BYTES], &mut ciphertext);
+
+        let mut decrypted = Vec::new();
+        assert_eq!(
+            aes.decrypt(&mut ciphertext, &mut decrypted),
+            Err(CryptoError::BadPadding)
+        );
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails_authentication() {
+        for (mode, size) in every_mode_and_size() {
+            if mode == AesMode::ECB {
+                continue;
+            }
+            let mut aes = AESContext::new(mode, size, None).unwrap();
+
+            let mut ciphertext = Vec::new();
+            aes.encrypt(&mut b"attack at dawn".to_vec(), &mut ciphertext)
+                .unwrap();
+            ciphertext[AES_BLOCK_LENGTH_BYTES] ^= 1;
+
+            let mut decrypted = Vec::new();
+            assert_eq!(
+                aes.decrypt(&mut ciphertext, &mut decrypted),
+                Err(CryptoError::AuthenticationFailed)
+            );
+        }
     }
 }
@@ -0,0 +1,425 @@
+use crate::cryptography::cryptography::{constant_time_eq, CryptoError, Encryption};
+use rand::RngCore;
+
+pub const CHACHA20_KEY_LENGTH_BYTES: usize = 32;
+const CHACHA20_NONCE_LENGTH_BYTES: usize = 12;
+const CHACHA20_BLOCK_LENGTH_BYTES: usize = 64;
+const POLY1305_TAG_LENGTH_BYTES: usize = 16;
+
+/*
+   "expand 32-byte k" as four little endian words, the first row of every ChaCha20 state
+*/
+const CHACHA20_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+/*
+   ChaCha20-Poly1305 AEAD as per RFC 8439. Unlike the table based AES everything in here is
+   add, rotate and xor on 32 bit words, so it is fast in software and runs in constant time.
+*/
+pub struct ChaCha20Poly1305 {
+    key: [u8; CHACHA20_KEY_LENGTH_BYTES],
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/*
+   Produces one 64 byte block of keystream. The state is laid out as
+   constants (4 words) || key (8 words) || block counter (1 word) || nonce (3 words)
+*/
+fn chacha20_block(
+    key: &[u8; CHACHA20_KEY_LENGTH_BYTES],
+    counter: u32,
+    nonce: &[u8],
+) -> [u8; CHACHA20_BLOCK_LENGTH_BYTES] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&CHACHA20_CONSTANTS);
+    for i in 0..8 {
+        state[4 + i] = read_u32_le(&key[i * 4..]);
+    }
+    state[12] = counter;
+    for i in 0..3 {
+        state[13 + i] = read_u32_le(&nonce[i * 4..]);
+    }
+
+    let mut working_state = state;
+    // 20 rounds, done as 10 iterations of a column round followed by a diagonal round
+    for _ in 0..10 {
+        quarter_round(&mut working_state, 0, 4, 8, 12);
+        quarter_round(&mut working_state, 1, 5, 9, 13);
+        quarter_round(&mut working_state, 2, 6, 10, 14);
+        quarter_round(&mut working_state, 3, 7, 11, 15);
+
+        quarter_round(&mut working_state, 0, 5, 10, 15);
+        quarter_round(&mut working_state, 1, 6, 11, 12);
+        quarter_round(&mut working_state, 2, 7, 8, 13);
+        quarter_round(&mut working_state, 3, 4, 9, 14);
+    }
+
+    let mut output = [0u8; CHACHA20_BLOCK_LENGTH_BYTES];
+    for i in 0..16 {
+        let word = working_state[i].wrapping_add(state[i]);
+        output[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    output
+}
+
+/*
+   Xor the buffer with the keystream starting at the given block counter, encryption and decryption are the same thing
+*/
+fn chacha20_xor(
+    key: &[u8; CHACHA20_KEY_LENGTH_BYTES],
+    initial_counter: u32,
+    nonce: &[u8],
+    buffer: &[u8],
+    output: &mut [u8],
+) {
+    for (i, chunk) in buffer.chunks(CHACHA20_BLOCK_LENGTH_BYTES).enumerate() {
+        let keystream = chacha20_block(key, initial_counter.wrapping_add(i as u32), nonce);
+        for (j, byte) in chunk.iter().enumerate() {
+            output[i * CHACHA20_BLOCK_LENGTH_BYTES + j] = byte ^ keystream[j];
+        }
+    }
+}
+
+/*
+   Poly1305 one time authenticator. Arithmetic mod 2^130 - 5 is done in five 26 bit limbs
+   so that every partial product fits comfortably in a u64 (this is the poly1305-donna approach).
+*/
+fn poly1305(one_time_key: &[u8], message: &[u8]) -> [u8; POLY1305_TAG_LENGTH_BYTES] {
+    // r is clamped as per the spec, s is added at the very end
+    let r0 = read_u32_le(&one_time_key[0..]) & 0x3ffffff;
+    let r1 = (read_u32_le(&one_time_key[3..]) >> 2) & 0x3ffff03;
+    let r2 = (read_u32_le(&one_time_key[6..]) >> 4) & 0x3ffc0ff;
+    let r3 = (read_u32_le(&one_time_key[9..]) >> 6) & 0x3f03fff;
+    let r4 = (read_u32_le(&one_time_key[12..]) >> 8) & 0x00fffff;
+
+    let s1 = r1 * 5;
+    let s2 = r2 * 5;
+    let s3 = r3 * 5;
+    let s4 = r4 * 5;
+
+    let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0u32, 0u32, 0u32, 0u32, 0u32);
+
+    for chunk in message.chunks(16) {
+        /*
+           Every block gets a 1 bit appended just past its last byte, for full blocks that is bit 128
+        */
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+        block[chunk.len()] = 1;
+
+        h0 += read_u32_le(&block[0..]) & 0x3ffffff;
+        h1 += (read_u32_le(&block[3..]) >> 2) & 0x3ffffff;
+        h2 += (read_u32_le(&block[6..]) >> 4) & 0x3ffffff;
+        h3 += (read_u32_le(&block[9..]) >> 6) & 0x3ffffff;
+        h4 += (read_u32_le(&block[12..]) >> 8) | ((block[16] as u32) << 24);
+
+        // h *= r, with the top limbs folded back in via the * 5 trick since 2^130 = 5 mod p
+        let d0 = h0 as u64 * r0 as u64
+            + h1 as u64 * s4 as u64
+            + h2 as u64 * s3 as u64
+            + h3 as u64 * s2 as u64
+            + h4 as u64 * s1 as u64;
+        let mut d1 = h0 as u64 * r1 as u64
+            + h1 as u64 * r0 as u64
+            + h2 as u64 * s4 as u64
+            + h3 as u64 * s3 as u64
+            + h4 as u64 * s2 as u64;
+        let mut d2 = h0 as u64 * r2 as u64
+            + h1 as u64 * r1 as u64
+            + h2 as u64 * r0 as u64
+            + h3 as u64 * s4 as u64
+            + h4 as u64 * s3 as u64;
+        let mut d3 = h0 as u64 * r3 as u64
+            + h1 as u64 * r2 as u64
+            + h2 as u64 * r1 as u64
+            + h3 as u64 * r0 as u64
+            + h4 as u64 * s4 as u64;
+        let mut d4 = h0 as u64 * r4 as u64
+            + h1 as u64 * r3 as u64
+            + h2 as u64 * r2 as u64
+            + h3 as u64 * r1 as u64
+            + h4 as u64 * r0 as u64;
+
+        // Partial carry propagation, enough to keep the limbs small for the next block
+        let mut carry = d0 >> 26;
+        h0 = (d0 as u32) & 0x3ffffff;
+        d1 += carry;
+        carry = d1 >> 26;
+        h1 = (d1 as u32) & 0x3ffffff;
+        d2 += carry;
+        carry = d2 >> 26;
+        h2 = (d2 as u32) & 0x3ffffff;
+        d3 += carry;
+        carry = d3 >> 26;
+        h3 = (d3 as u32) & 0x3ffffff;
+        d4 += carry;
+        carry = d4 >> 26;
+        h4 = (d4 as u32) & 0x3ffffff;
+        h0 += (carry as u32) * 5;
+        carry = (h0 >> 26) as u64;
+        h0 &= 0x3ffffff;
+        h1 += carry as u32;
+    }
+
+    // Full carry so every limb is back under 26 bits
+    let mut carry = h1 >> 26;
+    h1 &= 0x3ffffff;
+    h2 += carry;
+    carry = h2 >> 26;
+    h2 &= 0x3ffffff;
+    h3 += carry;
+    carry = h3 >> 26;
+    h3 &= 0x3ffffff;
+    h4 += carry;
+    carry = h4 >> 26;
+    h4 &= 0x3ffffff;
+    h0 += carry * 5;
+    carry = h0 >> 26;
+    h0 &= 0x3ffffff;
+    h1 += carry;
+
+    /*
+       Compute h - p and pick whichever of h or h - p is fully reduced, using a mask rather than a branch
+    */
+    let mut g0 = h0.wrapping_add(5);
+    carry = g0 >> 26;
+    g0 &= 0x3ffffff;
+    let mut g1 = h1.wrapping_add(carry);
+    carry = g1 >> 26;
+    g1 &= 0x3ffffff;
+    let mut g2 = h2.wrapping_add(carry);
+    carry = g2 >> 26;
+    g2 &= 0x3ffffff;
+    let mut g3 = h3.wrapping_add(carry);
+    carry = g3 >> 26;
+    g3 &= 0x3ffffff;
+    let g4 = h4.wrapping_add(carry).wrapping_sub(1 << 26);
+
+    let mask = (g4 >> 31).wrapping_sub(1);
+    h0 = (h0 & !mask) | (g0 & mask);
+    h1 = (h1 & !mask) | (g1 & mask);
+    h2 = (h2 & !mask) | (g2 & mask);
+    h3 = (h3 & !mask) | (g3 & mask);
+    h4 = (h4 & !mask) | (g4 & mask);
+
+    // Pack back down into 4 32 bit words and add s, anything past 128 bits is dropped
+    let words = [
+        h0 | (h1 << 26),
+        (h1 >> 6) | (h2 << 20),
+        (h2 >> 12) | (h3 << 14),
+        (h3 >> 18) | (h4 << 8),
+    ];
+
+    let mut tag = [0u8; POLY1305_TAG_LENGTH_BYTES];
+    let mut sum_carry = 0u64;
+    for i in 0..4 {
+        let sum = words[i] as u64 + read_u32_le(&one_time_key[16 + i * 4..]) as u64 + sum_carry;
+        tag[i * 4..i * 4 + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+        sum_carry = sum >> 32;
+    }
+    tag
+}
+
+impl ChaCha20Poly1305 {
+    /// Creates a new context with the given 256 bit key, or a random one if none is passed
+    pub fn new(key: Option<&[u8]>) -> Result<Self, CryptoError> {
+        let mut new = ChaCha20Poly1305 {
+            key: [0u8; CHACHA20_KEY_LENGTH_BYTES],
+        };
+
+        match key {
+            Some(key) => new.set_key(key)?,
+            None => rand::rng().fill_bytes(&mut new.key),
+        }
+        Ok(new)
+    }
+
+    /*
+       The Poly1305 key for a message is the first 32 bytes of keystream block 0, which is why
+       the message itself is encrypted starting from block 1
+    */
+    fn poly1305_key(&self, nonce: &[u8]) -> [u8; 32] {
+        let block = chacha20_block(&self.key, 0, nonce);
+        let mut one_time_key = [0u8; 32];
+        one_time_key.copy_from_slice(&block[..32]);
+        one_time_key
+    }
+
+    /*
+       The MAC input from RFC 8439 section 2.8, the associated data and the ciphertext each padded out to
+       16 bytes followed by both lengths as little endian u64s. Messages don't use associated data so
+       it is always empty outside the tests.
+    */
+    fn tag(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        ciphertext: &[u8],
+    ) -> [u8; POLY1305_TAG_LENGTH_BYTES] {
+        let mut mac_data = Vec::new();
+        for part in [associated_data, ciphertext] {
+            mac_data.extend_from_slice(part);
+            mac_data.resize(mac_data.len().next_multiple_of(16), 0);
+        }
+        mac_data.extend_from_slice(&(associated_data.len() as u64).to_le_bytes());
+        mac_data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+
+        poly1305(&self.poly1305_key(nonce), &mac_data)
+    }
+}
+
+impl Encryption for ChaCha20Poly1305 {
+    fn initialize_context(&mut self) {}
+
+    /*
+       Output layout is nonce (12 bytes) || ciphertext || tag (16 bytes), the nonce is a fresh random one
+       every message the same way CBC and CTR generate a new IV
+    */
+    fn encrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let mut nonce = [0u8; CHACHA20_NONCE_LENGTH_BYTES];
+        rand::fill(&mut nonce);
+
+        output.resize(
+            CHACHA20_NONCE_LENGTH_BYTES + input.len() + POLY1305_TAG_LENGTH_BYTES,
+            0,
+        );
+        output[..CHACHA20_NONCE_LENGTH_BYTES].copy_from_slice(&nonce);
+
+        let ciphertext_end = CHACHA20_NONCE_LENGTH_BYTES + input.len();
+        chacha20_xor(
+            &self.key,
+            1,
+            &nonce,
+            input,
+            &mut output[CHACHA20_NONCE_LENGTH_BYTES..ciphertext_end],
+        );
+
+        let tag = self.tag(
+            &nonce,
+            &[],
+            &output[CHACHA20_NONCE_LENGTH_BYTES..ciphertext_end],
+        );
+        output[ciphertext_end..].copy_from_slice(&tag);
+        Ok(())
+    }
+
+    /*
+       The tag is checked before anything is decrypted
+    */
+    fn decrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let minimum = CHACHA20_NONCE_LENGTH_BYTES + POLY1305_TAG_LENGTH_BYTES;
+        if input.len() < minimum {
+            return Err(CryptoError::CiphertextTooShort {
+                minimum,
+                actual: input.len(),
+            });
+        }
+
+        let nonce = &input[..CHACHA20_NONCE_LENGTH_BYTES];
+        let ciphertext =
+            &input[CHACHA20_NONCE_LENGTH_BYTES..input.len() - POLY1305_TAG_LENGTH_BYTES];
+        let received_tag = &input[input.len() - POLY1305_TAG_LENGTH_BYTES..];
+
+        if !constant_time_eq(&self.tag(nonce, &[], ciphertext), received_tag) {
+            return Err(CryptoError::AuthenticationFailed);
+        }
+
+        output.resize(ciphertext.len(), 0);
+        chacha20_xor(&self.key, 1, nonce, ciphertext, output);
+        Ok(())
+    }
+
+    fn set_key(&mut self, key: &[u8]) -> Result<(), CryptoError> {
+        if key.len() != CHACHA20_KEY_LENGTH_BYTES {
+            return Err(CryptoError::InvalidKeyLength(key.len()));
+        }
+        self.key.copy_from_slice(key);
+        Ok(())
+    }
+
+    fn get_key(&self) -> &[u8] {
+        &self.key
+    }
+
+    fn is_authenticated(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::encoding::hex_decode;
+
+    /// The AEAD test vector from RFC 8439 section 2.8.2
+    #[test]
+    fn matches_rfc_8439_aead_vector() {
+        let key: Vec<u8> = (0x80..0xa0).collect();
+        let context = ChaCha20Poly1305::new(Some(&key)).unwrap();
+        let nonce = hex_decode("070000004041424344454647").unwrap();
+        let associated_data = hex_decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+        let mut ciphertext = vec![0u8; plaintext.len()];
+        chacha20_xor(&context.key, 1, &nonce, plaintext, &mut ciphertext);
+        assert_eq!(
+            ciphertext,
+            hex_decode(concat!(
+                "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6",
+                "3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36",
+                "92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc",
+                "3ff4def08e4b7a9de576d26586cec64b6116"
+            ))
+            .unwrap()
+        );
+        assert_eq!(
+            context.tag(&nonce, &associated_data, &ciphertext).to_vec(),
+            hex_decode("1ae10b594f09e26a7e902ecbd0600691").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_a_tampered_message() {
+        let mut context = ChaCha20Poly1305::new(None).unwrap();
+        let mut encrypted = Vec::new();
+        context
+            .encrypt(&mut b"attack at dawn".to_vec(), &mut encrypted)
+            .unwrap();
+
+        let mut decrypted = Vec::new();
+        context
+            .decrypt(&mut encrypted.clone(), &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"attack at dawn");
+
+        // One bit flipped anywhere, nonce, ciphertext or tag, and the whole message is refused
+        for index in [0, CHACHA20_NONCE_LENGTH_BYTES + 3, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[index] ^= 1;
+            assert_eq!(
+                context.decrypt(&mut tampered, &mut decrypted),
+                Err(CryptoError::AuthenticationFailed)
+            );
+        }
+        assert!(matches!(
+            context.decrypt(&mut encrypted[..20].to_vec(), &mut decrypted),
+            Err(CryptoError::CiphertextTooShort { .. })
+        ));
+    }
+}
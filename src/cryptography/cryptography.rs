@@ -1,4 +1,107 @@
+use crate::arg_handling::arg_handling::arg_handling::{EncryptionInfo, KeySize};
+use crate::cryptography::aes::{AESContext, AesMode, AesSize};
+use crate::cryptography::chacha20poly1305::ChaCha20Poly1305;
+use crate::cryptography::ratchet::{next_key, KeyRatchet, RekeyPolicy, MAX_EPOCH_JUMP};
+use crate::cryptography::rc4::{Rc4State, MAX_DROP_BYTES};
 use std::fmt;
+use std::time::{Duration, Instant};
+
+/*
+   How long the previous epoch's key hangs around after a rekey. Anything the peer sent before it saw our
+   rekey should have turned up well within either limit, after that the old key is only a liability.
+*/
+const PREVIOUS_KEY_GRACE_PERIOD: Duration = Duration::from_secs(30);
+const PREVIOUS_KEY_MAX_MESSAGES: u64 = 256;
+
+/*
+   Everything that can go wrong inside an Encryption implementation. None of these are fatal to the session,
+   a message that fails to decrypt just gets dropped.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CryptoError {
+    /// The key passed in is not a length this cipher can use, holds the length we were given in bytes
+    InvalidKeyLength(usize),
+    /// Not even long enough to hold the IV/nonce/tag the message should be carrying
+    CiphertextTooShort {
+        minimum: usize,
+        actual: usize,
+    },
+    /// Block modes need the ciphertext to be a whole number of blocks
+    MisalignedCiphertext {
+        block_size: usize,
+        actual: usize,
+    },
+    BadPadding,
+    /// The tag or MAC did not verify, the message was tampered with or encrypted under a different key
+    AuthenticationFailed,
+    /// This cipher (or mode of it) has no streaming implementation
+    StreamingUnsupported,
+    /// stream_update or stream_finalize was called without a stream_init first
+    StreamNotStarted,
+    /// Rekeying needs a cipher that can tell which key a message was under, ECB and RC4 can't
+    RekeyUnsupported,
+    /// More RC4 keystream to throw away than MAX_DROP_BYTES, holds what was asked for
+    DropTooLarge(usize),
+    /// A rekey announcement more than MAX_EPOCH_JUMP epochs past the one we're on
+    EpochTooFarAhead {
+        epoch: u64,
+        current: u64,
+    },
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::InvalidKeyLength(length) => {
+                write!(f, "invalid key length of {} bits", length * 8)
+            }
+            CryptoError::CiphertextTooShort { minimum, actual } => write!(
+                f,
+                "ciphertext of {} bytes is shorter than the minimum of {} bytes",
+                actual, minimum
+            ),
+            CryptoError::MisalignedCiphertext { block_size, actual } => write!(
+                f,
+                "ciphertext of {} bytes is not a multiple of the {} byte block size",
+                actual, block_size
+            ),
+            CryptoError::BadPadding => write!(f, "invalid padding"),
+            CryptoError::AuthenticationFailed => write!(f, "message failed authentication"),
+            CryptoError::StreamingUnsupported => write!(f, "streaming is not supported by this cipher"),
+            CryptoError::StreamNotStarted => write!(f, "no stream has been started"),
+            CryptoError::RekeyUnsupported => {
+                write!(f, "rekeying needs an authenticated cipher")
+            }
+            CryptoError::DropTooLarge(drop) => write!(
+                f,
+                "RC4 drop of {} bytes is more than the maximum of {}",
+                drop, MAX_DROP_BYTES
+            ),
+            CryptoError::EpochTooFarAhead { epoch, current } => write!(
+                f,
+                "rekey to epoch {} is more than {} ahead of epoch {}",
+                epoch, MAX_EPOCH_JUMP, current
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/*
+   Compare tags/MACs without bailing out on the first mismatched byte so that the time taken doesn't tell
+   an attacker how much of their forged tag was right
+*/
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut difference = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        difference |= x ^ y;
+    }
+    difference == 0
+}
 
 pub trait Encryption {
     fn initialize_context(&mut self);
@@ -7,10 +110,12 @@ pub trait Encryption {
         Changing all references to mutable because in some cases you might need to resize the input buffer
         if it doesn't align with a certain block size alignment
      */
-    fn encrypt(&mut self, input:  &mut Vec<u8>, output : &mut Vec<u8>);
-    fn decrypt(&mut self, input:  &mut Vec<u8>, output: &mut Vec<u8>);
-    fn set_key(&mut self, key: &[u8]);
+    fn encrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<(), CryptoError>;
+    fn decrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<(), CryptoError>;
+    fn set_key(&mut self, key: &[u8]) -> Result<(), CryptoError>;
     fn get_key(&self) -> &[u8];
+    /// Whether decrypting under the wrong key fails with AuthenticationFailed instead of producing garbage
+    fn is_authenticated(&self) -> bool;
 }
 
 /*
@@ -24,6 +129,18 @@ pub struct EncryptionContext {
         to remember that
      */
     pub context: Box<dyn Encryption>,
+    pub ratchet: KeyRatchet,
+    /*
+       The key from the epoch before this one, anything the peer sent before it saw our rekey
+       message is still encrypted under it
+    */
+    previous_key: Option<PreviousKey>,
+}
+
+struct PreviousKey {
+    key: Vec<u8>,
+    retired_at: Instant,
+    decrypted: u64, // Messages that have come through since the rekey, under either key
 }
 /*
     This s required since the parent struct Telnet derives the debug trait
@@ -47,6 +164,228 @@ impl EncryptionContext {
     pub fn new<T: Encryption + 'static>(context: T) -> EncryptionContext {
         EncryptionContext {
             context: Box::new(context),
+            ratchet: KeyRatchet::new(RekeyPolicy::default()),
+            previous_key: None,
+        }
+    }
+
+    /*
+       The one place a cipher choice and key size turn into a working context. The key has to be exactly
+       key_size long, a short key is an error rather than something we quietly pad out.
+       rc4_drop is only looked at for RC4.
+    */
+    pub fn create(
+        enc_type: EncryptionInfo,
+        key_size: KeySize,
+        key: &[u8],
+        rc4_drop: usize,
+    ) -> Result<EncryptionContext, CryptoError> {
+        if key.len() != key_size.bytes() {
+            return Err(CryptoError::InvalidKeyLength(key.len()));
+        }
+
+        let aes_size = match key_size {
+            KeySize::Size128 => AesSize::S128,
+            KeySize::Size192 => AesSize::S192,
+            KeySize::Size256 => AesSize::S256,
+        };
+        let aes_mode = match enc_type {
+            EncryptionInfo::AesCbc => AesMode::CBC,
+            EncryptionInfo::AesCtr => AesMode::CTR,
+            EncryptionInfo::AesEcb => AesMode::ECB,
+            EncryptionInfo::AesGcm => AesMode::GCM,
+            EncryptionInfo::ChaCha20Poly1305 => {
+                return ChaCha20Poly1305::new(Some(key)).map(EncryptionContext::new)
+            }
+            EncryptionInfo::Rc4 => {
+                return Rc4State::new(Some(key), rc4_drop).map(EncryptionContext::new)
+            }
+        };
+        AESContext::new(aes_mode, aes_size, Some(key)).map(EncryptionContext::new)
+    }
+
+    /*
+       Step the key forward one epoch. Both sides do this in lock step (driven by the rekey control message)
+       so they always land on the same key without it ever going over the wire.
+    */
+    /*
+       Only the authenticated ciphers can tell us a message was under the wrong key, with ECB and RC4 a
+       message from the old epoch would just come out as garbage. So they never rekey.
+    */
+    pub fn can_rekey(&self) -> bool {
+        self.context.is_authenticated()
+    }
+
+    pub fn rekey(&mut self) -> Result<(), CryptoError> {
+        if !self.can_rekey() {
+            return Err(CryptoError::RekeyUnsupported);
+        }
+        let current = self.context.get_key().to_vec();
+        self.context.set_key(&next_key(&current))?;
+        self.previous_key = Some(PreviousKey {
+            key: current,
+            retired_at: Instant::now(),
+            decrypted: 0,
+        });
+        self.ratchet.advance();
+        Ok(())
+    }
+
+    /*
+       Rekeys until we're on the epoch a peer announced, nothing to do if we're already there (or past it).
+       A jump bigger than MAX_EPOCH_JUMP is refused without touching the key.
+    */
+    pub fn catch_up(&mut self, epoch: u64) -> Result<(), CryptoError> {
+        let current = self.ratchet.epoch();
+        if epoch.saturating_sub(current) > MAX_EPOCH_JUMP {
+            return Err(CryptoError::EpochTooFarAhead { epoch, current });
+        }
+        while self.ratchet.epoch() < epoch {
+            self.rekey()?;
+        }
+        Ok(())
+    }
+
+    /*
+       Decrypt under the current key, falling back to the previous epoch's key for messages that were
+       already in flight when we rekeyed, as long as it is still within its grace period
+    */
+    pub fn decrypt(
+        &mut self,
+        input: &mut Vec<u8>,
+        output: &mut Vec<u8>,
+    ) -> Result<(), CryptoError> {
+        let expired = self.previous_key.as_ref().is_some_and(|x| {
+            x.decrypted >= PREVIOUS_KEY_MAX_MESSAGES
+                || x.retired_at.elapsed() >= PREVIOUS_KEY_GRACE_PERIOD
+        });
+        if expired {
+            self.previous_key = None;
+        }
+
+        let mut retry_input = input.clone();
+        let result = self.context.decrypt(input, output);
+
+        let previous_key = match (&result, &mut self.previous_key) {
+            (Err(CryptoError::AuthenticationFailed), Some(x)) => {
+                x.decrypted += 1;
+                x.key.clone()
+            }
+            (_, Some(x)) => {
+                x.decrypted += 1;
+                return result;
+            }
+            _ => return result,
+        };
+
+        let current = self.context.get_key().to_vec();
+        self.context.set_key(&previous_key)?;
+        let retry = self.context.decrypt(&mut retry_input, output);
+        self.context.set_key(&current)?;
+        retry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(enc_type: EncryptionInfo) -> EncryptionContext {
+        EncryptionContext::create(enc_type, KeySize::Size256, &[7u8; 32], 0).unwrap()
+    }
+
+    fn encrypt(context: &mut EncryptionContext, message: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        context
+            .context
+            .encrypt(&mut message.to_vec(), &mut output)
+            .unwrap();
+        output
+    }
+
+    fn decrypt(context: &mut EncryptionContext, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut output = Vec::new();
+        context.decrypt(&mut ciphertext.to_vec(), &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn both_sides_land_on_the_same_key() {
+        let mut sender = context(EncryptionInfo::AesGcm);
+        let mut receiver = context(EncryptionInfo::AesGcm);
+        sender.rekey().unwrap();
+        assert_eq!(sender.ratchet.epoch(), 1);
+        assert_ne!(sender.context.get_key(), &[7u8; 32]);
+
+        let ciphertext = encrypt(&mut sender, b"epoch one");
+        assert_eq!(
+            decrypt(&mut receiver, &ciphertext),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        receiver.rekey().unwrap();
+        assert_eq!(receiver.ratchet.epoch(), 1);
+        assert_eq!(decrypt(&mut receiver, &ciphertext).unwrap(), b"epoch one");
+    }
+
+    #[test]
+    fn falls_back_to_the_previous_key_for_a_while() {
+        let mut sender = context(EncryptionInfo::ChaCha20Poly1305);
+        let mut receiver = context(EncryptionInfo::ChaCha20Poly1305);
+        let in_flight = encrypt(&mut sender, b"epoch zero");
+        receiver.rekey().unwrap();
+        assert_eq!(decrypt(&mut receiver, &in_flight).unwrap(), b"epoch zero");
+
+        // Once enough has come through under the new key the old one is gone
+        sender.rekey().unwrap();
+        let current = encrypt(&mut sender, b"epoch one");
+        for _ in 1..PREVIOUS_KEY_MAX_MESSAGES {
+            assert_eq!(decrypt(&mut receiver, &current).unwrap(), b"epoch one");
+        }
+        assert_eq!(
+            decrypt(&mut receiver, &in_flight),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        assert_eq!(decrypt(&mut receiver, &current).unwrap(), b"epoch one");
+    }
+
+    #[test]
+    fn catches_up_a_bounded_number_of_epochs() {
+        let mut sender = context(EncryptionInfo::AesGcm);
+        let mut receiver = context(EncryptionInfo::AesGcm);
+        for _ in 0..3 {
+            sender.rekey().unwrap();
+        }
+        receiver.catch_up(3).unwrap();
+        assert_eq!(receiver.ratchet.epoch(), 3);
+        assert_eq!(receiver.context.get_key(), sender.context.get_key());
+
+        // Behind us is nothing to do
+        receiver.catch_up(1).unwrap();
+        assert_eq!(receiver.ratchet.epoch(), 3);
+
+        assert_eq!(
+            receiver.catch_up(u64::MAX),
+            Err(CryptoError::EpochTooFarAhead {
+                epoch: u64::MAX,
+                current: 3
+            })
+        );
+        assert_eq!(receiver.ratchet.epoch(), 3);
+        receiver.catch_up(3 + MAX_EPOCH_JUMP).unwrap();
+        assert_eq!(receiver.ratchet.epoch(), 3 + MAX_EPOCH_JUMP);
+    }
+
+    #[test]
+    fn unauthenticated_ciphers_refuse_to_rekey() {
+        for enc_type in [EncryptionInfo::AesEcb, EncryptionInfo::Rc4] {
+            let mut state = context(enc_type);
+            assert!(!state.can_rekey());
+            assert_eq!(state.rekey(), Err(CryptoError::RekeyUnsupported));
+            assert_eq!(state.ratchet.epoch(), 0);
+            assert_eq!(state.context.get_key(), &[7u8; 32]);
+        }
+        for enc_type in [EncryptionInfo::AesCbc, EncryptionInfo::AesCtr] {
+            assert!(context(enc_type).can_rekey());
         }
     }
 }
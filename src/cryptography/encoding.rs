@@ -0,0 +1,94 @@
+/*
+   Just enough hex and base64 (RFC 4648, the standard alphabet with padding) to get binary keys in and out
+   of text. Decoding is strict, anything that isn't exactly a valid encoding is None rather than a best guess.
+*/
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Either case works, the length has to be even
+pub fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    let digits = text.as_bytes();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let high = (pair[0] as char).to_digit(16)?;
+            let low = (pair[1] as char).to_digit(16)?;
+            Some((high * 16 + low) as u8)
+        })
+        .collect()
+}
+
+/*
+   Every four characters are three bytes. The last group can end in one or two = to say it only holds two
+   or one, padding anywhere else or a missing one is an error.
+*/
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let characters = text.as_bytes();
+    if !characters.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(characters.len() / 4 * 3);
+    let groups = characters.len() / 4;
+    for (index, group) in characters.chunks(4).enumerate() {
+        let padding = group.iter().rev().take_while(|x| **x == b'=').count();
+        if padding > 2 || (padding > 0 && index + 1 != groups) {
+            return None;
+        }
+
+        let mut value = 0u32;
+        for character in &group[..4 - padding] {
+            let sextet = BASE64_ALPHABET.iter().position(|x| x == character)?;
+            value = value << 6 | sextet as u32;
+        }
+        value <<= 6 * padding;
+        bytes.extend_from_slice(&value.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = [0x00, 0x7f, 0x80, 0xff, 0x12];
+        assert_eq!(hex_encode(&bytes), "007f80ff12");
+        assert_eq!(hex_decode("007F80fF12").unwrap(), bytes);
+        assert_eq!(hex_decode("").unwrap(), []);
+        assert!(hex_decode("abc").is_none());
+        assert!(hex_decode("zz").is_none());
+        assert!(hex_decode("+1").is_none());
+    }
+
+    /// The test vectors from RFC 4648 section 10
+    #[test]
+    fn base64_decodes_rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("Zg==", "f"),
+            ("Zm8=", "fo"),
+            ("Zm9v", "foo"),
+            ("Zm9vYg==", "foob"),
+            ("Zm9vYmE=", "fooba"),
+            ("Zm9vYmFy", "foobar"),
+        ];
+        for (encoded, decoded) in vectors {
+            assert_eq!(base64_decode(encoded).unwrap(), decoded.as_bytes());
+        }
+        assert_eq!(base64_decode("+/8=").unwrap(), [0xfb, 0xff]);
+        assert!(base64_decode("Zm9").is_none());
+        assert!(base64_decode("Zg==Zm9v").is_none());
+        assert!(base64_decode("Z===").is_none());
+        assert!(base64_decode("Zm9-").is_none());
+    }
+}
@@ -0,0 +1,104 @@
+use crate::cryptography::sha256::{Sha256, SHA256_BLOCK_LENGTH_BYTES, SHA256_DIGEST_LENGTH_BYTES};
+
+pub const HMAC_SHA256_LENGTH_BYTES: usize = SHA256_DIGEST_LENGTH_BYTES;
+
+const INNER_PAD: u8 = 0x36;
+const OUTER_PAD: u8 = 0x5c;
+
+/*
+   HMAC-SHA256 as per RFC 2104, H((K ^ opad) || H((K ^ ipad) || message)).
+   The padded keys are absorbed up front, so cloning a keyed HmacSha256 skips both of those compressions,
+   which is what makes PBKDF2 bearable with a high iteration count.
+*/
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    /// Keys longer than a block are hashed down first, shorter ones are zero padded
+    pub fn new(key: &[u8]) -> Self {
+        let mut block_key = [0u8; SHA256_BLOCK_LENGTH_BYTES];
+        if key.len() > SHA256_BLOCK_LENGTH_BYTES {
+            let mut hasher = Sha256::new();
+            hasher.update(key);
+            block_key[..SHA256_DIGEST_LENGTH_BYTES].copy_from_slice(&hasher.finalize());
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner_key = [0u8; SHA256_BLOCK_LENGTH_BYTES];
+        let mut outer_key = [0u8; SHA256_BLOCK_LENGTH_BYTES];
+        for i in 0..SHA256_BLOCK_LENGTH_BYTES {
+            inner_key[i] = block_key[i] ^ INNER_PAD;
+            outer_key[i] = block_key[i] ^ OUTER_PAD;
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&inner_key);
+        let mut outer = Sha256::new();
+        outer.update(&outer_key);
+
+        HmacSha256 { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; HMAC_SHA256_LENGTH_BYTES] {
+        let inner_hash = self.inner.finalize();
+        let mut outer = self.outer;
+        outer.update(&inner_hash);
+        outer.finalize()
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; HMAC_SHA256_LENGTH_BYTES] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(message);
+    mac.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::encoding::hex_encode;
+
+    /// Test cases 1, 2, 3 and 6 from RFC 4231, the last one has a key longer than a block
+    #[test]
+    fn matches_rfc_4231_vectors() {
+        let cases: [(&[u8], &[u8], &str); 4] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ];
+        for (key, message, expected) in cases {
+            assert_eq!(hex_encode(&hmac_sha256(key, message)), expected);
+
+            let mut mac = HmacSha256::new(key);
+            for chunk in message.chunks(5) {
+                mac.update(chunk);
+            }
+            assert_eq!(hex_encode(&mac.finalize()), expected);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use crate::cryptography::hmac::{hmac_sha256, HmacSha256, HMAC_SHA256_LENGTH_BYTES};
+
+/*
+   OWASP's current recommendation for PBKDF2-HMAC-SHA256. It is deliberately slow, that is the whole point,
+   every guess an attacker makes at the passphrase costs them this many HMACs.
+*/
+pub const PBKDF2_DEFAULT_ITERATIONS: u32 = 600_000;
+
+/*
+   PBKDF2 from RFC 8018 with HMAC-SHA256 as the PRF. Fills the whole output buffer, so the length of
+   the buffer decides the length of the derived key.
+
+   Each 32 byte block i of output is U1 ^ U2 ^ ... ^ Uc where
+   U1 = HMAC(password, salt || i) and Un = HMAC(password, Un-1)
+*/
+pub fn pbkdf2_hmac_sha256(password: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) {
+    let keyed = HmacSha256::new(password);
+
+    for (block_index, chunk) in output.chunks_mut(HMAC_SHA256_LENGTH_BYTES).enumerate() {
+        let mut mac = keyed.clone();
+        mac.update(salt);
+        mac.update(&(block_index as u32 + 1).to_be_bytes());
+        let mut u = mac.finalize();
+        let mut block = u;
+
+        for _ in 1..iterations {
+            let mut mac = keyed.clone();
+            mac.update(&u);
+            u = mac.finalize();
+            for (byte, u_byte) in block.iter_mut().zip(u.iter()) {
+                *byte ^= u_byte;
+            }
+        }
+
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+}
+
+/*
+   HKDF from RFC 5869 with HMAC-SHA256. This is for turning something that is already high entropy
+   (like a Diffie-Hellman shared secret) into keys, not for passphrases, use PBKDF2 for those.
+*/
+pub fn hkdf_sha256(salt: &[u8], input_key_material: &[u8], info: &[u8], output: &mut [u8]) {
+    // Extract, concentrate the input down into a single pseudo random key
+    let pseudo_random_key = hmac_sha256(salt, input_key_material);
+
+    // Expand, T(i) = HMAC(PRK, T(i - 1) || info || i) until we have enough output
+    let mut previous: Vec<u8> = Vec::new();
+    for (i, chunk) in output.chunks_mut(HMAC_SHA256_LENGTH_BYTES).enumerate() {
+        let mut mac = HmacSha256::new(&pseudo_random_key);
+        mac.update(&previous);
+        mac.update(info);
+        mac.update(&[i as u8 + 1]);
+        let block = mac.finalize();
+
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        previous = block.to_vec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::encoding::hex_encode;
+
+    fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32, length: usize) -> String {
+        let mut output = vec![0u8; length];
+        pbkdf2_hmac_sha256(password, salt, iterations, &mut output);
+        hex_encode(&output)
+    }
+
+    /*
+       The PBKDF2-HMAC-SHA256 vectors from RFC 7914 section 11. Both ask for 64 bytes, two blocks, so they
+       cover the block counter as well as the iterations.
+    */
+    #[test]
+    fn matches_rfc_7914_vectors() {
+        assert_eq!(
+            pbkdf2(b"passwd", b"salt", 1, 64),
+            concat!(
+                "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+                "49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+            )
+        );
+        assert_eq!(
+            pbkdf2(b"Password", b"NaCl", 80000, 64),
+            concat!(
+                "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56",
+                "a1d425a1225833549adb841b51c9b3176a272bdebba1d078478f62b397f33c8d"
+            )
+        );
+    }
+
+    /// Test case 1 from RFC 5869
+    #[test]
+    fn matches_rfc_5869_hkdf_vector() {
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        let mut output = [0u8; 42];
+        hkdf_sha256(&salt, &[0x0b; 22], &info, &mut output);
+        assert_eq!(
+            hex_encode(&output),
+            concat!(
+                "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c",
+                "5db02d56ecc4c5bf34007208d5b887185865"
+            )
+        );
+    }
+
+    #[test]
+    fn shorter_output_is_a_prefix() {
+        let long = pbkdf2(b"passwd", b"salt", 1, 64);
+        assert_eq!(pbkdf2(b"passwd", b"salt", 1, 16), long[..32]);
+        assert_ne!(pbkdf2(b"passwd", b"salt", 2, 16), long[..32]);
+        assert_ne!(pbkdf2(b"passwd", b"pepper", 1, 16), long[..32]);
+    }
+}
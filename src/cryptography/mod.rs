@@ -1,5 +1,12 @@
 pub mod aes;
 
+pub mod chacha20poly1305;
 pub mod cryptography;
+pub mod encoding;
+pub mod hmac;
+pub mod kdf;
+pub mod ratchet;
 pub mod rc4;
-
+pub mod sha256;
+pub mod streaming;
+pub mod x25519;
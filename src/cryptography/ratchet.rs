@@ -0,0 +1,144 @@
+use crate::cryptography::kdf::hkdf_sha256;
+use std::time::{Duration, Instant};
+
+const RATCHET_LABEL: &[u8] = b"kryptos rekey";
+
+/*
+   How many epochs a rekey announcement can move us forward in one go. A peer only ever announces the epoch
+   after the one it's on, anything further ahead than a few missed rekeys is a bug or someone making us spin.
+*/
+pub const MAX_EPOCH_JUMP: u64 = 16;
+
+/*
+   When to move on to the next key. Any limit that is None is never hit, with all three None (the default) we never
+   rekey on our own. Only what we send counts towards these, the peer rekeys when we tell it to.
+   Off unless asked for since rekeying only works on the authenticated ciphers.
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RekeyPolicy {
+    pub max_messages: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+/*
+   Tracks how much has been sent under the current key and which key (epoch) we're on.
+   Epoch 0 is whatever key the session started with, every rekey moves us up by one.
+*/
+#[derive(Debug)]
+pub struct KeyRatchet {
+    policy: RekeyPolicy,
+    epoch: u64,
+    messages: u64,
+    bytes: u64,
+    last_rekey: Instant,
+}
+
+impl KeyRatchet {
+    pub fn new(policy: RekeyPolicy) -> Self {
+        KeyRatchet {
+            policy,
+            epoch: 0,
+            messages: 0,
+            bytes: 0,
+            last_rekey: Instant::now(),
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: RekeyPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Count a message we just sent against the current key
+    pub fn record(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+    }
+
+    pub fn rekey_due(&self) -> bool {
+        self.policy
+            .max_messages
+            .is_some_and(|max| self.messages >= max)
+            || self.policy.max_bytes.is_some_and(|max| self.bytes >= max)
+            || self
+                .policy
+                .max_age
+                .is_some_and(|max| self.last_rekey.elapsed() >= max)
+    }
+
+    /// Move to the next epoch and start counting from scratch
+    pub fn advance(&mut self) {
+        self.epoch += 1;
+        self.messages = 0;
+        self.bytes = 0;
+        self.last_rekey = Instant::now();
+    }
+}
+
+/*
+   One way step from the current key to the next one, same length as the current key.
+   Going forwards is easy, going backwards means inverting HMAC-SHA256, so a key stolen from
+   a later epoch can't be used to decrypt anything sent under an earlier one.
+*/
+pub fn next_key(current_key: &[u8]) -> Vec<u8> {
+    let mut next = vec![0u8; current_key.len()];
+    hkdf_sha256(&[], current_key, RATCHET_LABEL, &mut next);
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_rekeys_by_default() {
+        let mut ratchet = KeyRatchet::new(RekeyPolicy::default());
+        for _ in 0..10_000 {
+            ratchet.record(4096);
+        }
+        assert!(!ratchet.rekey_due());
+    }
+
+    #[test]
+    fn advancing_resets_the_counters() {
+        let mut ratchet = KeyRatchet::new(RekeyPolicy {
+            max_messages: Some(3),
+            max_bytes: Some(100),
+            max_age: None,
+        });
+        ratchet.record(10);
+        ratchet.record(10);
+        assert!(!ratchet.rekey_due());
+        ratchet.record(10);
+        assert!(ratchet.rekey_due());
+
+        ratchet.advance();
+        assert_eq!(ratchet.epoch(), 1);
+        assert!(!ratchet.rekey_due());
+        ratchet.record(100);
+        assert!(ratchet.rekey_due());
+        ratchet.advance();
+        assert_eq!(ratchet.epoch(), 2);
+        assert!(!ratchet.rekey_due());
+
+        ratchet.set_policy(RekeyPolicy {
+            max_age: Some(Duration::ZERO),
+            ..RekeyPolicy::default()
+        });
+        assert!(ratchet.rekey_due());
+    }
+
+    #[test]
+    fn next_key_is_deterministic_and_moves_on() {
+        let key = [1u8; 16];
+        let next = next_key(&key);
+        assert_eq!(next.len(), key.len());
+        assert_ne!(next, key);
+        assert_eq!(next, next_key(&key));
+        assert_ne!(next_key(&next), next);
+    }
+}
@@ -1,127 +1,275 @@
-use crate::cryptography::cryptography::Encryption;
+use crate::cryptography::cryptography::{CryptoError, Encryption};
+use crate::cryptography::streaming::{StreamDirection, StreamingEncryption};
 use rand::RngCore;
 
-pub const KEY_SIZE_BYTES: usize = 32;
+/*
+   RC4 takes anything from 1 to 256 bytes of key, we generate full 256 bit keys when asked for a random one
+*/
+pub const MIN_KEY_SIZE_BYTES: usize = 1;
+pub const MAX_KEY_SIZE_BYTES: usize = 256;
+pub const DEFAULT_KEY_SIZE_BYTES: usize = 32;
+const STATE_SIZE_BYTES: usize = 256;
 
-#[derive(Debug)]
-pub struct Rc4State {
-    s: [u8; KEY_SIZE_BYTES],
-    i: usize,
-    j: usize,
-    key: Rc4Key,
-}
+/*
+   The first bytes out of RC4 are badly biased towards the key, RC4-drop[n] throws the first n away.
+   768 and 3072 are the usual choices, but both ends have to agree so plain RC4 is still the default.
+*/
+pub const RECOMMENDED_DROP_BYTES: usize = 3072;
+/// Past this the drop is only costing time on every key setup, it doesn't make the keystream any better
+pub const MAX_DROP_BYTES: usize = 4096;
 
-#[derive(Debug)]
-pub struct Rc4Key {
-    key: [u8; KEY_SIZE_BYTES],
+/*
+   One running keystream. The S box and i, j carry over from message to message, so no two messages
+   ever get the same keystream under one key.
+*/
+#[derive(Debug, Clone)]
+struct Keystream {
+    s: [u8; STATE_SIZE_BYTES],
+    i: u8,
+    j: u8,
 }
 
-impl Rc4Key {
-    fn new(key: [u8; KEY_SIZE_BYTES]) -> Rc4Key {
-        Rc4Key { key }
+impl Keystream {
+    /// key_scheduling (KSA) sets up the S array from the key, then throws away the first drop bytes of keystream
+    fn key_scheduling(key: &[u8], drop: usize) -> Keystream {
+        let mut s = [0u8; STATE_SIZE_BYTES];
+        // Initialize the s array to the range [0..255]
+        for (i, entry) in s.iter_mut().enumerate() {
+            *entry = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..STATE_SIZE_BYTES {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+
+        let mut keystream = Keystream { s, i: 0, j: 0 };
+        for _ in 0..drop {
+            keystream.next_byte();
+        }
+        keystream
     }
+
+    /// prga (pseudo-random generator algorithm), one byte of keystream per call
+    fn next_byte(&mut self) -> u8 {
+        self.i = self.i.wrapping_add(1);
+        self.j = self.j.wrapping_add(self.s[self.i as usize]);
+        self.s.swap(self.i as usize, self.j as usize);
+        self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize]
+    }
+
+    fn apply(&mut self, input: &[u8], output: &mut [u8]) {
+        for (output_byte, &input_byte) in output.iter_mut().zip(input) {
+            *output_byte = input_byte ^ self.next_byte();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Rc4State {
+    key: Vec<u8>,
+    drop: usize,
+    /*
+       Sending and receiving each get their own copy of the keystream, both start from the same key.
+       Our encrypt stream lines up with the peer's decrypt stream and the other way around, which holds
+       as long as there is one peer (or an echo) on the other end and no message gets lost.
+    */
+    encrypt_stream: Keystream,
+    decrypt_stream: Keystream,
+    streaming: Option<StreamDirection>,
 }
 
 impl Rc4State {
-    /// Creates a new Rc4State object with a randomly generated key and default values for the s array, i, j
-    pub fn new(key: Option<&[u8]>) -> Self {
+    /// Creates a new Rc4State with the given key, or a randomly generated one if none is passed.
+    /// drop is how many keystream bytes to throw away after key scheduling, 0 is plain RC4
+    pub fn new(key: Option<&[u8]>, drop: usize) -> Result<Self, CryptoError> {
+        if drop > MAX_DROP_BYTES {
+            return Err(CryptoError::DropTooLarge(drop));
+        }
         let mut new = Self {
-            s: [0; KEY_SIZE_BYTES],
-            i: 0,
-            j: 0,
-            key: Rc4Key::new([0; KEY_SIZE_BYTES]), // Initialize with a default key
+            key: vec![0; DEFAULT_KEY_SIZE_BYTES],
+            drop,
+            encrypt_stream: Keystream::key_scheduling(&[0], 0),
+            decrypt_stream: Keystream::key_scheduling(&[0], 0),
+            streaming: None,
         };
 
-        if(key.is_some()) {
-            let key = key.unwrap();
-            new.set_key(key);
+        match key {
+            Some(key) => new.set_key(key)?,
+            None => new.generate_key(),
         }
-        new.initialize();
-        new
+        Ok(new)
     }
 
+    /// Restarts both keystreams from the beginning of the current key
     pub fn initialize(&mut self) {
-        self.generate_key();
+        let keystream = Keystream::key_scheduling(&self.key, self.drop);
+        self.encrypt_stream = keystream.clone();
+        self.decrypt_stream = keystream;
     }
 
-    /// Generates a key for your Rc4State object, this is called automatically on invocation of ::new however you can call it again if you wish to regenerate a new key
-    /// The key is of size 256 bytes (4096 bits)
+    /// Generates a random 256 bit key for your Rc4State object, this is called by ::new when no key is given
+    /// however you can call it again if you wish to regenerate a new key
     pub fn generate_key(&mut self) {
-        let mut key = [0u8; KEY_SIZE_BYTES];
-        rand::rng().fill_bytes(&mut key); // Fixed to use a random generator
-        self.key = Rc4Key::new(key);
+        let mut key = vec![0u8; DEFAULT_KEY_SIZE_BYTES];
+        rand::rng().fill_bytes(&mut key);
+        self.key = key;
+        self.initialize();
     }
+}
 
-    /// key_scheduling sets up the S array (initial key stream) with initial values getting ready to begin the encryption process.
-    fn key_scheduling(&mut self) {
-        let key = &self.key.key;
+impl Encryption for Rc4State {
+    fn initialize_context(&mut self) {
+        self.initialize();
+    }
 
-        // Initialize the s array to the range [0..255]
-        for i in 0..KEY_SIZE_BYTES {
-            self.s[i] = i as u8;
-        }
+    fn encrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        /*
+           Stream cipher so the output is always exactly the size of the input
+        */
+        output.resize(input.len(), 0);
+        self.encrypt_stream.apply(input, output);
+        Ok(())
+    }
 
-        let mut j = 0;
+    fn decrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        output.resize(input.len(), 0);
+        self.decrypt_stream.apply(input, output);
+        Ok(())
+    }
 
-        for i in 0..KEY_SIZE_BYTES {
-            j = (j + self.s[i] as usize + key[i] as usize) % KEY_SIZE_BYTES;
-            self.s.swap(i, j);
+    fn set_key(&mut self, key: &[u8]) -> Result<(), CryptoError> {
+        if !(MIN_KEY_SIZE_BYTES..=MAX_KEY_SIZE_BYTES).contains(&key.len()) {
+            return Err(CryptoError::InvalidKeyLength(key.len()));
         }
+        self.key = key.to_vec();
+        self.initialize();
+        Ok(())
+    }
 
-        self.i = 0;
-        self.j = 0;
+    fn get_key(&self) -> &[u8] {
+        &self.key
     }
 
-    /// prga (pseudo-random generator algorithm) sets up the final keystream buffer with pseudo random bytes derived from the initial keystream generated in key_scheduling
-    fn prga(&mut self, output_buffer: &mut [u8]) {
-        self.key_scheduling();
-        for byte in output_buffer {
-            self.i = (self.i + 1) % KEY_SIZE_BYTES;
-            self.j = (self.j + self.s[self.i] as usize) % KEY_SIZE_BYTES;
-            self.s.swap(self.i, self.j);
-            let k = self.s[(self.s[self.i] as usize + self.s[self.j] as usize) % KEY_SIZE_BYTES];
-            *byte = k;
-        }
+    fn is_authenticated(&self) -> bool {
+        false
     }
 }
 
-impl Encryption for Rc4State {
-    fn initialize_context(&mut self) {
-        self.initialize();
+/*
+   RC4 has no IV, padding or tag so a stream is just the same running keystream one chunk at a time.
+   Streaming carries on from wherever the keystream for that direction is, same as the next message would.
+*/
+impl StreamingEncryption for Rc4State {
+    fn stream_init(
+        &mut self,
+        direction: StreamDirection,
+        _output: &mut Vec<u8>,
+    ) -> Result<(), CryptoError> {
+        self.streaming = Some(direction);
+        Ok(())
     }
 
-    fn encrypt(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) {
-        let mut keystream = vec![0u8; input.len()];
+    fn stream_update(&mut self, chunk: &[u8], output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let keystream = match self.streaming {
+            Some(StreamDirection::Encrypt) => &mut self.encrypt_stream,
+            Some(StreamDirection::Decrypt) => &mut self.decrypt_stream,
+            None => return Err(CryptoError::StreamNotStarted),
+        };
+        let start = output.len();
+        output.resize(start + chunk.len(), 0);
+        keystream.apply(chunk, &mut output[start..]);
+        Ok(())
+    }
 
-        if output.len() < input.len() {
-            println!("RC4 encrypt: output buffer too short");
-            return;
+    fn stream_finalize(&mut self, _output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        match self.streaming.take() {
+            Some(_) => Ok(()),
+            None => Err(CryptoError::StreamNotStarted),
         }
+    }
+}
 
-        self.prga(&mut keystream);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::encoding::hex_decode;
 
-        for (i, &input_byte) in input.iter().enumerate() {
-            output[i] = keystream[i] ^ input_byte;
-        }
+    fn keystream(key: &[u8], drop: usize, length: usize) -> Vec<u8> {
+        let mut output = vec![0u8; length];
+        Keystream::key_scheduling(key, drop).apply(&vec![0u8; length], &mut output);
+        output
     }
 
-    fn decrypt(&mut self, input: &mut Vec<u8>, output:  &mut Vec<u8>) {
-        self.encrypt(input, output);
+    #[test]
+    fn matches_published_vectors() {
+        let vectors: [(&[u8], &[u8], &str); 3] = [
+            (b"Key", b"Plaintext", "bbf316e8d940af0ad3"),
+            (b"Wiki", b"pedia", "1021bf0420"),
+            (b"Secret", b"Attack at dawn", "45a01f645fc35b383552544b9bf5"),
+        ];
+        for (key, plaintext, ciphertext) in vectors {
+            let mut rc4 = Rc4State::new(Some(key), 0).unwrap();
+            let mut output = Vec::new();
+            rc4.encrypt(&mut plaintext.to_vec(), &mut output).unwrap();
+            assert_eq!(output, hex_decode(ciphertext).unwrap());
+        }
     }
 
-    fn set_key(&mut self, key: &[u8]) {
-        if key.len() != KEY_SIZE_BYTES {
-            println!("Key size is not correct! Expected {KEY_SIZE_BYTES}");
-            return;
+    /// RFC 6229 section 2, the 40 bit key at a few of its offsets, a drop of n is the keystream from offset n
+    #[test]
+    fn matches_rfc_6229_keystream() {
+        let key = [0x01, 0x02, 0x03, 0x04, 0x05];
+        let offsets = [
+            (0, "b2396305f03dc027ccc3524a0a1118a8"),
+            (16, "6982944f18fc82d589c403a47a0d0919"),
+            (1520, "3294f744d8f9790507e70f62e5bbceea"),
+            (4096, "ff25b58995996707e51fbdf08b34d875"),
+        ];
+        for (offset, expected) in offsets {
+            assert_eq!(keystream(&key, offset, 16), hex_decode(expected).unwrap());
         }
+    }
 
-        self.key.key = match key.try_into() {
-            Ok(x) => x,
-            Err(_) => self.key.key,
-        };
+    /*
+       The keystream carries on from one message to the next, so two messages come out exactly as one
+       message of both would, and the receiver has to see them in the same order
+    */
+    #[test]
+    fn keystream_carries_across_messages() {
+        let key = [0x01, 0x02, 0x03, 0x04, 0x05];
+        let mut sender = Rc4State::new(Some(&key), 16).unwrap();
+        let mut receiver = Rc4State::new(Some(&key), 16).unwrap();
+
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        sender.encrypt(&mut vec![0u8; 10], &mut first).unwrap();
+        sender.encrypt(&mut vec![0u8; 6], &mut second).unwrap();
+        assert_eq!(
+            [first.clone(), second.clone()].concat(),
+            hex_decode("6982944f18fc82d589c403a47a0d0919").unwrap()
+        );
+
+        let mut decrypted = Vec::new();
+        receiver.decrypt(&mut first, &mut decrypted).unwrap();
+        assert_eq!(decrypted, [0u8; 10]);
+        receiver.decrypt(&mut second, &mut decrypted).unwrap();
+        assert_eq!(decrypted, [0u8; 6]);
+
+        // Setting the key starts both directions over from the top
+        sender.set_key(&key).unwrap();
+        let mut restarted = Vec::new();
+        sender.encrypt(&mut vec![0u8; 10], &mut restarted).unwrap();
+        assert_eq!(restarted, first);
     }
 
-    fn get_key(&self) -> &[u8] {
-        &self.key.key
+    #[test]
+    fn rejects_an_oversized_drop() {
+        assert!(Rc4State::new(None, MAX_DROP_BYTES).is_ok());
+        assert_eq!(
+            Rc4State::new(None, MAX_DROP_BYTES + 1).unwrap_err(),
+            CryptoError::DropTooLarge(MAX_DROP_BYTES + 1)
+        );
     }
 }
@@ -0,0 +1,199 @@
+pub const SHA256_DIGEST_LENGTH_BYTES: usize = 32;
+pub const SHA256_BLOCK_LENGTH_BYTES: usize = 64;
+
+/*
+   First 32 bits of the fractional parts of the square roots of the first 8 primes, as per FIPS 180-4
+*/
+const INITIAL_HASH: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/*
+   First 32 bits of the fractional parts of the cube roots of the first 64 primes
+*/
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/*
+   Incremental SHA-256, feed it as many update calls as you like and then finalize once
+*/
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    buffer: [u8; SHA256_BLOCK_LENGTH_BYTES],
+    buffer_length: usize,
+    total_length: u64, // In bytes, converted to bits for the final length block
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Sha256 {
+            state: INITIAL_HASH,
+            buffer: [0u8; SHA256_BLOCK_LENGTH_BYTES],
+            buffer_length: 0,
+            total_length: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.total_length += data.len() as u64;
+        let mut data = data;
+
+        /*
+           Top up whatever partial block is left over from the last update first
+        */
+        if self.buffer_length > 0 {
+            let needed = SHA256_BLOCK_LENGTH_BYTES - self.buffer_length;
+            let taken = needed.min(data.len());
+            self.buffer[self.buffer_length..self.buffer_length + taken]
+                .copy_from_slice(&data[..taken]);
+            self.buffer_length += taken;
+            data = &data[taken..];
+
+            if self.buffer_length < SHA256_BLOCK_LENGTH_BYTES {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffer_length = 0;
+        }
+
+        let mut blocks = data.chunks_exact(SHA256_BLOCK_LENGTH_BYTES);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+
+        let remainder = blocks.remainder();
+        self.buffer[..remainder.len()].copy_from_slice(remainder);
+        self.buffer_length = remainder.len();
+    }
+
+    /*
+       Pad with a single 1 bit, zeroes up to 56 bytes into the block, then the message length in bits as a big endian u64
+    */
+    pub fn finalize(mut self) -> [u8; SHA256_DIGEST_LENGTH_BYTES] {
+        let bit_length = self.total_length.wrapping_mul(8);
+
+        let mut padding = vec![0x80u8];
+        let padded_length = (self.buffer_length + 1) % SHA256_BLOCK_LENGTH_BYTES;
+        let zeroes = (SHA256_BLOCK_LENGTH_BYTES + 56 - padded_length) % SHA256_BLOCK_LENGTH_BYTES;
+        padding.resize(1 + zeroes, 0);
+        padding.extend_from_slice(&bit_length.to_be_bytes());
+        self.update(&padding);
+
+        let mut digest = [0u8; SHA256_DIGEST_LENGTH_BYTES];
+        for (i, word) in self.state.iter().enumerate() {
+            digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut schedule = [0u32; 64];
+        for i in 0..16 {
+            schedule[i] = u32::from_be_bytes([
+                block[i * 4],
+                block[i * 4 + 1],
+                block[i * 4 + 2],
+                block[i * 4 + 3],
+            ]);
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7)
+                ^ schedule[i - 15].rotate_right(18)
+                ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17)
+                ^ schedule[i - 2].rotate_right(19)
+                ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(ROUND_CONSTANTS[i])
+                .wrapping_add(schedule[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::encoding::hex_encode;
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hex_encode(&hasher.finalize())
+    }
+
+    /// The one and two block examples from FIPS 180-4 plus the empty message
+    #[test]
+    fn matches_fips_180_4_vectors() {
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn split_updates_match_one_update() {
+        let data = vec![b'a'; 1_000_000];
+        let mut hasher = Sha256::new();
+        // Odd sizes so the chunks never line up with the block boundaries
+        for chunk in data.chunks(77) {
+            hasher.update(chunk);
+        }
+        assert_eq!(
+            hex_encode(&hasher.finalize()),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use crate::arg_handling::arg_handling::arg_handling::{EncryptionInfo, KeySize};
+use crate::cryptography::aes::{AESContext, AesMode, AesSize};
+use crate::cryptography::cryptography::CryptoError;
+use crate::cryptography::rc4::Rc4State;
+use std::io;
+use std::io::{Read, Write};
+
+/*
+   How much we pull from the underlying reader at a time, the adapters never hold much more than this
+*/
+const STREAM_CHUNK_SIZE_BYTES: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamDirection {
+    Encrypt,
+    Decrypt,
+}
+
+/*
+   Encryption a chunk at a time for payloads too big to hand to Encryption::encrypt in one go.
+   A stream is init, any number of updates, then finalize. The bytes that come out are exactly what the
+   one shot Encryption::encrypt would have produced for the whole payload, so either side can use either API.
+
+   On decrypt, plaintext comes out of update before the tag at the very end has been checked. Nothing from
+   a stream should be trusted until finalize has returned Ok.
+*/
+pub trait StreamingEncryption {
+    /// Starts a new stream in the given direction, anything that has to come first (the IV) goes into output
+    fn stream_init(
+        &mut self,
+        direction: StreamDirection,
+        output: &mut Vec<u8>,
+    ) -> Result<(), CryptoError>;
+    /// Feeds the next chunk through, whatever output is ready gets appended to output
+    fn stream_update(&mut self, chunk: &[u8], output: &mut Vec<u8>) -> Result<(), CryptoError>;
+    /// Flushes out whatever is left (padding, the MAC tag) and ends the stream
+    fn stream_finalize(&mut self, output: &mut Vec<u8>) -> Result<(), CryptoError>;
+}
+
+impl<T: StreamingEncryption + ?Sized> StreamingEncryption for &mut T {
+    fn stream_init(
+        &mut self,
+        direction: StreamDirection,
+        output: &mut Vec<u8>,
+    ) -> Result<(), CryptoError> {
+        (**self).stream_init(direction, output)
+    }
+
+    fn stream_update(&mut self, chunk: &[u8], output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        (**self).stream_update(chunk, output)
+    }
+
+    fn stream_finalize(&mut self, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        (**self).stream_finalize(output)
+    }
+}
+
+/*
+   EncryptionContext::create for the ciphers that can stream, the rest come back as StreamingUnsupported.
+   Same rules on the key, it has to be exactly key_size long.
+*/
+pub fn create_stream(
+    enc_type: EncryptionInfo,
+    key_size: KeySize,
+    key: &[u8],
+    rc4_drop: usize,
+) -> Result<Box<dyn StreamingEncryption>, CryptoError> {
+    if key.len() != key_size.bytes() {
+        return Err(CryptoError::InvalidKeyLength(key.len()));
+    }
+
+    let aes_size = match key_size {
+        KeySize::Size128 => AesSize::S128,
+        KeySize::Size192 => AesSize::S192,
+        KeySize::Size256 => AesSize::S256,
+    };
+    let aes_mode = match enc_type {
+        EncryptionInfo::AesCbc => AesMode::CBC,
+        EncryptionInfo::AesCtr => AesMode::CTR,
+        EncryptionInfo::Rc4 => return Ok(Box::new(Rc4State::new(Some(key), rc4_drop)?)),
+        _ => return Err(CryptoError::StreamingUnsupported),
+    };
+    Ok(Box::new(AESContext::new(aes_mode, aes_size, Some(key))?))
+}
+
+fn to_io_error(error: CryptoError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/*
+   Encrypts everything written to it on the way through to the inner writer. finish has to be called at the
+   end to write out the padding and tag, dropping the writer without it leaves a truncated stream behind.
+*/
+pub struct EncryptingWriter<W: Write, C: StreamingEncryption> {
+    inner: W,
+    cipher: C,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write, C: StreamingEncryption> EncryptingWriter<W, C> {
+    pub fn new(mut inner: W, mut cipher: C) -> io::Result<Self> {
+        let mut buffer = Vec::new();
+        cipher
+            .stream_init(StreamDirection::Encrypt, &mut buffer)
+            .map_err(to_io_error)?;
+        inner.write_all(&buffer)?;
+        buffer.clear();
+
+        Ok(EncryptingWriter {
+            inner,
+            cipher,
+            buffer,
+        })
+    }
+
+    /// Ends the stream and hands back the inner writer and cipher
+    pub fn finish(mut self) -> io::Result<(W, C)> {
+        self.cipher
+            .stream_finalize(&mut self.buffer)
+            .map_err(to_io_error)?;
+        self.inner.write_all(&self.buffer)?;
+        self.inner.flush()?;
+        Ok((self.inner, self.cipher))
+    }
+}
+
+impl<W: Write, C: StreamingEncryption> Write for EncryptingWriter<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.cipher
+            .stream_update(buf, &mut self.buffer)
+            .map_err(to_io_error)?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/*
+   Decrypts everything read from the inner reader. The tag gets checked when the inner reader runs dry,
+   if it fails that last read returns an InvalidData error, so read everything through to the end before
+   acting on any of it.
+*/
+pub struct DecryptingReader<R: Read, C: StreamingEncryption> {
+    inner: R,
+    cipher: C,
+    plaintext: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read, C: StreamingEncryption> DecryptingReader<R, C> {
+    pub fn new(inner: R, mut cipher: C) -> io::Result<Self> {
+        /*
+           Decrypting has nothing to output up front, the IV comes in with the first chunk
+        */
+        let mut plaintext = Vec::new();
+        cipher
+            .stream_init(StreamDirection::Decrypt, &mut plaintext)
+            .map_err(to_io_error)?;
+
+        Ok(DecryptingReader {
+            inner,
+            cipher,
+            plaintext,
+            position: 0,
+            finished: false,
+        })
+    }
+}
+
+impl<R: Read, C: StreamingEncryption> Read for DecryptingReader<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0u8; STREAM_CHUNK_SIZE_BYTES];
+
+        /*
+           A chunk doesn't always give us plaintext straight away (the tag and last block get held back)
+           so keep reading until we have some or the stream ends
+        */
+        while self.position == self.plaintext.len() && !self.finished {
+            self.plaintext.clear();
+            self.position = 0;
+
+            let read = self.inner.read(&mut chunk)?;
+            if read == 0 {
+                self.finished = true;
+                self.cipher
+                    .stream_finalize(&mut self.plaintext)
+                    .map_err(to_io_error)?;
+            } else {
+                self.cipher
+                    .stream_update(&chunk[..read], &mut self.plaintext)
+                    .map_err(to_io_error)?;
+            }
+        }
+
+        let available = &self.plaintext[self.position..];
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::aes::{AESContext, AesMode, AesSize};
+    use crate::cryptography::cryptography::Encryption;
+    use crate::cryptography::rc4::Rc4State;
+
+    fn payload(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn stream_encrypt<C: StreamingEncryption>(
+        cipher: C,
+        plaintext: &[u8],
+        chunk: usize,
+    ) -> Vec<u8> {
+        let mut writer = EncryptingWriter::new(Vec::new(), cipher).unwrap();
+        for piece in plaintext.chunks(chunk) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap().0
+    }
+
+    fn stream_decrypt<C: StreamingEncryption>(cipher: C, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = DecryptingReader::new(ciphertext, cipher)?;
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    /*
+       Whatever comes out of a stream has to be exactly what the one shot API produces and accepts
+    */
+    #[test]
+    fn aes_streams_match_one_shot() {
+        for mode in [AesMode::CBC, AesMode::CTR] {
+            let key = [7u8; 16];
+            let mut aes = AESContext::new(mode, AesSize::S128, Some(&key)).unwrap();
+            for length in [0, 1, 15, 16, 17, 48, 1000, 20_000] {
+                let plaintext = payload(length);
+                for chunk in [1, 7, 16, 4096] {
+                    let ciphertext = stream_encrypt(&mut aes, &plaintext, chunk);
+                    let mut decrypted = Vec::new();
+                    aes.decrypt(&mut ciphertext.clone(), &mut decrypted)
+                        .unwrap();
+                    assert_eq!(
+                        decrypted, plaintext,
+                        "{:?} length {} chunk {}",
+                        mode, length, chunk
+                    );
+                }
+
+                let mut ciphertext = Vec::new();
+                aes.encrypt(&mut plaintext.clone(), &mut ciphertext)
+                    .unwrap();
+                assert_eq!(stream_decrypt(&mut aes, &ciphertext).unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn aes_stream_rejects_tampering_and_truncation() {
+        for mode in [AesMode::CBC, AesMode::CTR] {
+            let mut aes = AESContext::new(mode, AesSize::S256, None).unwrap();
+            let mut ciphertext = stream_encrypt(&mut aes, &payload(100), 10);
+
+            let truncated = &ciphertext[..ciphertext.len() - 1];
+            assert!(stream_decrypt(&mut aes, truncated).is_err());
+
+            ciphertext[40] ^= 1;
+            let error = stream_decrypt(&mut aes, &ciphertext).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn aes_stream_unsupported_modes() {
+        for mode in [AesMode::ECB, AesMode::GCM] {
+            let mut aes = AESContext::new(mode, AesSize::S128, None).unwrap();
+            assert_eq!(
+                aes.stream_init(StreamDirection::Encrypt, &mut Vec::new()),
+                Err(CryptoError::StreamingUnsupported)
+            );
+        }
+    }
+
+    #[test]
+    fn rc4_stream_continues_the_keystream() {
+        let key = b"Key";
+        let plaintext = payload(10_000);
+
+        let mut sender = Rc4State::new(Some(key), 0).unwrap();
+        let mut receiver = Rc4State::new(Some(key), 0).unwrap();
+
+        let ciphertext = stream_encrypt(&mut sender, &plaintext, 333);
+        assert_eq!(ciphertext.len(), plaintext.len());
+        assert_eq!(
+            stream_decrypt(&mut receiver, &ciphertext).unwrap(),
+            plaintext
+        );
+
+        // A message after the stream picks up the keystream where the stream left off
+        let mut message = Vec::new();
+        sender
+            .encrypt(&mut b"after".to_vec(), &mut message)
+            .unwrap();
+        let mut decrypted = Vec::new();
+        receiver.decrypt(&mut message, &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"after");
+    }
+}
@@ -0,0 +1,294 @@
+use rand::RngCore;
+
+pub const X25519_KEY_LENGTH_BYTES: usize = 32;
+
+/*
+   Elements of GF(2^255 - 19) held as 16 limbs of 16 bits each, signed and in i64 so that
+   products and sums have plenty of headroom before we carry. Same layout as TweetNaCl.
+*/
+type FieldElement = [i64; 16];
+
+/*
+   (486662 - 2) / 4, the a24 constant from the Montgomery ladder in RFC 7748
+*/
+const A24: FieldElement = [0xdb41, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/*
+   u = 9, the base point of Curve25519
+*/
+const BASE_POINT: [u8; X25519_KEY_LENGTH_BYTES] = [
+    9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/*
+   Push everything above 16 bits in each limb up into the next one, the top limb wraps around
+   to the bottom multiplied by 38 since 2^256 = 38 mod p
+*/
+fn carry(element: &mut FieldElement) {
+    for i in 0..16 {
+        element[i] += 1 << 16;
+        let c = element[i] >> 16;
+        if i < 15 {
+            element[i + 1] += c - 1;
+        } else {
+            element[0] += 38 * (c - 1);
+        }
+        element[i] -= c << 16;
+    }
+}
+
+/*
+   Swap p and q if swap is 1, leave them alone if it is 0, without branching on it
+*/
+fn conditional_swap(p: &mut FieldElement, q: &mut FieldElement, swap: i64) {
+    let mask = !(swap - 1);
+    for i in 0..16 {
+        let t = mask & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+/*
+   Fully reduce mod p and serialize as 32 little endian bytes
+*/
+fn pack(element: &FieldElement) -> [u8; X25519_KEY_LENGTH_BYTES] {
+    let mut t = *element;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+
+    // Subtracting p at most twice is enough to land in [0, p)
+    let mut m: FieldElement = [0; 16];
+    for _ in 0..2 {
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let borrow = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        conditional_swap(&mut t, &mut m, 1 - borrow);
+    }
+
+    let mut output = [0u8; X25519_KEY_LENGTH_BYTES];
+    for i in 0..16 {
+        output[2 * i] = (t[i] & 0xff) as u8;
+        output[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    output
+}
+
+/*
+   The top bit of the u coordinate is ignored as per RFC 7748
+*/
+fn unpack(bytes: &[u8; X25519_KEY_LENGTH_BYTES]) -> FieldElement {
+    let mut element: FieldElement = [0; 16];
+    for i in 0..16 {
+        element[i] = bytes[2 * i] as i64 + ((bytes[2 * i + 1] as i64) << 8);
+    }
+    element[15] &= 0x7fff;
+    element
+}
+
+fn add(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut output: FieldElement = [0; 16];
+    for i in 0..16 {
+        output[i] = a[i] + b[i];
+    }
+    output
+}
+
+fn subtract(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut output: FieldElement = [0; 16];
+    for i in 0..16 {
+        output[i] = a[i] - b[i];
+    }
+    output
+}
+
+fn multiply(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut product = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            product[i + j] += a[i] * b[j];
+        }
+    }
+    // Fold the top half back down, 2^256 = 38 mod p
+    for i in 0..15 {
+        product[i] += 38 * product[i + 16];
+    }
+
+    let mut output: FieldElement = [0; 16];
+    output.copy_from_slice(&product[..16]);
+    carry(&mut output);
+    carry(&mut output);
+    output
+}
+
+fn square(a: &FieldElement) -> FieldElement {
+    multiply(a, a)
+}
+
+/*
+   Inversion by raising to p - 2 (Fermat), the exponent is 2^255 - 21 so every bit is set except bits 2 and 4
+*/
+fn invert(element: &FieldElement) -> FieldElement {
+    let mut c = *element;
+    for bit in (0..=253).rev() {
+        c = square(&c);
+        if bit != 2 && bit != 4 {
+            c = multiply(&c, element);
+        }
+    }
+    c
+}
+
+/*
+   Scalar multiplication on the u coordinate using the Montgomery ladder from RFC 7748 section 5.
+   The ladder does the same work for every bit of the scalar, with the swaps done by masking,
+   so the time it takes doesn't depend on the secret.
+*/
+pub fn x25519(
+    scalar: &[u8; X25519_KEY_LENGTH_BYTES],
+    u_coordinate: &[u8; X25519_KEY_LENGTH_BYTES],
+) -> [u8; X25519_KEY_LENGTH_BYTES] {
+    // Clamp the scalar, clear the low 3 bits and the top bit, set bit 254
+    let mut clamped = *scalar;
+    clamped[0] &= 248;
+    clamped[31] = (clamped[31] & 127) | 64;
+
+    let x1 = unpack(u_coordinate);
+    let mut x2: FieldElement = [0; 16];
+    let mut z2: FieldElement = [0; 16];
+    let mut x3 = x1;
+    let mut z3: FieldElement = [0; 16];
+    x2[0] = 1;
+    z3[0] = 1;
+
+    for i in (0..=254).rev() {
+        let bit = ((clamped[i >> 3] >> (i & 7)) & 1) as i64;
+        conditional_swap(&mut x2, &mut x3, bit);
+        conditional_swap(&mut z2, &mut z3, bit);
+
+        let a = add(&x2, &z2);
+        let aa = square(&a);
+        let b = subtract(&x2, &z2);
+        let bb = square(&b);
+        let e = subtract(&aa, &bb);
+        let c = add(&x3, &z3);
+        let d = subtract(&x3, &z3);
+        let da = multiply(&d, &a);
+        let cb = multiply(&c, &b);
+
+        x3 = square(&add(&da, &cb));
+        z3 = multiply(&x1, &square(&subtract(&da, &cb)));
+        x2 = multiply(&aa, &bb);
+        z2 = multiply(&e, &add(&aa, &multiply(&A24, &e)));
+
+        conditional_swap(&mut x2, &mut x3, bit);
+        conditional_swap(&mut z2, &mut z3, bit);
+    }
+
+    pack(&multiply(&x2, &invert(&z2)))
+}
+
+/*
+   A throwaway key pair for a single handshake, the secret never leaves this struct
+*/
+pub struct EphemeralKeyPair {
+    secret: [u8; X25519_KEY_LENGTH_BYTES],
+    pub public: [u8; X25519_KEY_LENGTH_BYTES],
+}
+
+impl EphemeralKeyPair {
+    pub fn generate() -> Self {
+        let mut secret = [0u8; X25519_KEY_LENGTH_BYTES];
+        rand::rng().fill_bytes(&mut secret);
+        let public = x25519(&secret, &BASE_POINT);
+        EphemeralKeyPair { secret, public }
+    }
+
+    /*
+       Consumes the key pair so the secret can't be reused for a second exchange
+    */
+    pub fn diffie_hellman(
+        self,
+        peer_public: &[u8; X25519_KEY_LENGTH_BYTES],
+    ) -> [u8; X25519_KEY_LENGTH_BYTES] {
+        x25519(&self.secret, peer_public)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptography::encoding::{hex_decode, hex_encode};
+
+    fn key(hex: &str) -> [u8; X25519_KEY_LENGTH_BYTES] {
+        hex_decode(hex).unwrap().try_into().unwrap()
+    }
+
+    /// The two scalar multiplication vectors from RFC 7748 section 5.2
+    #[test]
+    fn matches_rfc_7748_vectors() {
+        let cases = [
+            (
+                "a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4",
+                "e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c",
+                "c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552",
+            ),
+            (
+                "4b66e9d4d1b4673c5ad22691957d6af5c11b6421e0ea01d42ca4169e7918ba0d",
+                "e5210f12786811d3f4b7959d0538ae2c31dbe7106fc03c3efc4cd549c715a493",
+                "95cbde9476e8907d7aade45cb4b873f88b595a68799fa152e6f8f7647aac7957",
+            ),
+        ];
+        for (scalar, u_coordinate, expected) in cases {
+            assert_eq!(
+                hex_encode(&x25519(&key(scalar), &key(u_coordinate))),
+                expected
+            );
+        }
+    }
+
+    /*
+       The first step of the iterated test from section 5.2, the base point as both scalar and u coordinate.
+       The 1000 and million iteration versions take far too long in an unoptimised test build.
+    */
+    #[test]
+    fn matches_rfc_7748_first_iteration() {
+        assert_eq!(
+            hex_encode(&x25519(&BASE_POINT, &BASE_POINT)),
+            "422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079"
+        );
+    }
+
+    /// Alice and Bob from RFC 7748 section 6.1
+    #[test]
+    fn matches_rfc_7748_diffie_hellman() {
+        let pair = |secret| {
+            let secret = key(secret);
+            EphemeralKeyPair {
+                secret,
+                public: x25519(&secret, &BASE_POINT),
+            }
+        };
+        let alice = pair("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = pair("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        assert_eq!(
+            hex_encode(&alice.public),
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+        );
+        assert_eq!(
+            hex_encode(&bob.public),
+            "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"
+        );
+
+        let shared = "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742";
+        let alice_public = alice.public;
+        assert_eq!(hex_encode(&alice.diffie_hellman(&bob.public)), shared);
+        assert_eq!(hex_encode(&bob.diffie_hellman(&alice_public)), shared);
+    }
+}
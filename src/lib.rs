@@ -0,0 +1,15 @@
+pub mod arg_handling;
+pub mod client;
+pub mod commands;
+pub mod cryptography;
+pub mod network;
+pub mod server;
+pub mod subcommands;
+pub mod ui;
+
+pub use client::connection::KryptosClient;
+pub use client::error::ClientError;
+pub use client::event::{ClientEvent, SendStatus};
+
+pub static ERROR: i32 = 1;
+pub static SUCCESS: i32 = 0;
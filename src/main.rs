@@ -1,192 +1,171 @@
-mod arg_handling;
-mod cryptography;
-
-use crate::arg_handling::arg_handling::arg_handling::{EncryptionInfo, KeySize};
-use crate::cryptography::aes::{AESContext, AesMode, AesSize};
-use crate::cryptography::cryptography::EncryptionContext;
-use crate::cryptography::rc4::Rc4State;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use kryptos::arg_handling;
+use kryptos::arg_handling::arg_handling::arg_handling::{
+    BenchConfig, FileConfig, KryptosConfig, Subcommand,
+};
+use kryptos::commands::command::{Command, COMMAND_HELP};
+use kryptos::subcommands::bench::bench;
+use kryptos::subcommands::crypt::{decrypt, encrypt};
+use kryptos::subcommands::keygen::generate_key;
+use kryptos::ui::render::MessageDecoder;
+use kryptos::ui::screen::Screen;
+use kryptos::{ClientEvent, KryptosClient, SendStatus, ERROR};
+use std::io::IsTerminal;
+use std::path::Path;
 use std::process::exit;
-use std::sync::{Arc, Mutex, RwLock};
-use std::thread::{sleep, spawn};
-use std::time::Duration;
+use std::sync::Arc;
+use std::thread::spawn;
 use std::{env, io};
 
-static ERROR: i32 = 1;
-static SUCCESS: i32 = 0;
-type LockedStream = Arc<RwLock<TcpStream>>;
-type StateMachine = Arc<Mutex<EncryptionContext>>;
-
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let config = arg_handling::arg_handling::arg_handling::parse_arguments(args); // arg_handling::arg_handling::arg_handling::arg_handling::arg_handling
-
-    let ip = config.ip;
-    let port = config.port;
-    let session_key = config.key;
-
-    let mut state = match config.enc_type {
-        EncryptionInfo::AesCbc => match session_key.len().into() {
-            KeySize::Size128 => EncryptionContext::new(AESContext::new(
-                AesMode::CBC,
-                AesSize::S128,
-                Some(session_key.as_bytes()),
-            )),
-            KeySize::Size192 => EncryptionContext::new(AESContext::new(
-                AesMode::CBC,
-                AesSize::S192,
-                Some(session_key.as_bytes()),
-            )),
-            KeySize::Size256 => EncryptionContext::new(AESContext::new(
-                AesMode::CBC,
-                AesSize::S256,
-                Some(session_key.as_bytes()),
-            )),
-        },
-        EncryptionInfo::AesCtr => match session_key.len().into() {
-            KeySize::Size128 => EncryptionContext::new(AESContext::new(
-                AesMode::CTR,
-                AesSize::S128,
-                Some(session_key.as_bytes()),
-            )),
-            KeySize::Size192 => EncryptionContext::new(AESContext::new(
-                AesMode::CTR,
-                AesSize::S192,
-                Some(session_key.as_bytes()),
-            )),
-            KeySize::Size256 => EncryptionContext::new(AESContext::new(
-                AesMode::CTR,
-                AesSize::S256,
-                Some(session_key.as_bytes()),
-            )),
-        },
-        EncryptionInfo::AesEcb => match session_key.len().into() {
-            KeySize::Size128 => EncryptionContext::new(AESContext::new(
-                AesMode::ECB,
-                AesSize::S128,
-                Some(session_key.as_bytes()),
-            )),
-            KeySize::Size192 => EncryptionContext::new(AESContext::new(
-                AesMode::ECB,
-                AesSize::S192,
-                Some(session_key.as_bytes()),
-            )),
-            KeySize::Size256 => EncryptionContext::new(AESContext::new(
-                AesMode::ECB,
-                AesSize::S256,
-                Some(session_key.as_bytes()),
-            )),
-        },
-        EncryptionInfo::Rc4 => match session_key.len().into() {
-            KeySize::Size128 => EncryptionContext::new(Rc4State::new(Some(session_key.as_bytes()))),
-            KeySize::Size192 => EncryptionContext::new(Rc4State::new(Some(session_key.as_bytes()))),
-            KeySize::Size256 => EncryptionContext::new(Rc4State::new(Some(session_key.as_bytes()))),
-        },
-    };
+    let subcommand = arg_handling::arg_handling::arg_handling::parse_arguments(args); // arg_handling::arg_handling::arg_handling::arg_handling::arg_handling
 
-    state.context.set_key(session_key.as_bytes());
-    let encryption_context = Arc::new(Mutex::new(state));
-    let result = TcpStream::connect(format!("{}:{}", ip, port));
+    match subcommand {
+        Subcommand::Connect(config) => connect(config),
+        Subcommand::Encrypt(config) => crypt_file(&config, encrypt, "encrypt"),
+        Subcommand::Decrypt(config) => crypt_file(&config, decrypt, "decrypt"),
+        Subcommand::Keygen(key_size) => println!("{}", generate_key(key_size)),
+        Subcommand::Bench(config) => run_bench(&config),
+    }
+}
 
-    if (result.is_ok()) {
-        let mut stream = match result {
-            Ok(x) => x,
-            Err(_) => {
-                println!("Error unwrapping result");
-                exit(ERROR);
-            }
-        };
-        let wrapped_stream = Arc::new(RwLock::new(stream));
-        let read_reference = Arc::clone(&wrapped_stream);
-        let encryption_context_clone = encryption_context.clone();
-        spawn(move || {
-            client_read_routine(Arc::clone(&wrapped_stream), encryption_context_clone);
-        });
-
-        client_input_routine(read_reference, encryption_context);
+fn crypt_file(config: &FileConfig, run: fn(&FileConfig) -> io::Result<u64>, verb: &str) {
+    if let Err(e) = run(config) {
+        eprintln!("Could not {}: {}", verb, e);
+        exit(ERROR);
     }
 }
-fn client_read_routine(
-    tcp_stream: LockedStream,
-    encryption_context: Arc<Mutex<EncryptionContext>>,
-) {
-    loop {
-        let mut buffer = vec![0; 1024];
-        sleep(Duration::from_millis(25));
 
-        let mut stream = match tcp_stream.write() {
-            Ok(x) => x,
-            Err(_) => {
-                println!("TCP stream lock acquisition failed\n");
-                exit(ERROR);
-            }
-        };
-        match stream.set_nonblocking(true) {
+fn run_bench(config: &BenchConfig) {
+    println!(
+        "{} bit keys, {} bytes each way",
+        config.key_size.bits(),
+        config.bytes
+    );
+    for &enc_type in &config.ciphers {
+        if !enc_type.supports_key_size(config.key_size) {
+            println!(
+                "{:<18} skipped, needs a different key size",
+                enc_type.name()
+            );
+            continue;
+        }
+        match bench(enc_type, config.key_size, config.bytes) {
+            Ok(result) => println!(
+                "{:<18} encrypt {:>9.1} MiB/s   decrypt {:>9.1} MiB/s",
+                enc_type.name(),
+                result.encrypt / (1024.0 * 1024.0),
+                result.decrypt / (1024.0 * 1024.0)
+            ),
+            Err(e) => println!("{:<18} failed: {}", enc_type.name(), e),
+        }
+    }
+}
+
+fn connect(config: KryptosConfig) {
+    let cipher_description = format!(
+        "{} with a {} bit key",
+        config.cipher.enc_type.name(),
+        config.cipher.key_size.bits()
+    );
+
+    /*
+       The TUI needs a real terminal on both ends, anything piped in or out gets the plain line mode
+    */
+    let use_tui = !config.line_mode && io::stdin().is_terminal() && io::stdout().is_terminal();
+    let screen = if use_tui {
+        match Screen::tui(
+            format!("{}:{}", config.ip, config.port),
+            cipher_description.clone(),
+        ) {
             Ok(x) => x,
-            Err(_) => {
-                println!("Setting socket to non blocking failed\n");
-                exit(ERROR);
+            Err(e) => {
+                eprintln!(
+                    "Could not start the terminal UI, falling back to line mode: {}",
+                    e
+                );
+                Screen::line_mode()
             }
-        };
+        }
+    } else {
+        Screen::line_mode()
+    };
+    let screen = Arc::new(screen);
+
+    let client = match KryptosClient::connect(&config) {
+        Ok(x) => Arc::new(x),
+        Err(e) => screen.fatal(&format!("Could not start the session: {}", e)),
+    };
+    screen.set_connection_state("connected");
+
+    let events = {
+        let client = client.clone();
+        let screen = screen.clone();
+        spawn(move || client_event_routine(&client, &screen))
+    };
+
+    client_input_routine(&client, &cipher_description, &screen);
 
-        match stream.read(&mut buffer) {
-            Ok(0) => {
-                eprintln!("Remote server has closed the connection\n");
-                exit(ERROR);
-            }
-            Ok(_n) => {
-                let mut decrypted_buffer = buffer.clone();
-                let mut encryption_context_stream = match encryption_context.lock() {
-                    Ok(x) => x,
-                    Err(_) => continue,
-                };
-
-                /*
-                   Drop the rc4 stream after decrypting so that the other thread can acquire the lock when it needs
-                   to
-                */
-                buffer.resize(_n, 0);
-                decrypted_buffer.resize(_n, 0);
-                encryption_context_stream
-                    .context
-                    .decrypt(&mut buffer, &mut decrypted_buffer);
-                drop(encryption_context_stream);
-
-                /*
-                   Print the data that we read on the socket and 0 the buffer up until the point we just read to
-                   We do this to avoid iterating the entire buffer every time
-                */
-                for byte in &decrypted_buffer {
-                    print!("{}", *byte as char);
+    client.close();
+    let _ = events.join();
+    screen.close();
+}
+
+/*
+   Puts everything the client hears about on the screen, runs until the client is closed
+*/
+fn client_event_routine(client: &KryptosClient, screen: &Screen) {
+    let mut text_decoder = MessageDecoder::new();
+    for event in client.events() {
+        match event {
+            ClientEvent::Message(message) => {
+                screen.message(&text_decoder.decode(message.stream_id, &message.body))
+            }
+            ClientEvent::FileReceived { name, size } => screen.notice(&format!(
+                "Received {} ({} bytes, SHA-256 verified), /save [path] to keep it or /discard to drop it",
+                name, size
+            )),
+            ClientEvent::Notice(text) => screen.notice(&text),
+            ClientEvent::ConnectionLost(e) => {
+                screen.set_connection_state("disconnected");
+                screen.notice(&format!("Lost the connection: {}, reconnecting", e));
+            }
+            ClientEvent::Reconnecting { attempt, delay } => {
+                screen.set_connection_state(&format!(
+                    "reconnecting, attempt {} in {:.1}s",
+                    attempt,
+                    delay.as_secs_f64()
+                ));
+            }
+            ClientEvent::Reconnected { sent } => {
+                screen.set_connection_state("connected");
+                screen.notice("Reconnected");
+                if sent > 0 {
+                    screen.notice(&format!("Sent {} messages typed while disconnected", sent));
                 }
-                println!()
             }
-            //Since we require non blocking reads due to the lock scheme, just continue the loop, dropping the lock
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-            Err(_) => exit(ERROR),
-        };
-
-        drop(stream);
-        io::stdout().flush().unwrap();
+            ClientEvent::Closed(Some(e)) => {
+                screen.set_connection_state("disconnected");
+                screen.fatal(&format!("Disconnected: {}", e));
+            }
+            ClientEvent::Closed(None) => {}
+        }
     }
 }
 
 /*
-   As per the explicit drops due to the nature of both threads requiring access we need to manually drop (or use scope blocks but
-   manual drops are more explicit which is good) everything once we're done,otherwise locks would be held longer than is
-   absolutely necessary
+   Reads what the user types and runs it, chat goes to the room and commands get handled here.
+   Returns once the user quits or input runs out.
 */
-fn client_input_routine(stream: LockedStream, rc4: Arc<Mutex<EncryptionContext>>) {
+fn client_input_routine(client: &KryptosClient, cipher_description: &str, screen: &Screen) {
+    let mut nick: Option<String> = None;
     loop {
-        let mut line = String::new();
-
-        match io::stdin().read_line(&mut line) {
-            Ok(x) => x,
-            Err(_) => {
-                println!("read_line triggered error");
-                exit(ERROR);
-            }
+        /*
+           Out of input (or Ctrl-C in the TUI) is the same as /quit
+        */
+        let line = match screen.read_line() {
+            Some(x) => x,
+            None => return,
         };
 
         let line = line.trim();
@@ -194,28 +173,99 @@ fn client_input_routine(stream: LockedStream, rc4: Arc<Mutex<EncryptionContext>>
         if line.is_empty() {
             continue;
         }
-        let mut encrypted_buffer = vec![0; line.len()];
-
-        let mut rc4_unlocked = rc4.lock().unwrap();
-        rc4_unlocked
-            .context
-            .encrypt(&mut line.as_bytes().to_vec(), &mut encrypted_buffer);
-        drop(rc4_unlocked);
 
-        let mut stream = match stream.write() {
+        /*
+           Anything that goes wrong parsing a command stays on our screen, a typo never gets sent to the room
+        */
+        let command = match Command::parse(line) {
             Ok(x) => x,
-            Err(_) => {
-                println!("Acquiring write lock on stream failed");
-                exit(ERROR);
+            Err(e) => {
+                screen.notice(&e.to_string());
+                continue;
             }
         };
-        match stream.write(encrypted_buffer.as_slice()) {
-            Ok(x) => x,
-            Err(_) => {
-                println!("Failed to write line to stream");
-                exit(ERROR);
+
+        let chat = match command {
+            Command::Chat(text) => match &nick {
+                Some(nick) => format!("<{}> {}", nick, text),
+                None => text,
+            },
+            Command::Me(action) => match &nick {
+                Some(nick) => format!("* {} {}", nick, action),
+                None => format!("* {}", action),
+            },
+            Command::Nick(name) => {
+                screen.notice(&format!("You are now known as {}", name));
+                nick = Some(name);
+                continue;
+            }
+            Command::Quit => return,
+            Command::Clear => {
+                screen.clear();
+                continue;
+            }
+            Command::Help => {
+                for (usage, description) in COMMAND_HELP {
+                    screen.notice(&format!("  {:<14} {}", usage, description));
+                }
+                continue;
+            }
+            Command::Rekey => {
+                match client.rekey() {
+                    Ok(epoch) => screen.notice(&format!("Moved to key epoch {}", epoch)),
+                    Err(e) => screen.notice(&format!("Failed to rekey: {}", e)),
+                }
+                continue;
+            }
+            Command::Cipher => {
+                screen.notice(&format!(
+                    "{}, key epoch {}",
+                    cipher_description,
+                    client.epoch()
+                ));
+                continue;
+            }
+            Command::Raw(line) => {
+                if let Err(e) = client.send_raw(&line) {
+                    screen.notice(&format!("Nothing was sent: {}", e));
+                }
+                continue;
+            }
+            Command::Send(path) => {
+                match client.send_file(Path::new(&path)) {
+                    Ok(header) => {
+                        screen.notice(&format!("Sent {} ({} bytes)", header.name, header.size))
+                    }
+                    Err(e) => screen.notice(&format!("Could not send: {}", e)),
+                }
+                continue;
+            }
+            Command::Save(path) => {
+                match client.save_received_file(path.as_deref()) {
+                    Ok((name, path)) => {
+                        screen.notice(&format!("Saved {} to {}", name, path.display()))
+                    }
+                    Err(e) => screen.notice(&format!("Could not save: {}", e)),
+                }
+                continue;
+            }
+            Command::Discard => {
+                match client.discard_received_file() {
+                    Some(name) => screen.notice(&format!("Discarded {}", name)),
+                    None => screen.notice("No received files waiting"),
+                }
+                continue;
             }
         };
-        drop(stream);
+
+        screen.own_message(&chat);
+        match client.send(&chat) {
+            Ok(SendStatus::Sent) => {}
+            Ok(SendStatus::Queued { waiting }) => screen.notice(&format!(
+                "Not connected, message will be sent once reconnected ({} waiting)",
+                waiting
+            )),
+            Err(e) => screen.notice(&format!("Message not sent: {}", e)),
+        }
     }
 }
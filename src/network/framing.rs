@@ -0,0 +1,273 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+/*
+   Every frame on the wire is a 4 byte big endian length header followed by exactly that many bytes of payload.
+   One payload is always the output of exactly one Encryption::encrypt call, so the other side can hand it straight
+   to Encryption::decrypt without caring how TCP decided to split or merge the segments.
+*/
+pub const FRAME_HEADER_LENGTH_BYTES: usize = 4;
+
+/*
+   1 MiB is way more than any chat line will ever need, anything bigger is either a bug or someone being malicious
+   so we refuse it rather than allocating whatever the header tells us to
+*/
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The payload (or the length header we received) is larger than MAX_FRAME_SIZE
+    Oversized(usize),
+    /// The stream ended part way through a frame
+    Truncated {
+        expected: usize,
+        received: usize,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Oversized(size) => write!(
+                f,
+                "frame of {} bytes exceeds the maximum frame size of {} bytes",
+                size, MAX_FRAME_SIZE
+            ),
+            FrameError::Truncated { expected, received } => write!(
+                f,
+                "stream ended mid frame, expected {} bytes but only received {}",
+                expected, received
+            ),
+            FrameError::Io(error) => write!(f, "i/o error while framing: {}", error),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(error: io::Error) -> Self {
+        FrameError::Io(error)
+    }
+}
+
+/// Prefixes the payload with its length header, failing if it is too big to ever be accepted by the other side
+pub fn encode_frame(payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::Oversized(payload.len()));
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH_BYTES + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/*
+   Header and payload go out in a single write_all so a frame is never interleaved with anything else
+   written to the same stream
+*/
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), FrameError> {
+    let frame = encode_frame(payload)?;
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/*
+   Blocking read of exactly one frame. Ok(None) means the peer closed the stream cleanly in between frames,
+   closing it part way through one is a Truncated error.
+*/
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, FrameError> {
+    let mut header = [0u8; FRAME_HEADER_LENGTH_BYTES];
+    let received = read_fully(reader, &mut header)?;
+    if received == 0 {
+        return Ok(None);
+    }
+    if received < FRAME_HEADER_LENGTH_BYTES {
+        return Err(FrameError::Truncated {
+            expected: FRAME_HEADER_LENGTH_BYTES,
+            received,
+        });
+    }
+
+    let length = u32::from_be_bytes(header) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(FrameError::Oversized(length));
+    }
+
+    let mut payload = vec![0u8; length];
+    let received = read_fully(reader, &mut payload)?;
+    if received < length {
+        return Err(FrameError::Truncated {
+            expected: FRAME_HEADER_LENGTH_BYTES + length,
+            received: FRAME_HEADER_LENGTH_BYTES + received,
+        });
+    }
+    Ok(Some(payload))
+}
+
+/*
+   Like read_exact except hitting EOF early isn't an error, we hand back how much we got so the caller
+   can tell a clean close apart from a truncated frame
+*/
+fn read_fully<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/*
+   Accumulates raw bytes off the socket and hands back complete frames as they become available.
+   Reads can return half a frame, several frames, or one and a bit, this takes care of stitching them back together.
+*/
+#[derive(Debug, Default)]
+pub struct FrameBuffer {
+    buffer: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        FrameBuffer { buffer: Vec::new() }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete frame payload, or None if we need more bytes first
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.buffer.len() < FRAME_HEADER_LENGTH_BYTES {
+            return Ok(None);
+        }
+
+        let length = self.frame_length();
+
+        /*
+           Check this before waiting on the payload, otherwise a bogus header would have us buffering
+           up to 4 GiB before we ever noticed
+        */
+        if length > MAX_FRAME_SIZE {
+            return Err(FrameError::Oversized(length));
+        }
+
+        if self.buffer.len() < FRAME_HEADER_LENGTH_BYTES + length {
+            return Ok(None);
+        }
+
+        let payload =
+            self.buffer[FRAME_HEADER_LENGTH_BYTES..FRAME_HEADER_LENGTH_BYTES + length].to_vec();
+        self.buffer.drain(..FRAME_HEADER_LENGTH_BYTES + length);
+        Ok(Some(payload))
+    }
+
+    /*
+       Called once the stream has hit EOF, if there are leftover bytes sitting in here the peer
+       hung up on us part way through a frame
+    */
+    pub fn finish(&self) -> Result<(), FrameError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let expected = if self.buffer.len() < FRAME_HEADER_LENGTH_BYTES {
+            FRAME_HEADER_LENGTH_BYTES
+        } else {
+            FRAME_HEADER_LENGTH_BYTES + self.frame_length()
+        };
+
+        Err(FrameError::Truncated {
+            expected,
+            received: self.buffer.len(),
+        })
+    }
+
+    fn frame_length(&self) -> usize {
+        let mut header = [0u8; FRAME_HEADER_LENGTH_BYTES];
+        header.copy_from_slice(&self.buffer[..FRAME_HEADER_LENGTH_BYTES]);
+        u32::from_be_bytes(header) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stitches_frames_back_together() {
+        let mut stream = encode_frame(b"first").unwrap();
+        stream.extend(encode_frame(b"").unwrap());
+        stream.extend(encode_frame(b"third frame").unwrap());
+
+        // A byte at a time is as split up as a frame can get
+        let mut frames = FrameBuffer::new();
+        let mut received = Vec::new();
+        for byte in &stream {
+            frames.push(&[*byte]);
+            while let Some(frame) = frames.next_frame().unwrap() {
+                received.push(frame);
+            }
+        }
+        assert_eq!(received, [&b"first"[..], b"", b"third frame"]);
+        assert!(frames.finish().is_ok());
+
+        // And all three in a single read
+        frames.push(&stream);
+        assert_eq!(frames.next_frame().unwrap().unwrap(), b"first");
+        assert_eq!(frames.next_frame().unwrap().unwrap(), b"");
+        assert_eq!(frames.next_frame().unwrap().unwrap(), b"third frame");
+        assert_eq!(frames.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_oversized_and_truncated_frames() {
+        let mut frames = FrameBuffer::new();
+        frames.push(&((MAX_FRAME_SIZE + 1) as u32).to_be_bytes());
+        assert!(matches!(
+            frames.next_frame(),
+            Err(FrameError::Oversized(x)) if x == MAX_FRAME_SIZE + 1
+        ));
+        assert!(matches!(
+            encode_frame(&vec![0; MAX_FRAME_SIZE + 1]),
+            Err(FrameError::Oversized(_))
+        ));
+
+        let mut frames = FrameBuffer::new();
+        frames.push(&encode_frame(b"cut short").unwrap()[..6]);
+        assert_eq!(frames.next_frame().unwrap(), None);
+        assert!(matches!(
+            frames.finish(),
+            Err(FrameError::Truncated {
+                expected: 13,
+                received: 6
+            })
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_write_and_read() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"hello").unwrap();
+        write_frame(&mut wire, &[0xff; 1000]).unwrap();
+        assert_eq!(wire.len(), 2 * FRAME_HEADER_LENGTH_BYTES + 1005);
+
+        let mut reader = wire.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"hello");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), [0xff; 1000]);
+        assert!(read_frame(&mut reader).unwrap().is_none());
+
+        let mut truncated = &wire[..wire.len() - 1];
+        read_frame(&mut truncated).unwrap();
+        assert!(matches!(
+            read_frame(&mut truncated),
+            Err(FrameError::Truncated { .. })
+        ));
+    }
+}
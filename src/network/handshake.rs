@@ -0,0 +1,419 @@
+use crate::cryptography::cryptography::constant_time_eq;
+use crate::cryptography::hmac::{hmac_sha256, HMAC_SHA256_LENGTH_BYTES};
+use crate::cryptography::kdf::hkdf_sha256;
+use crate::cryptography::x25519::{EphemeralKeyPair, X25519_KEY_LENGTH_BYTES};
+use crate::network::framing::{read_frame, write_frame, FrameError};
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/*
+   Labels so that a MAC or key computed for one purpose can never be passed off as one for another
+*/
+const HANDSHAKE_MAC_LABEL: &[u8] = b"kryptos handshake";
+const SESSION_KEY_LABEL: &[u8] = b"kryptos session key";
+const CONFIRMATION_KEY_LABEL: &[u8] = b"kryptos key confirmation";
+const REPLY_LABEL: &[u8] = b"kryptos handshake reply";
+const CONFIRM_LABEL: &[u8] = b"kryptos handshake confirm";
+
+/*
+   Handshake frames share the socket with whatever else is going on in the room, this is how they are told apart
+   from encrypted messages. A ciphertext starting with these exact 8 bytes is a 1 in 2^64 chance.
+*/
+const HANDSHAKE_MAGIC: &[u8; 8] = b"KRYPTOHS";
+
+/// How long connecting waits for someone to handshake with before giving up
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type PublicKey = [u8; X25519_KEY_LENGTH_BYTES];
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Frame(FrameError),
+    /// The peer hung up before sending its half of the handshake
+    ConnectionClosed,
+    /// The peer's key confirmation didn't match, it didn't end up with the same key we did
+    AuthenticationFailed,
+    /// The peer sent a low order point that would force the shared secret to zero
+    InvalidPublicKey,
+    /// Nobody finished a handshake with us in time, either the room is empty or everyone already paired up
+    Timeout,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Frame(error) => write!(f, "{}", error),
+            HandshakeError::ConnectionClosed => {
+                write!(f, "connection closed during the handshake")
+            }
+            HandshakeError::AuthenticationFailed => {
+                write!(f, "peer could not confirm the session key")
+            }
+            HandshakeError::InvalidPublicKey => write!(f, "peer sent an invalid public key"),
+            HandshakeError::Timeout => write!(f, "no peer completed the handshake in time"),
+        }
+    }
+}
+
+/*
+   A read timeout on the socket comes back as one of these two depending on the platform
+*/
+impl From<FrameError> for HandshakeError {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::Io(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                HandshakeError::Timeout
+            }
+            _ => HandshakeError::Frame(error),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HandshakeKind {
+    Hello,   // Here is my public key, is anyone there
+    Reply,   // Answers a Hello with our own public key and proof we derived the key
+    Confirm, // Answers a Reply with proof we derived the same key
+}
+
+impl HandshakeKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            HandshakeKind::Hello => 1,
+            HandshakeKind::Reply => 2,
+            HandshakeKind::Confirm => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<HandshakeKind> {
+        match byte {
+            1 => Some(HandshakeKind::Hello),
+            2 => Some(HandshakeKind::Reply),
+            3 => Some(HandshakeKind::Confirm),
+            _ => None,
+        }
+    }
+}
+
+/*
+   On the wire a handshake message is
+   magic || kind || sender's public key || [recipient's public key || key confirmation] || MAC
+
+   A Hello stops after the sender's key, it isn't for anyone in particular. The MAC is HMAC-SHA256 under the
+   pre-shared key over everything between the magic and itself, so nobody without the passphrase can take part.
+*/
+struct HandshakeMessage {
+    kind: HandshakeKind,
+    sender: PublicKey,
+    recipient: Option<(PublicKey, [u8; HMAC_SHA256_LENGTH_BYTES])>,
+}
+
+impl HandshakeMessage {
+    fn encode(&self, pre_shared_key: &[u8]) -> Vec<u8> {
+        let mut body = vec![self.kind.to_byte()];
+        body.extend_from_slice(&self.sender);
+        if let Some((recipient, confirmation)) = &self.recipient {
+            body.extend_from_slice(recipient);
+            body.extend_from_slice(confirmation);
+        }
+
+        let mut frame = HANDSHAKE_MAGIC.to_vec();
+        frame.extend_from_slice(&body);
+        frame.extend_from_slice(&handshake_mac(pre_shared_key, &body));
+        frame
+    }
+
+    /*
+       None for anything that isn't a handshake message under our pre-shared key. That covers the rest of the
+       room's traffic as well as anyone handshaking with a different passphrase, neither is for us.
+    */
+    fn decode(frame: &[u8], pre_shared_key: &[u8]) -> Option<HandshakeMessage> {
+        let rest = frame.strip_prefix(HANDSHAKE_MAGIC)?;
+        let split = rest.len().checked_sub(HMAC_SHA256_LENGTH_BYTES)?;
+        let (body, mac) = rest.split_at(split);
+        if !constant_time_eq(&handshake_mac(pre_shared_key, body), mac) {
+            return None;
+        }
+
+        let (&kind, body) = body.split_first()?;
+        let kind = HandshakeKind::from_byte(kind)?;
+        let expected_length = match kind {
+            HandshakeKind::Hello => X25519_KEY_LENGTH_BYTES,
+            _ => 2 * X25519_KEY_LENGTH_BYTES + HMAC_SHA256_LENGTH_BYTES,
+        };
+        if body.len() != expected_length {
+            return None;
+        }
+
+        let (sender, rest) = body.split_at(X25519_KEY_LENGTH_BYTES);
+        let recipient = match kind {
+            HandshakeKind::Hello => None,
+            _ => {
+                let (recipient, confirmation) = rest.split_at(X25519_KEY_LENGTH_BYTES);
+                Some((recipient.try_into().ok()?, confirmation.try_into().ok()?))
+            }
+        };
+        Some(HandshakeMessage {
+            kind,
+            sender: sender.try_into().ok()?,
+            recipient,
+        })
+    }
+
+    /// A Reply or Confirm for us, from whoever sent it, along with its key confirmation
+    fn addressed_to(&self, public: &PublicKey) -> Option<&[u8; HMAC_SHA256_LENGTH_BYTES]> {
+        match &self.recipient {
+            Some((recipient, confirmation)) if recipient == public => Some(confirmation),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a frame is part of someone's handshake, the reader skips these once we are past our own
+pub fn is_handshake_frame(frame: &[u8]) -> bool {
+    frame.starts_with(HANDSHAKE_MAGIC)
+}
+
+fn handshake_mac(pre_shared_key: &[u8], body: &[u8]) -> [u8; HMAC_SHA256_LENGTH_BYTES] {
+    let mut message = HANDSHAKE_MAC_LABEL.to_vec();
+    message.extend_from_slice(body);
+    hmac_sha256(pre_shared_key, &message)
+}
+
+/*
+   What the two of us end up sharing. The session key comes out of HKDF over the shared secret, salted with the
+   pre-shared key, with both public keys (sorted, so both sides agree on the order) bound into the info. The
+   confirmation key comes out the same way under another label and is only used to prove we got there.
+*/
+struct Agreement {
+    peer: PublicKey,
+    session_key: Vec<u8>,
+    confirmation_key: [u8; HMAC_SHA256_LENGTH_BYTES],
+}
+
+impl Agreement {
+    fn new(
+        key_pair: EphemeralKeyPair,
+        peer: PublicKey,
+        pre_shared_key: &[u8],
+        session_key_length: usize,
+    ) -> Result<Agreement, HandshakeError> {
+        let public = key_pair.public;
+        let (first, second) = if public <= peer {
+            (public, peer)
+        } else {
+            (peer, public)
+        };
+
+        let shared_secret = key_pair.diffie_hellman(&peer);
+        if constant_time_eq(&shared_secret, &[0u8; X25519_KEY_LENGTH_BYTES]) {
+            return Err(HandshakeError::InvalidPublicKey);
+        }
+
+        let derive = |label: &[u8], output: &mut [u8]| {
+            let mut info = label.to_vec();
+            info.extend_from_slice(&first);
+            info.extend_from_slice(&second);
+            hkdf_sha256(pre_shared_key, &shared_secret, &info, output);
+        };
+        let mut session_key = vec![0u8; session_key_length];
+        derive(SESSION_KEY_LABEL, &mut session_key);
+        let mut confirmation_key = [0u8; HMAC_SHA256_LENGTH_BYTES];
+        derive(CONFIRMATION_KEY_LABEL, &mut confirmation_key);
+
+        Ok(Agreement {
+            peer,
+            session_key,
+            confirmation_key,
+        })
+    }
+
+    /*
+       Proof that whoever sent from has the key, labelled by step so a Reply can never be bounced back
+       as a Confirm
+    */
+    fn confirmation(
+        &self,
+        label: &[u8],
+        from: &PublicKey,
+        to: &PublicKey,
+    ) -> [u8; HMAC_SHA256_LENGTH_BYTES] {
+        let mut message = label.to_vec();
+        message.extend_from_slice(from);
+        message.extend_from_slice(to);
+        hmac_sha256(&self.confirmation_key, &message)
+    }
+
+    fn check(
+        &self,
+        label: &[u8],
+        from: &PublicKey,
+        to: &PublicKey,
+        confirmation: &[u8],
+    ) -> Result<(), HandshakeError> {
+        match constant_time_eq(&self.confirmation(label, from, to), confirmation) {
+            true => Ok(()),
+            false => Err(HandshakeError::AuthenticationFailed),
+        }
+    }
+}
+
+/*
+   Ephemeral X25519 exchange between two clients done right after connecting, before any chat traffic.
+
+   The relay sends every frame to everyone, us included, and whoever was there first never saw our Hello
+   if they connected before us. So it goes
+     1. we send a Hello and wait, skipping our own frames as the relay echoes them back
+     2. a Hello from someone else gets a Reply carrying our public key and a key confirmation
+     3. a Reply to our Hello gets checked and answered with a Confirm, we're done
+     4. a Confirm to our Reply gets checked, we're done
+   If both Hellos cross on the wire both sides Reply and both finish at step 3, the keys come out the same either
+   way. Once we have answered one Hello we stick with that peer, it is a two person exchange. Anyone joining
+   afterwards has nobody left handshaking to answer it, so it gives up with Timeout once the deadline passes.
+   The deadline is only checked between frames, a blocking stream also needs a read timeout set on it for a
+   silent room to ever time out.
+
+   The pre-shared key is only used to authenticate the exchange so a man in the middle can't swap in their own
+   keys. Since the key pairs are thrown away afterwards, leaking the pre-shared key later doesn't decrypt any
+   of this session's traffic.
+
+   session_key is filled completely, so its length picks the size of the key we derive.
+*/
+pub fn perform_handshake<S: Read + Write>(
+    stream: &mut S,
+    pre_shared_key: &[u8],
+    session_key: &mut [u8],
+    deadline: Instant,
+) -> Result<(), HandshakeError> {
+    let mut key_pair = Some(EphemeralKeyPair::generate());
+    let public = key_pair.as_ref().unwrap().public;
+    let send = |stream: &mut S, kind, recipient| {
+        let message = HandshakeMessage {
+            kind,
+            sender: public,
+            recipient,
+        };
+        write_frame(stream, &message.encode(pre_shared_key))
+    };
+    send(stream, HandshakeKind::Hello, None)?;
+
+    // Set once we have replied to someone's Hello, the key pair is used up by then
+    let mut replied: Option<Agreement> = None;
+    loop {
+        if Instant::now() >= deadline {
+            return Err(HandshakeError::Timeout);
+        }
+        let frame = match read_frame(stream)? {
+            Some(x) => x,
+            None => return Err(HandshakeError::ConnectionClosed),
+        };
+        let message = match HandshakeMessage::decode(&frame, pre_shared_key) {
+            Some(x) if x.sender != public => x,
+            _ => continue,
+        };
+
+        let agreement = match message.kind {
+            HandshakeKind::Hello => {
+                if let Some(key_pair) = key_pair.take() {
+                    let agreement = Agreement::new(
+                        key_pair,
+                        message.sender,
+                        pre_shared_key,
+                        session_key.len(),
+                    )?;
+                    let confirmation =
+                        agreement.confirmation(REPLY_LABEL, &public, &message.sender);
+                    send(
+                        stream,
+                        HandshakeKind::Reply,
+                        Some((message.sender, confirmation)),
+                    )?;
+                    replied = Some(agreement);
+                }
+                continue;
+            }
+            HandshakeKind::Reply => {
+                let confirmation = match message.addressed_to(&public) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let agreement = match (replied.take(), key_pair.take()) {
+                    (Some(x), _) if x.peer == message.sender => x,
+                    (None, Some(key_pair)) => {
+                        Agreement::new(key_pair, message.sender, pre_shared_key, session_key.len())?
+                    }
+                    (other, _) => {
+                        // Someone else answering after we already picked a peer
+                        replied = other;
+                        continue;
+                    }
+                };
+                agreement.check(REPLY_LABEL, &message.sender, &public, confirmation)?;
+                let confirmation = agreement.confirmation(CONFIRM_LABEL, &public, &message.sender);
+                send(
+                    stream,
+                    HandshakeKind::Confirm,
+                    Some((message.sender, confirmation)),
+                )?;
+                agreement
+            }
+            HandshakeKind::Confirm => {
+                let confirmation = match message.addressed_to(&public) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                match replied.take() {
+                    Some(x) if x.peer == message.sender => {
+                        x.check(CONFIRM_LABEL, &message.sender, &public, confirmation)?;
+                        x
+                    }
+                    other => {
+                        replied = other;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        session_key.copy_from_slice(&agreement.session_key);
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_only_decode_under_the_right_key() {
+        let message = HandshakeMessage {
+            kind: HandshakeKind::Reply,
+            sender: [1; X25519_KEY_LENGTH_BYTES],
+            recipient: Some(([2; X25519_KEY_LENGTH_BYTES], [3; HMAC_SHA256_LENGTH_BYTES])),
+        };
+        let frame = message.encode(b"psk");
+        assert!(is_handshake_frame(&frame));
+
+        let decoded = HandshakeMessage::decode(&frame, b"psk").unwrap();
+        assert_eq!(decoded.kind, HandshakeKind::Reply);
+        assert_eq!(decoded.sender, message.sender);
+        assert_eq!(
+            decoded.addressed_to(&[2; X25519_KEY_LENGTH_BYTES]),
+            Some(&[3; 32])
+        );
+        assert_eq!(decoded.addressed_to(&[1; X25519_KEY_LENGTH_BYTES]), None);
+
+        assert!(HandshakeMessage::decode(&frame, b"other").is_none());
+        let mut tampered = frame.clone();
+        tampered[HANDSHAKE_MAGIC.len() + 1] ^= 1;
+        assert!(HandshakeMessage::decode(&tampered, b"psk").is_none());
+        assert!(HandshakeMessage::decode(&frame[..40], b"psk").is_none());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::network::replay::now_millis;
+use std::fmt;
+
+/*
+   What goes inside every encrypted frame, a single kind byte, the sender's stream id, sequence number and
+   timestamp, then the body. Keeping all of that inside the encryption means nobody on the path can tell control
+   traffic from chat, and the header is covered by the same tag/MAC as the message itself.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    Chat,
+    Rekey,     // Body is the epoch (big endian u64) the sender just moved to
+    FileStart, // Body is a transfer::FileHeader
+    FileChunk, // Body is the transfer id (big endian u64) followed by the next piece of the file
+}
+
+impl MessageKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            MessageKind::Chat => 0,
+            MessageKind::Rekey => 1,
+            MessageKind::FileStart => 2,
+            MessageKind::FileChunk => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<MessageKind> {
+        match byte {
+            0 => Some(MessageKind::Chat),
+            1 => Some(MessageKind::Rekey),
+            2 => Some(MessageKind::FileStart),
+            3 => Some(MessageKind::FileChunk),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageError {
+    Empty,
+    UnknownKind(u8),
+    /// The body doesn't fit what its kind says it should be
+    MalformedBody(MessageKind),
+    /// Too short to hold the stream id, sequence number and timestamp
+    MalformedHeader,
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Empty => write!(f, "empty message"),
+            MessageError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            MessageError::MalformedBody(kind) => write!(f, "malformed {:?} message", kind),
+            MessageError::MalformedHeader => write!(f, "malformed message header"),
+        }
+    }
+}
+
+/*
+   The stream id, sequence number and timestamp each go out as a big endian u64
+*/
+const HEADER_FIELD_LENGTH_BYTES: usize = 8;
+const HEADER_LENGTH_BYTES: usize = 3 * HEADER_FIELD_LENGTH_BYTES;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub kind: MessageKind,
+    pub stream_id: u64, // Random per sender, so the receiver can keep a separate sequence for each direction
+    pub sequence: u64,
+    pub timestamp: u64, // Milliseconds since the Unix epoch when it was sent, so a replay of an old one can be spotted
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    /// The stream id and sequence number are left at 0 for the sender to fill in, the timestamp is now
+    pub fn new(kind: MessageKind, body: Vec<u8>) -> Message {
+        Message {
+            kind,
+            stream_id: 0,
+            sequence: 0,
+            timestamp: now_millis(),
+            body,
+        }
+    }
+
+    pub fn chat(body: &[u8]) -> Message {
+        Message::new(MessageKind::Chat, body.to_vec())
+    }
+
+    pub fn rekey(epoch: u64) -> Message {
+        Message::new(MessageKind::Rekey, epoch.to_be_bytes().to_vec())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(1 + HEADER_LENGTH_BYTES + self.body.len());
+        encoded.push(self.kind.to_byte());
+        encoded.extend_from_slice(&self.stream_id.to_be_bytes());
+        encoded.extend_from_slice(&self.sequence.to_be_bytes());
+        encoded.extend_from_slice(&self.timestamp.to_be_bytes());
+        encoded.extend_from_slice(&self.body);
+        encoded
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Message, MessageError> {
+        let (&kind, body) = match bytes.split_first() {
+            Some(x) => x,
+            None => return Err(MessageError::Empty),
+        };
+        let kind = match MessageKind::from_byte(kind) {
+            Some(x) => x,
+            None => return Err(MessageError::UnknownKind(kind)),
+        };
+
+        if body.len() < HEADER_LENGTH_BYTES {
+            return Err(MessageError::MalformedHeader);
+        }
+        let (stream_id, rest) = body.split_at(HEADER_FIELD_LENGTH_BYTES);
+        let (sequence, rest) = rest.split_at(HEADER_FIELD_LENGTH_BYTES);
+        let (timestamp, body) = rest.split_at(HEADER_FIELD_LENGTH_BYTES);
+
+        let message = Message {
+            kind,
+            stream_id: read_u64(stream_id)?,
+            sequence: read_u64(sequence)?,
+            timestamp: read_u64(timestamp)?,
+            body: body.to_vec(),
+        };
+        if kind == MessageKind::Rekey && message.epoch().is_none() {
+            return Err(MessageError::MalformedBody(kind));
+        }
+
+        Ok(message)
+    }
+
+    /// The epoch carried by a Rekey message
+    pub fn epoch(&self) -> Option<u64> {
+        if self.kind != MessageKind::Rekey {
+            return None;
+        }
+        read_u64(&self.body).ok()
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> Result<u64, MessageError> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| MessageError::MalformedHeader)?;
+    Ok(u64::from_be_bytes(bytes))
+}
@@ -0,0 +1,6 @@
+pub mod framing;
+pub mod handshake;
+pub mod message;
+pub mod reconnect;
+pub mod replay;
+pub mod transfer;
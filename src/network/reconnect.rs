@@ -0,0 +1,69 @@
+use rand::Rng;
+use std::time::Duration;
+
+/*
+   Where the backoff starts and where it stops growing. Half a second is quick enough for a server that is
+   just restarting, a minute is slow enough not to hammer one that is down for good.
+*/
+pub const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/*
+   Exponential backoff with jitter. Each attempt doubles the delay up to the cap, then a random amount of
+   up to half of it is taken off so a room full of clients that all lost the server at once don't all come
+   back at the same instant.
+*/
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY)
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// How many delays have been handed out since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        // Past 2^16 the cap has long since taken over, this just keeps the shift from overflowing
+        let factor = 1u32 << self.attempt.min(16);
+        self.attempt += 1;
+
+        let delay = self.initial.saturating_mul(factor).min(self.max);
+        let jitter = rand::rng().random_range(0.0..=0.5);
+        delay.mul_f64(1.0 - jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially_with_jitter_up_to_the_cap() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(2));
+        let mut ceiling = Duration::from_millis(100);
+        for _ in 0..40 {
+            let delay = backoff.next_delay();
+            assert!(delay <= ceiling, "{:?} over {:?}", delay, ceiling);
+            assert!(delay >= ceiling / 2, "{:?} under {:?}", delay, ceiling / 2);
+            ceiling = (ceiling * 2).min(Duration::from_secs(2));
+        }
+        assert_eq!(backoff.attempt(), 40);
+    }
+}
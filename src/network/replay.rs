@@ -0,0 +1,309 @@
+use rand::RngCore;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+   How far behind the newest sequence number a message can be and still get through. Anything older than
+   this is dropped even if we never saw it, same as the anti replay window in IPsec.
+*/
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/*
+   How far a message's timestamp can be from our clock, either way, before we call it stale. This is what stops
+   an old recording being replayed as a stream we've never heard from, so it only needs to be loose enough for
+   everyone's clocks being a little off.
+*/
+pub const MAX_CLOCK_SKEW_MILLIS: u64 = 2 * 60 * 1000;
+
+/*
+   How many senders we keep a window for. Past this the one we heard from least recently gets dropped, so a room
+   full of stream ids that each send once can't grow this forever.
+*/
+pub const MAX_TRACKED_STREAMS: usize = 1024;
+
+/// Milliseconds since the Unix epoch, what message timestamps are in
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_millis() as u64)
+}
+
+/*
+   Stamps everything we send with our stream id and the next sequence number. The stream id is picked at random
+   when we start so that everyone else in the room can track our direction separately from everyone else's.
+*/
+pub struct OutgoingSequence {
+    stream_id: u64,
+    next: u64,
+}
+
+impl Default for OutgoingSequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutgoingSequence {
+    pub fn new() -> Self {
+        OutgoingSequence {
+            stream_id: rand::rng().next_u64(),
+            next: 0,
+        }
+    }
+
+    pub fn stream_id(&self) -> u64 {
+        self.stream_id
+    }
+
+    /// Hands out the next sequence number, never the same one twice
+    pub fn next_sequence(&mut self) -> u64 {
+        let sequence = self.next;
+        self.next += 1;
+        sequence
+    }
+}
+
+/// A message that made it through the replay check
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery {
+    InOrder,
+    /// Arrived after a message with a higher sequence number, but inside the window and not seen before
+    Reordered,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    /// We have already accepted a message with this sequence number
+    Duplicate(u64),
+    /// Too far behind the newest message to tell whether it is a replay
+    OutsideWindow(u64),
+    /// Timestamped too far from now, or from before we stopped tracking its sender
+    Stale(u64),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Duplicate(sequence) => {
+                write!(
+                    f,
+                    "message {} was already received, possible replay",
+                    sequence
+                )
+            }
+            ReplayError::OutsideWindow(sequence) => write!(
+                f,
+                "message {} is more than {} messages old, possible replay",
+                sequence, REPLAY_WINDOW_SIZE
+            ),
+            ReplayError::Stale(sequence) => {
+                write!(
+                    f,
+                    "message {} has a stale timestamp, possible replay",
+                    sequence
+                )
+            }
+        }
+    }
+}
+
+/*
+   Sliding window over one sender's sequence numbers. Bit i of seen is set once we have accepted
+   highest - i, so anything inside the window can only ever get through once.
+*/
+struct ReplayWindow {
+    highest: u64,
+    seen: u64,
+    newest_timestamp: u64, // Of anything we've accepted from this sender, for picking who to evict
+}
+
+impl ReplayWindow {
+    fn new(sequence: u64, timestamp: u64) -> Self {
+        ReplayWindow {
+            highest: sequence,
+            seen: 1,
+            newest_timestamp: timestamp,
+        }
+    }
+
+    fn check(&mut self, sequence: u64) -> Result<Delivery, ReplayError> {
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = sequence;
+            return Ok(Delivery::InOrder);
+        }
+
+        let offset = self.highest - sequence;
+        if offset >= REPLAY_WINDOW_SIZE {
+            return Err(ReplayError::OutsideWindow(sequence));
+        }
+        if (self.seen >> offset) & 1 == 1 {
+            return Err(ReplayError::Duplicate(sequence));
+        }
+        self.seen |= 1 << offset;
+        Ok(Delivery::Reordered)
+    }
+}
+
+/*
+   One window per sender we have heard from. Messages only get this far once they've decrypted and
+   authenticated, so only someone holding the key can add streams here.
+
+   The window can't say anything about a sender we've never heard from, so its first message could be a replay
+   of one recorded any time under the same key. The authenticated timestamp is what pins a stream to now: anything
+   more than MAX_CLOCK_SKEW_MILLIS off is dropped, from known senders and new ones alike. With a handshake the key
+   itself is only good for this session, which rules out recordings from any other.
+
+   Once a sender gets evicted we'd take its old messages as a brand new stream, so the floor moves up to the
+   newest timestamp it sent and nothing from a sender we don't know is accepted from at or before that.
+*/
+#[derive(Default)]
+pub struct ReplayGuard {
+    windows: HashMap<u64, ReplayWindow>,
+    floor: u64,
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        ReplayGuard {
+            windows: HashMap::new(),
+            floor: 0,
+        }
+    }
+
+    pub fn check(
+        &mut self,
+        stream_id: u64,
+        sequence: u64,
+        timestamp: u64,
+        now: u64,
+    ) -> Result<Delivery, ReplayError> {
+        if timestamp.abs_diff(now) > MAX_CLOCK_SKEW_MILLIS {
+            return Err(ReplayError::Stale(sequence));
+        }
+
+        if let Some(window) = self.windows.get_mut(&stream_id) {
+            let delivery = window.check(sequence)?;
+            window.newest_timestamp = window.newest_timestamp.max(timestamp);
+            return Ok(delivery);
+        }
+
+        if timestamp <= self.floor {
+            return Err(ReplayError::Stale(sequence));
+        }
+        if self.windows.len() >= MAX_TRACKED_STREAMS {
+            self.evict();
+        }
+        self.windows
+            .insert(stream_id, ReplayWindow::new(sequence, timestamp));
+        Ok(Delivery::InOrder)
+    }
+
+    /// Drops whoever we've heard from least recently
+    fn evict(&mut self) {
+        let oldest = self
+            .windows
+            .iter()
+            .min_by_key(|(_, window)| window.newest_timestamp)
+            .map(|(stream_id, window)| (*stream_id, window.newest_timestamp));
+        if let Some((stream_id, newest_timestamp)) = oldest {
+            self.windows.remove(&stream_id);
+            self.floor = self.floor.max(newest_timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut guard = ReplayGuard::new();
+        assert_eq!(guard.check(1, 0, NOW, NOW), Ok(Delivery::InOrder));
+        assert_eq!(guard.check(1, 0, NOW, NOW), Err(ReplayError::Duplicate(0)));
+        assert_eq!(guard.check(1, 2, NOW, NOW), Ok(Delivery::InOrder));
+        assert_eq!(guard.check(1, 1, NOW, NOW), Ok(Delivery::Reordered));
+        assert_eq!(guard.check(1, 1, NOW, NOW), Err(ReplayError::Duplicate(1)));
+        assert_eq!(guard.check(1, 2, NOW, NOW), Err(ReplayError::Duplicate(2)));
+
+        // Every sender gets a window of its own
+        assert_eq!(guard.check(2, 0, NOW, NOW), Ok(Delivery::InOrder));
+    }
+
+    #[test]
+    fn window_slides_forward() {
+        let mut guard = ReplayGuard::new();
+        guard.check(1, 10, NOW, NOW).unwrap();
+        guard
+            .check(1, 10 + REPLAY_WINDOW_SIZE - 1, NOW, NOW)
+            .unwrap();
+
+        // 10 is still just inside and already seen, 11 is inside and never seen
+        assert_eq!(
+            guard.check(1, 10, NOW, NOW),
+            Err(ReplayError::Duplicate(10))
+        );
+        assert_eq!(guard.check(1, 11, NOW, NOW), Ok(Delivery::Reordered));
+
+        // Jumping a whole window ahead forgets everything behind it
+        let far = 10 + 3 * REPLAY_WINDOW_SIZE;
+        assert_eq!(guard.check(1, far, NOW, NOW), Ok(Delivery::InOrder));
+        assert_eq!(
+            guard.check(1, far - REPLAY_WINDOW_SIZE, NOW, NOW),
+            Err(ReplayError::OutsideWindow(far - REPLAY_WINDOW_SIZE))
+        );
+        assert_eq!(
+            guard.check(1, far - REPLAY_WINDOW_SIZE + 1, NOW, NOW),
+            Ok(Delivery::Reordered)
+        );
+    }
+
+    #[test]
+    fn unknown_streams_need_a_fresh_timestamp() {
+        let mut guard = ReplayGuard::new();
+        let old = NOW - MAX_CLOCK_SKEW_MILLIS - 1;
+        assert_eq!(guard.check(1, 5, old, NOW), Err(ReplayError::Stale(5)));
+        assert_eq!(
+            guard.check(1, 5, NOW + MAX_CLOCK_SKEW_MILLIS + 1, NOW),
+            Err(ReplayError::Stale(5))
+        );
+        assert_eq!(
+            guard.check(1, 5, NOW - MAX_CLOCK_SKEW_MILLIS, NOW),
+            Ok(Delivery::InOrder)
+        );
+
+        // Known senders don't get a pass either
+        assert_eq!(guard.check(1, 6, old, NOW), Err(ReplayError::Stale(6)));
+        assert_eq!(guard.check(1, 6, NOW, NOW), Ok(Delivery::InOrder));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_heard_from() {
+        let mut guard = ReplayGuard::new();
+        for stream_id in 0..MAX_TRACKED_STREAMS as u64 {
+            guard.check(stream_id, 0, NOW + stream_id, NOW).unwrap();
+        }
+        // Stream 0 is heard from again so stream 1 is now the quietest
+        guard.check(0, 1, NOW + 5000, NOW).unwrap();
+
+        let newcomer = MAX_TRACKED_STREAMS as u64;
+        guard.check(newcomer, 0, NOW + 6000, NOW).unwrap();
+        assert_eq!(guard.windows.len(), MAX_TRACKED_STREAMS);
+        assert!(!guard.windows.contains_key(&1));
+        assert_eq!(guard.check(0, 1, NOW, NOW), Err(ReplayError::Duplicate(1)));
+
+        // Stream 1's old messages don't come back as a new stream
+        assert_eq!(guard.check(1, 0, NOW + 1, NOW), Err(ReplayError::Stale(0)));
+        assert_eq!(guard.check(1, 1, NOW + 7000, NOW), Ok(Delivery::InOrder));
+    }
+}
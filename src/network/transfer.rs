@@ -0,0 +1,504 @@
+use crate::cryptography::sha256::{Sha256, SHA256_DIGEST_LENGTH_BYTES};
+use crate::network::message::{Message, MessageError, MessageKind};
+use rand::RngCore;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/*
+   Each chunk goes out as its own message, small enough that a file doesn't hold up chat for long
+   and well under the frame size limit once encrypted
+*/
+pub const FILE_CHUNK_SIZE_BYTES: usize = 16 * 1024;
+
+/*
+   Files are put back together in memory before we ask what to do with them, so there has to be a cap
+   on how much someone in the room can make us hold on to
+*/
+pub const MAX_FILE_SIZE_BYTES: u64 = 64 * 1024 * 1024;
+
+/*
+   How many files we'll collect at once across the whole room. Memory only gets used as chunks actually
+   arrive, this stops anyone opening transfers they never finish until we run out of room to track them.
+*/
+pub const MAX_OPEN_TRANSFERS: usize = 16;
+
+/*
+   Finished files sit in memory until the user saves or discards them. Anyone who ignores them shouldn't be
+   able to be buried in them, so past either of these anything new is refused until some get cleared out.
+*/
+pub const MAX_RECEIVED_FILES: usize = 16;
+pub const MAX_RECEIVED_BYTES: u64 = 2 * MAX_FILE_SIZE_BYTES;
+
+const TRANSFER_ID_LENGTH_BYTES: usize = 8;
+
+/*
+   Everything the receiver needs to know up front. On the wire it is the transfer id, size (both big endian u64),
+   the SHA-256 of the whole file, then the file name as UTF-8 taking up the rest of the body.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileHeader {
+    pub transfer_id: u64,
+    pub size: u64,
+    pub sha256: [u8; SHA256_DIGEST_LENGTH_BYTES],
+    pub name: String,
+}
+
+impl FileHeader {
+    /*
+       Reads through the file once to hash it, the chunks themselves get read again as they are sent.
+       The size gets checked before any of that so we don't spend ages hashing something we'd refuse anyway.
+    */
+    pub fn for_path(path: &Path) -> io::Result<FileHeader> {
+        let name = match path.file_name().and_then(|x| x.to_str()) {
+            Some(x) => x.to_string(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "path does not name a file",
+                ))
+            }
+        };
+
+        let too_large = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("file is larger than {} bytes", MAX_FILE_SIZE_BYTES),
+            )
+        };
+        let file = File::open(path)?;
+        if file.metadata()?.len() > MAX_FILE_SIZE_BYTES {
+            return Err(too_large());
+        }
+
+        // The file can still grow while we read it, never read more than one byte past the limit
+        let mut file = file.take(MAX_FILE_SIZE_BYTES + 1);
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let mut buffer = vec![0u8; FILE_CHUNK_SIZE_BYTES];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+        }
+
+        if size > MAX_FILE_SIZE_BYTES {
+            return Err(too_large());
+        }
+
+        Ok(FileHeader {
+            transfer_id: rand::rng().next_u64(),
+            size,
+            sha256: hasher.finalize(),
+            name,
+        })
+    }
+
+    pub fn to_message(&self) -> Message {
+        let mut body = Vec::new();
+        body.extend_from_slice(&self.transfer_id.to_be_bytes());
+        body.extend_from_slice(&self.size.to_be_bytes());
+        body.extend_from_slice(&self.sha256);
+        body.extend_from_slice(self.name.as_bytes());
+        Message::new(MessageKind::FileStart, body)
+    }
+
+    pub fn from_message(message: &Message) -> Result<FileHeader, MessageError> {
+        let malformed = MessageError::MalformedBody(MessageKind::FileStart);
+        let fixed_length = 2 * TRANSFER_ID_LENGTH_BYTES + SHA256_DIGEST_LENGTH_BYTES;
+        if message.kind != MessageKind::FileStart || message.body.len() <= fixed_length {
+            return Err(malformed);
+        }
+
+        let (transfer_id, rest) = message.body.split_at(TRANSFER_ID_LENGTH_BYTES);
+        let (size, rest) = rest.split_at(TRANSFER_ID_LENGTH_BYTES);
+        let (sha256, name) = rest.split_at(SHA256_DIGEST_LENGTH_BYTES);
+        let name = match std::str::from_utf8(name) {
+            Ok(x) => x.to_string(),
+            Err(_) => return Err(malformed),
+        };
+
+        Ok(FileHeader {
+            transfer_id: u64::from_be_bytes(transfer_id.try_into().unwrap()),
+            size: u64::from_be_bytes(size.try_into().unwrap()),
+            sha256: sha256.try_into().unwrap(),
+            name,
+        })
+    }
+}
+
+pub fn chunk_message(transfer_id: u64, data: &[u8]) -> Message {
+    let mut body = Vec::with_capacity(TRANSFER_ID_LENGTH_BYTES + data.len());
+    body.extend_from_slice(&transfer_id.to_be_bytes());
+    body.extend_from_slice(data);
+    Message::new(MessageKind::FileChunk, body)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransferError {
+    /// A chunk for a transfer we never saw the header for (or already finished)
+    UnknownTransfer(u64),
+    /// The header announces a file bigger than MAX_FILE_SIZE_BYTES
+    TooLarge(u64),
+    /// More data arrived than the header said the file would have
+    Overrun(String),
+    /// Everything arrived but it doesn't hash to what the sender said it would
+    HashMismatch(String),
+    /// The name would put the file somewhere other than where the user asked
+    UnsafeName(String),
+    /// Already collecting MAX_OPEN_TRANSFERS files
+    TooManyTransfers,
+    /// Holding on to as many unsaved files (or bytes of them) as we're allowed, holds the file's name
+    ReceivedQueueFull(String),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::UnknownTransfer(id) => {
+                write!(f, "data for unknown transfer {:016x}", id)
+            }
+            TransferError::TooLarge(size) => write!(
+                f,
+                "offered file of {} bytes exceeds the limit of {} bytes",
+                size, MAX_FILE_SIZE_BYTES
+            ),
+            TransferError::Overrun(name) => write!(f, "{} is larger than announced", name),
+            TransferError::HashMismatch(name) => {
+                write!(f, "{} does not match the SHA-256 it was sent with", name)
+            }
+            TransferError::UnsafeName(name) => {
+                write!(f, "refusing file with unsafe name {:?}", name)
+            }
+            TransferError::TooManyTransfers => write!(
+                f,
+                "already receiving the maximum of {} files",
+                MAX_OPEN_TRANSFERS
+            ),
+            TransferError::ReceivedQueueFull(name) => write!(
+                f,
+                "no room for {}, save or discard some of the files waiting first (limit is {} files or {} bytes)",
+                name, MAX_RECEIVED_FILES, MAX_RECEIVED_BYTES
+            ),
+        }
+    }
+}
+
+/// A complete file that passed its hash check, waiting for the user to decide where it goes
+pub struct ReceivedFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl ReceivedFile {
+    /// Writes the file out, refusing to overwrite anything that is already there
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(&self.data)
+    }
+}
+
+/*
+   Finished files waiting on the user, oldest first. Keeps count of how many bytes are in there so the
+   limits can be checked without adding everything up each time.
+*/
+#[derive(Default)]
+pub struct ReceivedFiles {
+    files: VecDeque<ReceivedFile>,
+    bytes: u64,
+}
+
+impl ReceivedFiles {
+    pub fn new() -> Self {
+        ReceivedFiles {
+            files: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Whether a file of this size would still fit, checked up front so we don't collect one only to refuse it
+    pub fn has_room(&self, size: u64) -> bool {
+        self.files.len() < MAX_RECEIVED_FILES && self.bytes + size <= MAX_RECEIVED_BYTES
+    }
+
+    pub fn push_back(&mut self, file: ReceivedFile) -> Result<(), TransferError> {
+        if !self.has_room(file.data.len() as u64) {
+            return Err(TransferError::ReceivedQueueFull(file.name));
+        }
+        self.bytes += file.data.len() as u64;
+        self.files.push_back(file);
+        Ok(())
+    }
+
+    /// Puts back a file that was just taken off the front, it already had its place so there's no limit check
+    pub fn push_front(&mut self, file: ReceivedFile) {
+        self.bytes += file.data.len() as u64;
+        self.files.push_front(file);
+    }
+
+    pub fn pop_front(&mut self) -> Option<ReceivedFile> {
+        let file = self.files.pop_front()?;
+        self.bytes -= file.data.len() as u64;
+        Some(file)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+struct IncomingFile {
+    header: FileHeader,
+    data: Vec<u8>,
+}
+
+/*
+   Transfers in progress, keyed by the sender's stream id as well as the transfer id so two people
+   can't trip over each other's transfers
+*/
+#[derive(Default)]
+pub struct IncomingTransfers {
+    transfers: HashMap<(u64, u64), IncomingFile>,
+}
+
+impl IncomingTransfers {
+    pub fn new() -> Self {
+        IncomingTransfers {
+            transfers: HashMap::new(),
+        }
+    }
+
+    /// Starts collecting a new file, hands back the header so the caller can tell the user what's coming
+    pub fn start(
+        &mut self,
+        stream_id: u64,
+        header: FileHeader,
+    ) -> Result<FileHeader, TransferError> {
+        /*
+           The name is only ever used as a suggestion for where to save, but it still can't be allowed
+           to climb out of the directory it gets saved in
+        */
+        let safe_name = Path::new(&header.name).file_name().and_then(|x| x.to_str());
+        if safe_name != Some(header.name.as_str()) {
+            return Err(TransferError::UnsafeName(header.name));
+        }
+        if header.size > MAX_FILE_SIZE_BYTES {
+            return Err(TransferError::TooLarge(header.size));
+        }
+        let key = (stream_id, header.transfer_id);
+        if self.transfers.len() >= MAX_OPEN_TRANSFERS && !self.transfers.contains_key(&key) {
+            return Err(TransferError::TooManyTransfers);
+        }
+
+        // The size is only the sender's word, the buffer grows as the data actually turns up
+        let file = IncomingFile {
+            header: header.clone(),
+            data: Vec::new(),
+        };
+        self.transfers.insert(key, file);
+        Ok(header)
+    }
+
+    /*
+       Adds the next chunk, once the last one is in the hash gets checked and the finished file comes back.
+       A transfer that goes wrong is dropped so it can't keep eating memory.
+    */
+    pub fn chunk(
+        &mut self,
+        stream_id: u64,
+        message: &Message,
+    ) -> Result<Option<ReceivedFile>, TransferError> {
+        if message.body.len() < TRANSFER_ID_LENGTH_BYTES {
+            return Err(TransferError::UnknownTransfer(0));
+        }
+        let (transfer_id, data) = message.body.split_at(TRANSFER_ID_LENGTH_BYTES);
+        let transfer_id = u64::from_be_bytes(transfer_id.try_into().unwrap());
+        let key = (stream_id, transfer_id);
+
+        let file = match self.transfers.get_mut(&key) {
+            Some(x) => x,
+            None => return Err(TransferError::UnknownTransfer(transfer_id)),
+        };
+        if (file.data.len() + data.len()) as u64 > file.header.size {
+            let file = self.transfers.remove(&key).unwrap();
+            return Err(TransferError::Overrun(file.header.name));
+        }
+        file.data.extend_from_slice(data);
+        if (file.data.len() as u64) < file.header.size {
+            return Ok(None);
+        }
+
+        let file = self.transfers.remove(&key).unwrap();
+        let mut hasher = Sha256::new();
+        hasher.update(&file.data);
+        if hasher.finalize() != file.header.sha256 {
+            return Err(TransferError::HashMismatch(file.header.name));
+        }
+
+        Ok(Some(ReceivedFile {
+            name: file.header.name,
+            data: file.data,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, data: &[u8]) -> FileHeader {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        FileHeader {
+            transfer_id: 42,
+            size: data.len() as u64,
+            sha256: hasher.finalize(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn reassembles_chunks_and_checks_the_hash() {
+        let data: Vec<u8> = (0..40_000).map(|i| (i % 251) as u8).collect();
+        let header = header("notes.txt", &data);
+        let decoded = FileHeader::from_message(&header.to_message()).unwrap();
+        assert_eq!(decoded, header);
+
+        let mut transfers = IncomingTransfers::new();
+        transfers.start(1, decoded).unwrap();
+        let mut received = None;
+        for chunk in data.chunks(FILE_CHUNK_SIZE_BYTES) {
+            assert!(received.is_none());
+            received = transfers.chunk(1, &chunk_message(42, chunk)).unwrap();
+        }
+        assert_eq!(received.unwrap().data, data);
+
+        // Finished transfers are forgotten
+        assert_eq!(
+            transfers.chunk(1, &chunk_message(42, b"x")).err(),
+            Some(TransferError::UnknownTransfer(42))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_transfers() {
+        let mut transfers = IncomingTransfers::new();
+        for name in ["../evil", "/etc/passwd", "a/b", ".."] {
+            let result = transfers.start(1, header(name, b"data"));
+            assert_eq!(result, Err(TransferError::UnsafeName(name.to_string())));
+        }
+
+        let mut too_large = header("big", b"");
+        too_large.size = MAX_FILE_SIZE_BYTES + 1;
+        assert!(matches!(
+            transfers.start(1, too_large),
+            Err(TransferError::TooLarge(_))
+        ));
+
+        transfers.start(1, header("file", b"data")).unwrap();
+        assert!(matches!(
+            transfers.chunk(1, &chunk_message(42, b"dada")),
+            Err(TransferError::HashMismatch(_))
+        ));
+
+        transfers.start(1, header("file", b"data")).unwrap();
+        assert!(matches!(
+            transfers.chunk(1, &chunk_message(42, b"data!")),
+            Err(TransferError::Overrun(_))
+        ));
+
+        // Another sender can't feed chunks into our transfer
+        transfers.start(1, header("file", b"data")).unwrap();
+        assert_eq!(
+            transfers.chunk(2, &chunk_message(42, b"data")).err(),
+            Some(TransferError::UnknownTransfer(42))
+        );
+    }
+
+    #[test]
+    fn limits_open_transfers() {
+        let mut transfers = IncomingTransfers::new();
+        let mut huge = header("huge", b"");
+        huge.size = MAX_FILE_SIZE_BYTES;
+        for stream_id in 0..MAX_OPEN_TRANSFERS as u64 {
+            transfers.start(stream_id, huge.clone()).unwrap();
+        }
+        // Announcing a file doesn't cost its size up front
+        assert!(transfers.transfers.values().all(|x| x.data.capacity() == 0));
+
+        assert_eq!(
+            transfers.start(99, header("one more", b"")),
+            Err(TransferError::TooManyTransfers)
+        );
+        // Starting over on a transfer that's already open doesn't need another slot
+        assert!(transfers.start(0, huge.clone()).is_ok());
+
+        // Finishing one frees its slot up
+        transfers.start(0, header("small", b"data")).unwrap();
+        assert!(transfers
+            .chunk(0, &chunk_message(42, b"data"))
+            .unwrap()
+            .is_some());
+        assert!(transfers.start(99, header("one more", b"")).is_ok());
+    }
+
+    #[test]
+    fn limits_unsaved_files() {
+        let file = |name: &str, size: usize| ReceivedFile {
+            name: name.to_string(),
+            data: vec![0; size],
+        };
+
+        let mut received = ReceivedFiles::new();
+        for i in 0..MAX_RECEIVED_FILES {
+            received.push_back(file(&i.to_string(), 1)).unwrap();
+        }
+        assert!(!received.has_room(0));
+        assert_eq!(
+            received.push_back(file("one more", 1)),
+            Err(TransferError::ReceivedQueueFull("one more".to_string()))
+        );
+        assert_eq!(received.len(), MAX_RECEIVED_FILES);
+
+        // Saving one makes room again, and what's left comes out oldest first
+        assert_eq!(received.pop_front().unwrap().name, "0");
+        received.push_back(file("one more", 1)).unwrap();
+        assert_eq!(received.pop_front().unwrap().name, "1");
+
+        // The byte limit counts too, even with file slots free
+        let mut received = ReceivedFiles::new();
+        let half = (MAX_RECEIVED_BYTES / 2) as usize;
+        received.push_back(file("a", half)).unwrap();
+        received.push_back(file("b", half)).unwrap();
+        assert!(!received.has_room(1));
+        assert!(received.push_back(file("c", 1)).is_err());
+
+        // A file taken off and put back keeps its place without going through the limits
+        let first = received.pop_front().unwrap();
+        received.push_back(file("c", 1)).unwrap();
+        received.push_front(first);
+        assert_eq!(received.len(), 3);
+        assert_eq!(received.pop_front().unwrap().name, "a");
+        assert!(!received.is_empty());
+    }
+
+    #[test]
+    fn refuses_to_hash_an_oversized_file() {
+        let path = std::env::temp_dir().join(format!("kryptos-big-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        file.set_len(MAX_FILE_SIZE_BYTES + 1).unwrap();
+        let error = FileHeader::for_path(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
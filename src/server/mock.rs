@@ -0,0 +1,186 @@
+use crate::cryptography::cryptography::{CryptoError, EncryptionContext};
+use crate::network::framing::{read_frame, write_frame};
+use crate::network::message::Message;
+use std::io;
+use std::io::Write;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Clients = Arc<Mutex<Vec<(usize, TcpStream)>>>;
+
+/*
+   A stand in for the Kryptos server, good enough to point the client at in tests. Like the real one it never
+   sees a key, every frame a client sends gets relayed as is to every client connected (the sender included,
+   that's how the client sees its own messages come back). Anything it says itself it has to be handed a
+   context for.
+*/
+pub struct MockServer {
+    address: SocketAddr,
+    clients: Clients,
+    relayed: Arc<AtomicUsize>,
+    closing: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    /// Port 0 picks a free one, address says which it got
+    pub fn start(address: &str) -> io::Result<MockServer> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let clients: Clients = Arc::new(Mutex::new(Vec::new()));
+        let relayed = Arc::new(AtomicUsize::new(0));
+        let closing = Arc::new(AtomicBool::new(false));
+
+        let accept = {
+            let clients = clients.clone();
+            let relayed = relayed.clone();
+            let closing = closing.clone();
+            spawn(move || accept_routine(listener, clients, relayed, closing))
+        };
+
+        Ok(MockServer {
+            address,
+            clients,
+            relayed,
+            closing,
+            listener: Some(accept),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn port(&self) -> u16 {
+        self.address.port()
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// How many frames from clients have gone back out so far
+    pub fn frames_relayed(&self) -> usize {
+        self.relayed.load(Ordering::SeqCst)
+    }
+
+    /*
+       The client's connect returns as soon as the socket is open, which can be before we have got round to
+       accepting it. False if there still aren't count clients by the timeout.
+    */
+    pub fn wait_for_clients(&self, count: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.client_count() != count {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// Hangs up on everyone, as if the server went away
+    pub fn disconnect_all(&self) {
+        for (_, stream) in self.clients.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Sends payload to every client as a frame of its own
+    pub fn send_frame(&self, payload: &[u8]) {
+        broadcast(&self.clients, payload);
+    }
+
+    /// Writes bytes straight onto every connection with no framing, for making a mess of the stream on purpose
+    pub fn send_raw(&self, bytes: &[u8]) {
+        for (_, stream) in self.clients.lock().unwrap().iter_mut() {
+            let _ = stream.write_all(bytes);
+        }
+    }
+
+    /*
+       Says something to the room under whatever key the context holds. The message goes out with the stream id
+       and sequence number it already has, the clients check those like they would for anyone else.
+    */
+    pub fn send_message(
+        &self,
+        context: &mut EncryptionContext,
+        message: &Message,
+    ) -> Result<(), CryptoError> {
+        let mut encrypted_buffer = Vec::new();
+        context
+            .context
+            .encrypt(&mut message.encode(), &mut encrypted_buffer)?;
+        self.send_frame(&encrypted_buffer);
+        Ok(())
+    }
+
+    /*
+       The accept thread is sitting in accept, connecting to ourselves is the simplest way to get it out
+    */
+    pub fn stop(&mut self) {
+        self.closing.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(self.address);
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+        self.disconnect_all();
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn accept_routine(
+    listener: TcpListener,
+    clients: Clients,
+    relayed: Arc<AtomicUsize>,
+    closing: Arc<AtomicBool>,
+) {
+    let mut next_id = 0;
+    for stream in listener.incoming() {
+        if closing.load(Ordering::SeqCst) {
+            return;
+        }
+        let stream = match stream {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let write_stream = match stream.try_clone() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+
+        let id = next_id;
+        next_id += 1;
+        clients.lock().unwrap().push((id, write_stream));
+
+        let clients = clients.clone();
+        let relayed = relayed.clone();
+        spawn(move || client_routine(id, stream, clients, relayed));
+    }
+}
+
+/*
+   One of these per client, it lives until the client hangs up or sends something that isn't a frame
+*/
+fn client_routine(id: usize, mut stream: TcpStream, clients: Clients, relayed: Arc<AtomicUsize>) {
+    while let Ok(Some(frame)) = read_frame(&mut stream) {
+        relayed.fetch_add(1, Ordering::SeqCst);
+        broadcast(&clients, &frame);
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    clients.lock().unwrap().retain(|(x, _)| *x != id);
+}
+
+fn broadcast(clients: &Clients, payload: &[u8]) {
+    for (_, stream) in clients.lock().unwrap().iter_mut() {
+        let _ = write_frame(stream, payload);
+    }
+}
@@ -0,0 +1,52 @@
+use crate::arg_handling::arg_handling::arg_handling::{EncryptionInfo, KeySize};
+use crate::cryptography::cryptography::{CryptoError, EncryptionContext};
+use crate::network::transfer::FILE_CHUNK_SIZE_BYTES;
+use rand::RngCore;
+use std::time::Instant;
+
+/*
+   Throughput in bytes a second each way
+*/
+pub struct BenchResult {
+    pub encrypt: f64,
+    pub decrypt: f64,
+}
+
+/*
+   Pushes bytes through the cipher a message at a time, each message the size of a file chunk since that is
+   the biggest thing the client ever encrypts in one go. Decrypting goes over the same message again and again,
+   it costs the same as decrypting different ones.
+*/
+pub fn bench(
+    enc_type: EncryptionInfo,
+    key_size: KeySize,
+    bytes: usize,
+) -> Result<BenchResult, CryptoError> {
+    let mut key = vec![0u8; key_size.bytes()];
+    rand::rng().fill_bytes(&mut key);
+    let mut context = EncryptionContext::create(enc_type, key_size, &key, 0)?;
+
+    let mut message = vec![0u8; FILE_CHUNK_SIZE_BYTES];
+    rand::rng().fill_bytes(&mut message);
+    let messages = bytes.div_ceil(FILE_CHUNK_SIZE_BYTES);
+    let total = (messages * FILE_CHUNK_SIZE_BYTES) as f64;
+
+    let mut ciphertext = Vec::new();
+    let started = Instant::now();
+    for _ in 0..messages {
+        ciphertext.clear();
+        context
+            .context
+            .encrypt(&mut message.clone(), &mut ciphertext)?;
+    }
+    let encrypt = total / started.elapsed().as_secs_f64();
+
+    let started = Instant::now();
+    for _ in 0..messages {
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        context.decrypt(&mut ciphertext.clone(), &mut plaintext)?;
+    }
+    let decrypt = total / started.elapsed().as_secs_f64();
+
+    Ok(BenchResult { encrypt, decrypt })
+}
@@ -0,0 +1,75 @@
+use crate::arg_handling::arg_handling::arg_handling::FileConfig;
+use crate::cryptography::streaming::{
+    create_stream, DecryptingReader, EncryptingWriter, StreamingEncryption,
+};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/*
+   encrypt and decrypt push the whole input through the streaming adapters so it never has to fit in memory.
+   What comes out of encrypt is the cipher's own stream format (IV, ciphertext, tag) with nothing added, the
+   same cipher, key size and passphrase are all it takes to get it back.
+*/
+
+/// Returns how many bytes of plaintext went in
+pub fn encrypt(config: &FileConfig) -> io::Result<u64> {
+    run(config, |cipher, input, output| {
+        let mut writer = EncryptingWriter::new(output, cipher)?;
+        let copied = io::copy(input, &mut writer)?;
+        writer.finish()?;
+        Ok(copied)
+    })
+}
+
+/*
+   Plaintext comes out before the tag at the end has been checked. Into a file that's fine, a failure removes
+   the file again, but anything reading from stdout has to wait for a zero exit before trusting it.
+*/
+/// Returns how many bytes of plaintext came out
+pub fn decrypt(config: &FileConfig) -> io::Result<u64> {
+    run(config, |cipher, input, output| {
+        let mut reader = DecryptingReader::new(input, cipher)?;
+        let copied = io::copy(&mut reader, output)?;
+        output.flush()?;
+        Ok(copied)
+    })
+}
+
+type Direction = fn(&mut dyn StreamingEncryption, &mut dyn Read, &mut dyn Write) -> io::Result<u64>;
+
+fn run(config: &FileConfig, direction: Direction) -> io::Result<u64> {
+    let cipher = &config.cipher;
+    let mut stream = create_stream(
+        cipher.enc_type,
+        cipher.key_size,
+        &cipher.derive_key(),
+        cipher.rc4_drop,
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut input: Box<dyn Read> = match &config.input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
+    };
+
+    /*
+       Never overwrites anything, which also means a file we have to clean up after a failure is always ours
+    */
+    let mut output: Box<dyn Write> = match &config.output {
+        Some(path) => Box::new(BufWriter::new(
+            OpenOptions::new().write(true).create_new(true).open(path)?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    let result = direction(stream.as_mut(), input.as_mut(), output.as_mut());
+    drop(output);
+    if result.is_err() {
+        if let Some(path) = &config.output {
+            let _ = fs::remove_file(path);
+        }
+    }
+    result
+}
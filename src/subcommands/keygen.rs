@@ -0,0 +1,13 @@
+use crate::arg_handling::arg_handling::arg_handling::KeySize;
+use crate::cryptography::encoding::hex_encode;
+use rand::RngCore;
+
+/*
+   A fresh random key of the given size written out as hex behind a hex: prefix, so it can go straight into a
+   key file or KRYPTOS_KEY and be used as the key itself rather than as a passphrase
+*/
+pub fn generate_key(key_size: KeySize) -> String {
+    let mut key = vec![0u8; key_size.bytes()];
+    rand::rng().fill_bytes(&mut key);
+    format!("hex:{}", hex_encode(&key))
+}
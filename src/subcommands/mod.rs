@@ -0,0 +1,3 @@
+pub mod bench;
+pub mod crypt;
+pub mod keygen;
@@ -0,0 +1,260 @@
+/*
+   Keys as far as the input line cares, decoded from the raw bytes a terminal sends in raw mode
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    ClearLine,  // Ctrl-U
+    Redraw,     // Ctrl-L
+    Interrupt,  // Ctrl-C
+    EndOfInput, // Ctrl-D
+}
+
+/*
+   Keys don't always arrive as a single byte, arrows and friends are escape sequences and anything outside
+   ASCII is a multi byte UTF-8 character, so bytes are collected here until they make up a whole key
+*/
+#[derive(Default)]
+pub struct KeyDecoder {
+    pending: Vec<u8>,
+}
+
+/*
+   Escape sequences are never longer than this, anything that gets this far without ending is junk
+*/
+const MAX_ESCAPE_SEQUENCE_LENGTH: usize = 8;
+
+impl KeyDecoder {
+    pub fn new() -> Self {
+        KeyDecoder {
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<Key> {
+        if self.pending.is_empty() {
+            return match byte {
+                0x1b | 0x80..=0xff => {
+                    self.pending.push(byte);
+                    None
+                }
+                b'\r' | b'\n' => Some(Key::Enter),
+                0x7f | 0x08 => Some(Key::Backspace),
+                0x01 => Some(Key::Home),
+                0x05 => Some(Key::End),
+                0x15 => Some(Key::ClearLine),
+                0x0c => Some(Key::Redraw),
+                0x03 => Some(Key::Interrupt),
+                0x04 => Some(Key::EndOfInput),
+                0x00..=0x1f => None,
+                _ => Some(Key::Char(byte as char)),
+            };
+        }
+
+        self.pending.push(byte);
+        if self.pending[0] == 0x1b {
+            return self.escape_sequence();
+        }
+        self.utf8_character()
+    }
+
+    fn escape_sequence(&mut self) -> Option<Key> {
+        let sequence = &self.pending[1..];
+        if sequence[0] != b'[' && sequence[0] != b'O' {
+            self.pending.clear();
+            return None;
+        }
+        let last = sequence[sequence.len() - 1];
+        if sequence.len() < 2 || !(0x40..=0x7e).contains(&last) {
+            if self.pending.len() >= MAX_ESCAPE_SEQUENCE_LENGTH {
+                self.pending.clear();
+            }
+            return None;
+        }
+
+        let key = match &sequence[1..] {
+            b"A" => Some(Key::Up),
+            b"B" => Some(Key::Down),
+            b"C" => Some(Key::Right),
+            b"D" => Some(Key::Left),
+            b"H" | b"1~" | b"7~" => Some(Key::Home),
+            b"F" | b"4~" | b"8~" => Some(Key::End),
+            b"3~" => Some(Key::Delete),
+            b"5~" => Some(Key::PageUp),
+            b"6~" => Some(Key::PageDown),
+            _ => None,
+        };
+        self.pending.clear();
+        key
+    }
+
+    fn utf8_character(&mut self) -> Option<Key> {
+        let expected = match self.pending[0] {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 0,
+        };
+        let last = self.pending[self.pending.len() - 1];
+        if expected == 0 {
+            self.pending.clear();
+            return None;
+        }
+        if self.pending.len() > 1 && last & 0xc0 != 0x80 {
+            // The character was cut short, the byte that cut it off is still a key of its own
+            self.pending.clear();
+            return self.push(last);
+        }
+        if self.pending.len() < expected {
+            return None;
+        }
+
+        let key = std::str::from_utf8(&self.pending)
+            .ok()
+            .and_then(|x| x.chars().next())
+            .map(Key::Char);
+        self.pending.clear();
+        key
+    }
+}
+
+/*
+   The line being typed, kept as chars so the cursor always sits on a character boundary
+*/
+#[derive(Default)]
+pub struct LineEditor {
+    buffer: Vec<char>,
+    cursor: usize,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor {
+            buffer: Vec::new(),
+            cursor: 0,
+        }
+    }
+
+    pub fn insert(&mut self, character: char) {
+        self.buffer.insert(self.cursor, character);
+        self.cursor += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.buffer.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.buffer.len() {
+            self.buffer.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.buffer.len());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.buffer.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.cursor = 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Hands back the finished line and starts a fresh one
+    pub fn take(&mut self) -> String {
+        let line = self.buffer.iter().collect();
+        self.clear();
+        line
+    }
+
+    pub fn chars(&self) -> &[char] {
+        &self.buffer
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<Key> {
+        let mut decoder = KeyDecoder::new();
+        bytes.iter().filter_map(|&x| decoder.push(x)).collect()
+    }
+
+    #[test]
+    fn decodes_keys() {
+        assert_eq!(
+            decode(b"a\x1b[D\x1b[3~\x1bOH\x1b[5~\r\x7f\x03"),
+            vec![
+                Key::Char('a'),
+                Key::Left,
+                Key::Delete,
+                Key::Home,
+                Key::PageUp,
+                Key::Enter,
+                Key::Backspace,
+                Key::Interrupt
+            ]
+        );
+        assert_eq!(
+            decode("é日🙂".as_bytes()),
+            vec![Key::Char('é'), Key::Char('日'), Key::Char('🙂')]
+        );
+        // Broken UTF-8 and unknown sequences are dropped without eating the keys after them
+        assert_eq!(
+            decode(b"\xe6x\x1b[99Zy"),
+            vec![Key::Char('x'), Key::Char('y')]
+        );
+    }
+
+    #[test]
+    fn edits_lines() {
+        let mut editor = LineEditor::new();
+        for character in "helo".chars() {
+            editor.insert(character);
+        }
+        editor.left();
+        editor.insert('l');
+        editor.home();
+        editor.delete();
+        editor.insert('H');
+        editor.end();
+        editor.backspace();
+        assert_eq!(editor.cursor(), 4);
+        assert_eq!(editor.take(), "Hell");
+        assert!(editor.is_empty());
+    }
+}
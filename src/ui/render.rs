@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+/*
+   Turns message bodies into text, one UTF-8 character at a time even when a character is split across
+   messages. Bytes that can never be valid UTF-8 come out as \xNN so a broken or malicious message is
+   obvious rather than silently mangled.
+*/
+#[derive(Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>, // The start of a character the next message should finish
+}
+
+impl Utf8Decoder {
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(bytes);
+
+        let mut text = String::with_capacity(input.len());
+        let mut rest = input.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(x) => {
+                    text.push_str(x);
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(length) => {
+                            for byte in &invalid[..length] {
+                                text.push_str(&format!("\\x{:02x}", byte));
+                            }
+                            rest = &invalid[length..];
+                        }
+                        /*
+                           Cut off at the end rather than invalid, hold on to it in case the rest is on its way
+                        */
+                        None => {
+                            self.pending = invalid.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        text
+    }
+}
+
+/*
+   One decoder per sender, so a character one peer left half finished can't get glued onto another peer's message
+*/
+#[derive(Default)]
+pub struct MessageDecoder {
+    streams: HashMap<u64, Utf8Decoder>,
+}
+
+impl MessageDecoder {
+    pub fn new() -> Self {
+        MessageDecoder {
+            streams: HashMap::new(),
+        }
+    }
+
+    pub fn decode(&mut self, stream_id: u64, body: &[u8]) -> String {
+        self.streams.entry(stream_id).or_default().decode(body)
+    }
+}
+
+/*
+   Anything from the room goes through here before it reaches the terminal. Control characters would
+   otherwise let a peer move the cursor, rewrite what's on screen or worse, so they are shown in caret
+   notation (^[ for escape) and the C1 controls as \u{..}. Tabs become a single space to keep the layout.
+*/
+pub fn escape_controls(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '\t' => escaped.push(' '),
+            '\u{0}'..='\u{1f}' => {
+                escaped.push('^');
+                escaped.push((character as u8 + 0x40) as char);
+            }
+            '\u{7f}' => escaped.push_str("^?"),
+            '\u{80}'..='\u{9f}' => escaped.push_str(&format!("\\u{{{:x}}}", character as u32)),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/*
+   How many terminal columns a character takes up. Not the full Unicode tables, just enough that CJK and emoji
+   count as two and combining marks as zero so wrapping and the cursor line up on most terminals.
+*/
+pub fn char_width(character: char) -> usize {
+    match character as u32 {
+        0x0300..=0x036f | 0x200b..=0x200f | 0x20d0..=0x20ff | 0xfe00..=0xfe0f => 0,
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_characters_split_across_messages() {
+        let text = "héllo 日本 🙂";
+        let bytes = text.as_bytes();
+        for split in 0..=bytes.len() {
+            let mut decoder = Utf8Decoder::default();
+            let mut decoded = decoder.decode(&bytes[..split]);
+            decoded.push_str(&decoder.decode(&bytes[split..]));
+            assert_eq!(decoded, text, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn shows_invalid_bytes() {
+        let mut decoder = Utf8Decoder::default();
+        assert_eq!(decoder.decode(b"a\xffb\xc3("), "a\\xffb\\xc3(");
+        // A character that never gets finished shows up once the next message proves it won't be
+        assert_eq!(decoder.decode(b"x\xe6\x97"), "x");
+        assert_eq!(decoder.decode(b"y"), "\\xe6\\x97y");
+    }
+
+    #[test]
+    fn keeps_senders_apart() {
+        let mut decoder = MessageDecoder::new();
+        assert_eq!(decoder.decode(1, b"\xc3"), "");
+        assert_eq!(decoder.decode(2, b"\xa9"), "\\xa9");
+        assert_eq!(decoder.decode(1, b"\xa9"), "é");
+    }
+
+    #[test]
+    fn escapes_terminal_controls() {
+        assert_eq!(
+            escape_controls("\x1b[2Jhi\x07\tthere\u{9b}31m\x7f\n"),
+            "^[[2Jhi^G there\\u{9b}31m^?^J"
+        );
+        assert_eq!(escape_controls("ünïcödé 日本"), "ünïcödé 日本");
+    }
+
+    fn text_width(text: &str) -> usize {
+        text.chars().map(char_width).sum()
+    }
+
+    #[test]
+    fn measures_width() {
+        assert_eq!(text_width("abc"), 3);
+        assert_eq!(text_width("日本"), 4);
+        assert_eq!(text_width("e\u{301}"), 1);
+    }
+}
@@ -0,0 +1,287 @@
+use crate::ui::input::{Key, KeyDecoder, LineEditor};
+use crate::ui::render::{char_width, escape_controls};
+use crate::ui::terminal;
+use crate::ERROR;
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::process::exit;
+use std::sync::Mutex;
+
+/*
+   How many messages the TUI holds on to for scrolling back through, the oldest go first
+*/
+const SCROLLBACK_LINES: usize = 10_000;
+const PROMPT: &str = "> ";
+
+/*
+   Where everything the client shows ends up. Line mode is the plain stdin/stdout client, the TUI keeps
+   incoming messages in their own pane so they never land in the middle of what the user is typing.
+   Both threads share one of these, all drawing happens under the lock so output never interleaves.
+*/
+pub struct Screen {
+    tui: Option<Mutex<TuiState>>,
+}
+
+struct TuiState {
+    lines: VecDeque<String>,
+    scroll: usize, // Rows up from the bottom of the scrollback, 0 follows new messages
+    editor: LineEditor,
+    server: String,
+    cipher: String,
+    connection: String,
+}
+
+impl Screen {
+    pub fn line_mode() -> Screen {
+        Screen { tui: None }
+    }
+
+    /// Takes over the terminal, it gets handed back by close (or by the process exiting)
+    pub fn tui(server: String, cipher: String) -> io::Result<Screen> {
+        terminal::enter_raw_mode()?;
+        let mut state = TuiState {
+            lines: VecDeque::new(),
+            scroll: 0,
+            editor: LineEditor::new(),
+            server,
+            cipher,
+            connection: "connecting".to_string(),
+        };
+        state.draw();
+        Ok(Screen {
+            tui: Some(Mutex::new(state)),
+        })
+    }
+
+    /*
+       Everything shown goes through escape_controls first, messages and file names come from peers and
+       must never get to drive the terminal
+    */
+
+    /// A message from the room
+    pub fn message(&self, text: &str) {
+        let text = escape_controls(text);
+        match &self.tui {
+            Some(state) => state.lock().unwrap().push(text),
+            None => {
+                println!("{}", text);
+                io::stdout().flush().unwrap();
+            }
+        }
+    }
+
+    /// Something we sent. Line mode already has it on screen from the user typing it.
+    pub fn own_message(&self, text: &str) {
+        if let Some(state) = &self.tui {
+            state.lock().unwrap().push(escape_controls(text));
+        }
+    }
+
+    /// Anything from the client itself, warnings, errors and command output
+    pub fn notice(&self, text: &str) {
+        let text = escape_controls(text);
+        match &self.tui {
+            Some(state) => state.lock().unwrap().push(format!("-!- {}", text)),
+            None => eprintln!("{}", text),
+        }
+    }
+
+    pub fn set_connection_state(&self, connection: &str) {
+        if let Some(state) = &self.tui {
+            let mut state = state.lock().unwrap();
+            state.connection = connection.to_string();
+            state.draw();
+        }
+    }
+
+    pub fn clear(&self) {
+        match &self.tui {
+            Some(state) => {
+                let mut state = state.lock().unwrap();
+                state.lines.clear();
+                state.scroll = 0;
+                state.draw();
+            }
+            None => {
+                print!("\x1b[2J\x1b[H");
+                io::stdout().flush().unwrap();
+            }
+        }
+    }
+
+    /// Hands the terminal back, anything printed after this goes to the normal screen
+    pub fn close(&self) {
+        if self.tui.is_some() {
+            terminal::restore();
+        }
+    }
+
+    /// For errors the client can't carry on from, the message has to outlive the TUI so it goes out after close
+    pub fn fatal(&self, text: &str) -> ! {
+        self.close();
+        eprintln!("{}", text);
+        exit(ERROR);
+    }
+
+    /*
+       The next line the user entered, None once there is no more input (end of file, Ctrl-C or Ctrl-D).
+       The TUI only takes the lock once a key has come in, never while waiting on stdin.
+    */
+    pub fn read_line(&self) -> Option<String> {
+        let state = match &self.tui {
+            Some(x) => x,
+            None => {
+                /*
+                   Whatever gets piped in isn't necessarily UTF-8, a bad byte shouldn't end the session
+                */
+                let mut line = Vec::new();
+                return match io::stdin().lock().read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(String::from_utf8_lossy(&line).into_owned()),
+                };
+            }
+        };
+
+        let mut decoder = KeyDecoder::new();
+        let mut stdin = io::stdin();
+        let mut byte = [0u8; 1];
+        loop {
+            match stdin.read(&mut byte) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+            let key = match decoder.push(byte[0]) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let mut state = state.lock().unwrap();
+            let page = terminal::size().1.saturating_sub(2).max(1);
+            match key {
+                Key::Enter if !state.editor.is_empty() => {
+                    let line = state.editor.take();
+                    state.scroll = 0;
+                    state.draw();
+                    return Some(line);
+                }
+                Key::Enter => {}
+                Key::Char(x) if !x.is_control() => state.editor.insert(x),
+                Key::Char(_) => {}
+                Key::Backspace => state.editor.backspace(),
+                Key::Delete => state.editor.delete(),
+                Key::Left => state.editor.left(),
+                Key::Right => state.editor.right(),
+                Key::Home => state.editor.home(),
+                Key::End => state.editor.end(),
+                Key::ClearLine => state.editor.clear(),
+                Key::Up => state.scroll += 1,
+                Key::Down => state.scroll = state.scroll.saturating_sub(1),
+                Key::PageUp => state.scroll += page,
+                Key::PageDown => state.scroll = state.scroll.saturating_sub(page),
+                Key::Redraw => {}
+                Key::Interrupt => return None,
+                Key::EndOfInput if state.editor.is_empty() => return None,
+                Key::EndOfInput => state.editor.delete(),
+            }
+            state.draw();
+        }
+    }
+}
+
+impl TuiState {
+    fn push(&mut self, line: String) {
+        if self.lines.len() == SCROLLBACK_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+        self.draw();
+    }
+
+    /*
+       Redraws the whole screen every time, the terminal is small enough that this is never the slow part.
+       Messages wrap at the screen width, the scroll offset counts wrapped rows.
+    */
+    fn draw(&mut self) {
+        let (columns, rows) = terminal::size();
+        let pane_rows = rows.saturating_sub(2);
+
+        let wrapped: Vec<String> = self
+            .lines
+            .iter()
+            .flat_map(|line| wrap(line, columns))
+            .collect();
+        self.scroll = self.scroll.min(wrapped.len().saturating_sub(pane_rows));
+        let scroll = self.scroll;
+        let end = wrapped.len() - scroll;
+        let start = end.saturating_sub(pane_rows);
+
+        let mut output = String::from("\x1b[?25l\x1b[H");
+        for row in 0..pane_rows {
+            if let Some(line) = wrapped.get(start + row) {
+                output.push_str(line);
+            }
+            output.push_str("\x1b[K\r\n");
+        }
+
+        let mut status = format!(" {} | {} | {}", self.server, self.cipher, self.connection);
+        if scroll > 0 {
+            status.push_str(&format!(" | scrolled back {} lines", scroll));
+        }
+        let status = truncate(&status, columns);
+        output.push_str(&format!(
+            "\x1b[7m{:<width$}\x1b[0m\r\n",
+            status,
+            width = columns
+        ));
+
+        /*
+           The input line scrolls sideways once it gets longer than the screen is wide, keeping the cursor in view
+        */
+        let characters = self.editor.chars();
+        let cursor = self.editor.cursor();
+        let width = columns.saturating_sub(PROMPT.len() + 1).max(1);
+        let mut first = cursor;
+        let mut before_cursor = 0;
+        while first > 0 && before_cursor + char_width(characters[first - 1]) <= width {
+            first -= 1;
+            before_cursor += char_width(characters[first]);
+        }
+        let visible: String = characters[first..].iter().collect();
+        output.push_str(PROMPT);
+        output.push_str(&truncate(&visible, width + 1));
+        output.push_str("\x1b[K");
+        output.push_str(&format!(
+            "\x1b[{};{}H\x1b[?25h",
+            rows,
+            PROMPT.len() + before_cursor + 1
+        ));
+
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(output.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+/*
+   Splits a line into rows of at most columns wide, a double width character that won't fit at the end of a row
+   starts the next one
+*/
+fn wrap(line: &str, columns: usize) -> Vec<String> {
+    let mut rows = vec![String::new()];
+    let mut row_width = 0;
+    for character in line.chars() {
+        let width = char_width(character);
+        if row_width + width > columns.max(2) {
+            rows.push(String::new());
+            row_width = 0;
+        }
+        rows.last_mut().unwrap().push(character);
+        row_width += width;
+    }
+    rows
+}
+
+fn truncate(text: &str, columns: usize) -> String {
+    wrap(text, columns).swap_remove(0)
+}
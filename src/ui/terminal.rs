@@ -0,0 +1,141 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/*
+   The terminal settings from before we went raw. Kept in a static so the atexit hook can put them back,
+   the client calls exit from all over the place and none of those run destructors.
+*/
+static ORIGINAL_SETTINGS: OnceLock<libc::termios> = OnceLock::new();
+static RAW_MODE_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+const ENTER_ALTERNATE_SCREEN: &str = "\x1b[?1049h";
+const LEAVE_ALTERNATE_SCREEN: &str = "\x1b[?1049l";
+const SHOW_CURSOR: &str = "\x1b[?25h";
+
+/*
+   Switches stdin to raw mode (no echo, no line buffering, Ctrl-C comes through as a key) and moves to the
+   alternate screen so whatever was on the terminal before is still there when we leave
+*/
+pub fn enter_raw_mode() -> io::Result<()> {
+    let mut settings = MaybeUninit::<libc::termios>::uninit();
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, settings.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let original = unsafe { settings.assume_init() };
+    let _ = ORIGINAL_SETTINGS.set(original);
+
+    let mut raw = original;
+    raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+    raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &raw) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if !RAW_MODE_ACTIVE.swap(true, Ordering::SeqCst) {
+        unsafe { libc::atexit(restore_at_exit) };
+    }
+
+    let mut stdout = io::stdout();
+    stdout.write_all(ENTER_ALTERNATE_SCREEN.as_bytes())?;
+    stdout.flush()
+}
+
+/// Puts the terminal back how we found it, safe to call more than once
+pub fn restore() {
+    if !RAW_MODE_ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(original) = ORIGINAL_SETTINGS.get() {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, original) };
+    }
+    let mut stdout = io::stdout();
+    let _ = write!(stdout, "{}{}", SHOW_CURSOR, LEAVE_ALTERNATE_SCREEN);
+    let _ = stdout.flush();
+}
+
+extern "C" fn restore_at_exit() {
+    restore();
+}
+
+/// Columns and rows, 80x24 if the terminal won't tell us
+pub fn size() -> (usize, usize) {
+    let mut size = MaybeUninit::<libc::winsize>::uninit();
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, size.as_mut_ptr()) } != 0 {
+        return (80, 24);
+    }
+    let size = unsafe { size.assume_init() };
+    if size.ws_col == 0 || size.ws_row == 0 {
+        return (80, 24);
+    }
+    (size.ws_col as usize, size.ws_row as usize)
+}
+
+/*
+   Asks for something that mustn't show on screen, a key. Goes through /dev/tty rather than stdin and stdout so
+   it still works with both of those taken up by pipes. The terminal is put into non canonical mode with echo
+   and signals off and we do the little bit of line editing ourselves, that way Ctrl-C cancels straight away
+   and never leaves the terminal with echo still off.
+*/
+pub fn read_hidden(prompt: &str) -> io::Result<String> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+
+    let mut settings = MaybeUninit::<libc::termios>::uninit();
+    if unsafe { libc::tcgetattr(fd, settings.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let original = unsafe { settings.assume_init() };
+    let mut hidden = original;
+    hidden.c_lflag &= !(libc::ECHO | libc::ICANON | libc::ISIG);
+    hidden.c_cc[libc::VMIN] = 1;
+    hidden.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(fd, libc::TCSADRAIN, &hidden) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let result = tty
+        .write_all(prompt.as_bytes())
+        .and_then(|_| read_hidden_line(&mut tty));
+
+    unsafe { libc::tcsetattr(fd, libc::TCSADRAIN, &original) };
+    let _ = tty.write_all(b"\n");
+    result
+}
+
+fn read_hidden_line(tty: &mut File) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if tty.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        /*
+           Ctrl-C cancels, Ctrl-D on an empty line is the end of input and Ctrl-U starts the line over
+        */
+        match byte[0] {
+            b'\r' | b'\n' => break,
+            0x03 => return Err(io::ErrorKind::Interrupted.into()),
+            0x04 if line.is_empty() => return Err(io::ErrorKind::UnexpectedEof.into()),
+            0x15 => line.clear(),
+            0x08 | 0x7f => {
+                /*
+                   Backspace takes off a whole character, not just its last byte
+                */
+                while let Some(x) = line.pop() {
+                    if x & 0xc0 != 0x80 {
+                        break;
+                    }
+                }
+            }
+            x => line.push(x),
+        }
+    }
+    String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,536 @@
+use kryptos::arg_handling::arg_handling::arg_handling::{
+    CipherConfig, EncryptionInfo, KeyMaterial, KeySize, KryptosConfig,
+};
+use kryptos::cryptography::cryptography::EncryptionContext;
+use kryptos::cryptography::kdf::pbkdf2_hmac_sha256;
+use kryptos::cryptography::ratchet::RekeyPolicy;
+use kryptos::network::framing::FrameError;
+use kryptos::network::handshake::HandshakeError;
+use kryptos::network::message::Message;
+use kryptos::network::replay::now_millis;
+use kryptos::network::transfer::MAX_RECEIVED_FILES;
+use kryptos::server::mock::MockServer;
+use kryptos::{ClientError, ClientEvent, KryptosClient, SendStatus};
+use std::fs;
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+/*
+   End to end tests of the client against the mock server on localhost. Everything waits with a timeout so a
+   broken client fails the test instead of hanging it.
+*/
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const SALT: &str = "test room";
+
+fn config(port: u16, enc_type: EncryptionInfo, key: &str) -> KryptosConfig {
+    KryptosConfig {
+        cipher: CipherConfig {
+            enc_type,
+            key: KeyMaterial::Passphrase(key.to_string()),
+            key_size: KeySize::Size256,
+            salt: SALT.to_string(),
+            iterations: 1, // Nothing to protect here, keep the tests quick
+            rc4_drop: 0,
+        },
+        handshake: false,
+        handshake_timeout: TIMEOUT,
+        line_mode: true,
+        reconnect: false,
+        rekey_policy: RekeyPolicy::default(),
+        port,
+        ip: "127.0.0.1".to_string(),
+    }
+}
+
+fn start_server() -> MockServer {
+    MockServer::start("127.0.0.1:0").unwrap()
+}
+
+fn connect(server: &MockServer, clients: usize, config: &KryptosConfig) -> KryptosClient {
+    let client = KryptosClient::connect(config).unwrap();
+    assert!(server.wait_for_clients(clients, TIMEOUT));
+    client
+}
+
+/*
+   Skips over events until matcher picks one out
+*/
+fn wait_for<T>(client: &KryptosClient, mut matcher: impl FnMut(ClientEvent) -> Option<T>) -> T {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let event = client
+            .next_event_timeout(remaining)
+            .expect("timed out waiting for an event");
+        if let Some(x) = matcher(event) {
+            return x;
+        }
+    }
+}
+
+fn next_chat(client: &KryptosClient) -> Vec<u8> {
+    wait_for(client, |event| match event {
+        ClientEvent::Message(message) => Some(message.body),
+        _ => None,
+    })
+}
+
+fn closed_with(client: &KryptosClient) -> Option<ClientError> {
+    wait_for(client, |event| match event {
+        ClientEvent::Closed(reason) => Some(reason),
+        _ => None,
+    })
+}
+
+#[test]
+fn sent_messages_come_back_through_the_server() {
+    let server = start_server();
+    let client = connect(
+        &server,
+        1,
+        &config(server.port(), EncryptionInfo::AesGcm, "pw"),
+    );
+
+    assert_eq!(client.send("hello").unwrap(), SendStatus::Sent);
+    assert_eq!(next_chat(&client), b"hello");
+    assert_eq!(
+        client.send_bytes(&[0, 159, 146, 150]).unwrap(),
+        SendStatus::Sent
+    );
+    assert_eq!(next_chat(&client), [0, 159, 146, 150]);
+    assert_eq!(server.frames_relayed(), 2);
+}
+
+#[test]
+fn messages_reach_every_client_in_the_room() {
+    let server = start_server();
+    let config = config(server.port(), EncryptionInfo::ChaCha20Poly1305, "pw");
+    let alice = connect(&server, 1, &config);
+    let bob = connect(&server, 2, &config);
+
+    alice.send("from alice").unwrap();
+    assert_eq!(next_chat(&bob), b"from alice");
+    assert_eq!(next_chat(&alice), b"from alice");
+
+    bob.send("from bob").unwrap();
+    assert_eq!(next_chat(&alice), b"from bob");
+}
+
+#[test]
+fn every_cipher_works_end_to_end() {
+    let server = start_server();
+    let ciphers = [
+        EncryptionInfo::AesGcm,
+        EncryptionInfo::ChaCha20Poly1305,
+        EncryptionInfo::AesCbc,
+        EncryptionInfo::AesCtr,
+        EncryptionInfo::AesEcb,
+        EncryptionInfo::Rc4,
+    ];
+    for enc_type in ciphers {
+        let client = connect(&server, 1, &config(server.port(), enc_type, "pw"));
+        client.send(enc_type.name()).unwrap();
+        assert_eq!(next_chat(&client), enc_type.name().as_bytes());
+        client.close();
+        assert!(server.wait_for_clients(0, TIMEOUT));
+    }
+}
+
+#[test]
+fn messages_under_another_key_are_dropped() {
+    let server = start_server();
+    let client = connect(
+        &server,
+        1,
+        &config(server.port(), EncryptionInfo::AesGcm, "pw"),
+    );
+
+    let mut key = vec![0u8; KeySize::Size256.bytes()];
+    pbkdf2_hmac_sha256(b"not the key", SALT.as_bytes(), 1, &mut key);
+    let mut wrong =
+        EncryptionContext::create(EncryptionInfo::AesGcm, KeySize::Size256, &key, 0).unwrap();
+    server
+        .send_message(&mut wrong, &Message::chat(b"intruder"))
+        .unwrap();
+    let notice = wait_for(&client, |event| match event {
+        ClientEvent::Notice(text) => Some(text),
+        ClientEvent::Message(_) => panic!("a message under the wrong key got through"),
+        _ => None,
+    });
+    assert!(notice.starts_with("Dropped a message"), "{}", notice);
+
+    pbkdf2_hmac_sha256(b"pw", SALT.as_bytes(), 1, &mut key);
+    let mut right =
+        EncryptionContext::create(EncryptionInfo::AesGcm, KeySize::Size256, &key, 0).unwrap();
+    server
+        .send_message(&mut right, &Message::chat(b"from the server"))
+        .unwrap();
+    assert_eq!(next_chat(&client), b"from the server");
+}
+
+#[test]
+fn replayed_and_stale_messages_are_dropped() {
+    let server = start_server();
+    let client = connect(
+        &server,
+        1,
+        &config(server.port(), EncryptionInfo::AesGcm, "pw"),
+    );
+    let mut key = vec![0u8; KeySize::Size256.bytes()];
+    pbkdf2_hmac_sha256(b"pw", SALT.as_bytes(), 1, &mut key);
+    let mut context =
+        EncryptionContext::create(EncryptionInfo::AesGcm, KeySize::Size256, &key, 0).unwrap();
+    let dropped = |client: &KryptosClient| {
+        wait_for(client, |event| match event {
+            ClientEvent::Notice(text) => Some(text),
+            ClientEvent::Message(_) => panic!("a replayed message got through"),
+            _ => None,
+        })
+    };
+
+    let mut message = Message::chat(b"once");
+    message.stream_id = 42;
+    server.send_message(&mut context, &message).unwrap();
+    assert_eq!(next_chat(&client), b"once");
+    server.send_message(&mut context, &message).unwrap();
+    assert!(dropped(&client).contains("already received"));
+
+    // A sender we've never heard from, but the message was recorded an hour ago
+    let mut recording = Message::chat(b"an hour ago");
+    recording.stream_id = 43;
+    recording.timestamp -= 60 * 60 * 1000;
+    server.send_message(&mut context, &recording).unwrap();
+    assert!(dropped(&client).contains("stale"));
+
+    recording.timestamp = now_millis();
+    server.send_message(&mut context, &recording).unwrap();
+    assert_eq!(next_chat(&client), b"an hour ago");
+}
+
+#[test]
+fn connecting_to_nothing_is_an_error() {
+    // Grab a free port and let it go again so nothing is listening there
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    match KryptosClient::connect(&config(port, EncryptionInfo::AesGcm, "pw")) {
+        Err(ClientError::Connect { .. }) => {}
+        Err(e) => panic!("wrong error {}", e),
+        Ok(_) => panic!("connected to nothing"),
+    }
+}
+
+#[test]
+fn server_hanging_up_ends_the_session() {
+    let server = start_server();
+    let client = connect(
+        &server,
+        1,
+        &config(server.port(), EncryptionInfo::AesGcm, "pw"),
+    );
+
+    server.disconnect_all();
+    assert!(matches!(
+        closed_with(&client),
+        Some(ClientError::ConnectionClosed)
+    ));
+    assert!(client.next_event_timeout(TIMEOUT).is_none());
+    assert!(!client.is_connected());
+    assert!(matches!(
+        client.send("anyone?"),
+        Err(ClientError::NotConnected)
+    ));
+}
+
+#[test]
+fn malformed_frame_ends_the_session() {
+    let server = start_server();
+    let client = connect(
+        &server,
+        1,
+        &config(server.port(), EncryptionInfo::AesGcm, "pw"),
+    );
+
+    // A length header far past the maximum frame size
+    server.send_raw(&[0xff, 0xff, 0xff, 0xff]);
+    assert!(matches!(
+        closed_with(&client),
+        Some(ClientError::Frame(FrameError::Oversized(_)))
+    ));
+}
+
+#[test]
+fn closing_disconnects_and_ends_the_events() {
+    let server = start_server();
+    let client = connect(
+        &server,
+        1,
+        &config(server.port(), EncryptionInfo::AesGcm, "pw"),
+    );
+
+    client.close();
+    assert!(closed_with(&client).is_none());
+    assert!(client.next_event().is_none());
+    assert!(server.wait_for_clients(0, TIMEOUT));
+}
+
+#[test]
+fn reconnects_and_sends_what_was_queued() {
+    let server = start_server();
+    let mut config = config(server.port(), EncryptionInfo::AesGcm, "pw");
+    config.reconnect = true;
+    let client = connect(&server, 1, &config);
+
+    server.disconnect_all();
+    wait_for(&client, |event| match event {
+        ClientEvent::ConnectionLost(_) => Some(()),
+        _ => None,
+    });
+    assert_eq!(
+        client.send("while away").unwrap(),
+        SendStatus::Queued { waiting: 1 }
+    );
+
+    let sent = wait_for(&client, |event| match event {
+        ClientEvent::Reconnected { sent } => Some(sent),
+        _ => None,
+    });
+    assert_eq!(sent, 1);
+    assert!(client.is_connected());
+    assert_eq!(next_chat(&client), b"while away");
+}
+
+#[test]
+fn files_arrive_intact() {
+    let server = start_server();
+    let config = config(server.port(), EncryptionInfo::AesGcm, "pw");
+    let alice = connect(&server, 1, &config);
+    let bob = connect(&server, 2, &config);
+
+    let directory = std::env::temp_dir().join(format!("kryptos-test-{}", std::process::id()));
+    fs::create_dir_all(directory.join("in")).unwrap();
+    let source = directory.join("data.bin");
+    // A few chunks worth with a partial one on the end
+    let data: Vec<u8> = (0..40_000u32).map(|x| (x % 251) as u8).collect();
+    fs::write(&source, &data).unwrap();
+
+    let header = alice.send_file(&source).unwrap();
+    assert_eq!(header.size, data.len() as u64);
+    let (name, size) = wait_for(&bob, |event| match event {
+        ClientEvent::FileReceived { name, size } => Some((name, size)),
+        _ => None,
+    });
+    assert_eq!((name.as_str(), size), ("data.bin", data.len()));
+
+    let target = directory.join("in");
+    let (_, saved) = bob
+        .save_received_file(Some(target.to_str().unwrap()))
+        .unwrap();
+    assert_eq!(fs::read(&saved).unwrap(), data);
+    assert!(matches!(
+        bob.save_received_file(None),
+        Err(ClientError::NoReceivedFiles)
+    ));
+
+    // Everything alice sent has come back to her once her chat does, none of it as a file of her own
+    alice.send("done").unwrap();
+    wait_for(&alice, |event| match event {
+        ClientEvent::FileReceived { .. } => panic!("got our own file back"),
+        ClientEvent::Notice(text) if text.starts_with("Receiving") => panic!("{}", text),
+        ClientEvent::Message(message) => Some(message),
+        _ => None,
+    });
+    assert!(matches!(
+        alice.save_received_file(None),
+        Err(ClientError::NoReceivedFiles)
+    ));
+
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn unsaved_files_are_capped() {
+    let server = start_server();
+    let config = config(server.port(), EncryptionInfo::AesGcm, "pw");
+    let alice = connect(&server, 1, &config);
+    let bob = connect(&server, 2, &config);
+
+    let source = std::env::temp_dir().join(format!("kryptos-cap-{}", std::process::id()));
+    fs::write(&source, b"small").unwrap();
+
+    // Bob never saves anything, so once the queue is full the next file gets turned away
+    for _ in 0..MAX_RECEIVED_FILES {
+        alice.send_file(&source).unwrap();
+        wait_for(&bob, |event| match event {
+            ClientEvent::FileReceived { .. } => Some(()),
+            _ => None,
+        });
+    }
+    alice.send_file(&source).unwrap();
+    let refused = wait_for(&bob, |event| match event {
+        ClientEvent::FileReceived { .. } => panic!("queue grew past its limit"),
+        ClientEvent::Notice(text) if text.contains("refused") => Some(text),
+        _ => None,
+    });
+    assert!(refused.contains("no room"), "{}", refused);
+
+    // Clearing one out makes room for the next
+    assert!(bob.discard_received_file().is_some());
+    alice.send_file(&source).unwrap();
+    wait_for(&bob, |event| match event {
+        ClientEvent::FileReceived { .. } => Some(()),
+        _ => None,
+    });
+
+    fs::remove_file(&source).unwrap();
+}
+
+#[test]
+fn handshake_agrees_a_key_between_two_clients() {
+    let server = start_server();
+    let handshake_config = || {
+        let mut config = config(server.port(), EncryptionInfo::AesGcm, "pw");
+        config.handshake = true;
+        config
+    };
+
+    // The first one in has nobody to answer its handshake until the second turns up
+    let first_config = handshake_config();
+    let first = std::thread::spawn(move || KryptosClient::connect(&first_config).unwrap());
+    assert!(server.wait_for_clients(1, TIMEOUT));
+    let bob = KryptosClient::connect(&handshake_config()).unwrap();
+    let alice = first.join().unwrap();
+
+    let no_drops = |client: &KryptosClient| {
+        wait_for(client, |event| match event {
+            ClientEvent::Message(message) => Some(message.body),
+            ClientEvent::Notice(text) => panic!("{}", text),
+            _ => None,
+        })
+    };
+    alice.send("hi bob").unwrap();
+    assert_eq!(no_drops(&bob), b"hi bob");
+    assert_eq!(no_drops(&alice), b"hi bob");
+    bob.send("hi alice").unwrap();
+    assert_eq!(no_drops(&alice), b"hi alice");
+    assert_eq!(no_drops(&bob), b"hi alice");
+}
+
+#[test]
+fn reconnecting_runs_the_handshake_again() {
+    let server = start_server();
+    let handshake_config = || {
+        let mut config = config(server.port(), EncryptionInfo::AesGcm, "pw");
+        config.handshake = true;
+        config.reconnect = true;
+        config
+    };
+
+    let first_config = handshake_config();
+    let first = std::thread::spawn(move || KryptosClient::connect(&first_config).unwrap());
+    assert!(server.wait_for_clients(1, TIMEOUT));
+    let bob = KryptosClient::connect(&handshake_config()).unwrap();
+    let alice = first.join().unwrap();
+    alice.rekey().unwrap();
+    alice.send("before").unwrap();
+    assert_eq!(next_chat(&bob), b"before");
+    assert_eq!(bob.epoch(), 1);
+
+    // Losing the relay drops the whole room, everyone comes back and handshakes with whoever else is there
+    server.disconnect_all();
+    for client in [&alice, &bob] {
+        wait_for(client, |event| match event {
+            ClientEvent::ConnectionLost(_) => Some(()),
+            _ => None,
+        });
+    }
+    alice.send("while away").unwrap();
+    for client in [&alice, &bob] {
+        wait_for(client, |event| match event {
+            ClientEvent::Reconnected { .. } => Some(()),
+            _ => None,
+        });
+        // A new session key starts the ratchet over
+        assert_eq!(client.epoch(), 0);
+    }
+
+    let no_drops = |client: &KryptosClient| {
+        wait_for(client, |event| match event {
+            ClientEvent::Message(message) => Some(message.body),
+            ClientEvent::Notice(text) => panic!("{}", text),
+            _ => None,
+        })
+    };
+    assert_eq!(no_drops(&bob), b"while away");
+    bob.send("welcome back").unwrap();
+    assert_eq!(no_drops(&alice), b"while away");
+    assert_eq!(no_drops(&alice), b"welcome back");
+}
+
+#[test]
+fn handshake_with_nobody_times_out() {
+    let server = start_server();
+    let mut config = config(server.port(), EncryptionInfo::AesGcm, "pw");
+    config.handshake = true;
+    config.handshake_timeout = Duration::from_millis(300);
+
+    let started = Instant::now();
+    let error = KryptosClient::connect(&config).err();
+    assert!(
+        matches!(error, Some(ClientError::Handshake(HandshakeError::Timeout))),
+        "{:?}",
+        error
+    );
+    assert!(started.elapsed() < TIMEOUT);
+}
+
+#[test]
+fn idle_sessions_rotate_on_time() {
+    let server = start_server();
+    let mut config = config(server.port(), EncryptionInfo::ChaCha20Poly1305, "pw");
+    config.rekey_policy.max_age = Some(Duration::from_millis(200));
+    let client = connect(&server, 1, &config);
+
+    // Nothing gets sent, the reader has to notice the key is too old on its own
+    let deadline = Instant::now() + TIMEOUT;
+    while client.epoch() == 0 {
+        assert!(Instant::now() < deadline, "never rekeyed");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    client.send("still readable").unwrap();
+    assert_eq!(next_chat(&client), b"still readable");
+}
+
+#[test]
+fn runaway_epochs_are_dropped() {
+    let server = start_server();
+    let client = connect(
+        &server,
+        1,
+        &config(server.port(), EncryptionInfo::AesGcm, "pw"),
+    );
+    let mut key = vec![0u8; KeySize::Size256.bytes()];
+    pbkdf2_hmac_sha256(b"pw", SALT.as_bytes(), 1, &mut key);
+    let mut context =
+        EncryptionContext::create(EncryptionInfo::AesGcm, KeySize::Size256, &key, 0).unwrap();
+
+    let mut rekey = Message::rekey(u64::MAX);
+    rekey.stream_id = 7;
+    server.send_message(&mut context, &rekey).unwrap();
+    let notice = wait_for(&client, |event| match event {
+        ClientEvent::Notice(text) => Some(text),
+        _ => None,
+    });
+    assert!(notice.contains("epoch"), "{}", notice);
+    assert_eq!(client.epoch(), 0);
+
+    // Still on the original key and still reading
+    let mut chat = Message::chat(b"after");
+    chat.stream_id = 7;
+    chat.sequence = 1;
+    server.send_message(&mut context, &chat).unwrap();
+    assert_eq!(next_chat(&client), b"after");
+}